@@ -1,14 +1,178 @@
+extern crate hdrhistogram;
 extern crate pubsub;
 
-use pubsub::server::Server; 
-use pubsub::server::Topics;
+use hdrhistogram::Histogram;
+use pubsub::server::{Options, Server, Topics};
 
+use std::env;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Bench
+///
+/// Load generator for a local server. For each combination of
+/// message size and fan-out, starts a server, connects the
+/// subscribers, has each publisher send its messages as fast as
+/// possible and reports delivery throughput and the latency from
+/// publish to receipt.
+///
+/// usage: bench [--publishers N] [--messages N] [--sizes A,B,..] [--fanouts A,B,..]
+struct Config {
+    publishers : usize,
+    messages   : usize,
+    sizes      : Vec<usize>,
+    fanouts    : Vec<usize>
+}
+impl Config {
+    fn parse() -> Config {
+        let mut config = Config {
+            publishers : 1,
+            messages   : 10000,
+            sizes      : vec![16, 256, 4096],
+            fanouts    : vec![1, 10, 50]
+        };
+        let args = env::args().skip(1).collect::<Vec<_>>();
+        for pair in args.chunks(2) {
+            let value = pair.get(1).map(|value| value.as_str()).unwrap_or("");
+            match pair[0].as_str() {
+                "--publishers" => config.publishers = Config::number(&pair[0], value),
+                "--messages"   => config.messages   = Config::number(&pair[0], value),
+                "--sizes"      => config.sizes      = value.split(',').map(|size| Config::number(&pair[0], size)).collect(),
+                "--fanouts"    => config.fanouts    = value.split(',').map(|fanout| Config::number(&pair[0], fanout)).collect(),
+                flag => {
+                    eprintln!("unknown flag {}", flag);
+                    std::process::exit(1);
+                }
+            }
+        } config
+    }
+
+    fn number(flag: &str, value: &str) -> usize {
+        value.parse().unwrap_or_else(|_| {
+            eprintln!("{} expects a number, got '{}'", flag, value);
+            std::process::exit(1);
+        })
+    }
+}
+
+/// Run
+///
+/// Results of one size and fan-out combination.
+struct Run {
+    delivered : u64,
+    expected  : u64,
+    elapsed   : Duration,
+    latency   : Histogram<u64>
+}
+
+/// subscribes a connection and waits until the server has
+/// processed the subscription.
+fn subscribe(addr: SocketAddr) -> BufReader<TcpStream> {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"s:bench\nping:ready\n").unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut line   = String::new();
+    while line.trim_end() != "pong:ready" {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+    } reader
+}
+
+/// receives messages until all are delivered or the stream goes
+/// quiet, recording the latency of each and when the last arrived.
+fn receive(mut reader: BufReader<TcpStream>, epoch: Instant, expected: u64) -> (u64, Histogram<u64>, Option<Instant>) {
+    let mut latency   = Histogram::<u64>::new_with_bounds(1, 60_000_000_000, 3).unwrap();
+    let mut delivered = 0;
+    let mut last      = None;
+    let mut line      = String::new();
+    reader.get_ref().set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    while delivered < expected {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        // m:bench:user:timestamp:padding
+        let sent = line.split(':').nth(3).and_then(|sent| sent.parse::<u64>().ok());
+        if let Some(sent) = sent {
+            let now = epoch.elapsed().as_nanos() as u64;
+            latency.saturating_record(now.saturating_sub(sent).max(1));
+            delivered += 1;
+            last = Some(Instant::now());
+        }
+    } (delivered, latency, last)
+}
+
+/// publishes messages of this size, stamped with the send time.
+fn publish(addr: SocketAddr, epoch: Instant, messages: usize, size: usize) {
+    let stream     = TcpStream::connect(addr).unwrap();
+    let mut writer = std::io::BufWriter::new(stream);
+    let padding    = "x".repeat(size);
+    for _ in 0..messages {
+        let sent = epoch.elapsed().as_nanos() as u64;
+        writeln!(writer, "p:bench:{}:{}", sent, padding).unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+fn run(config: &Config, size: usize, fanout: usize) -> Run {
+    let options = Options {
+        queue_capacity: 65536,
+        ..Options::default()
+    };
+    let handle   = Server::start_with("127.0.0.1:0", Topics::new(), options).unwrap();
+    let addr     = handle.local_addr();
+    let epoch    = Instant::now();
+    let expected = (config.publishers * config.messages) as u64;
+    let readers  = (0..fanout).map(|_| subscribe(addr)).collect::<Vec<_>>();
+    let start    = Instant::now();
+    let subscribers = readers.into_iter().map(|reader| {
+        thread::spawn(move || receive(reader, epoch, expected))
+    }).collect::<Vec<_>>();
+    let publishers = (0..config.publishers).map(|_| {
+        let messages = config.messages;
+        thread::spawn(move || publish(addr, epoch, messages, size))
+    }).collect::<Vec<_>>();
+    for publisher in publishers {
+        publisher.join().unwrap();
+    }
+    // time up to the last message received, so the quiet timeout
+    // of a short run is not counted.
+    let mut latency   = Histogram::<u64>::new_with_bounds(1, 60_000_000_000, 3).unwrap();
+    let mut delivered = 0;
+    let mut last      = start;
+    for subscriber in subscribers {
+        let (count, histogram, received) = subscriber.join().unwrap();
+        delivered += count;
+        latency.add(histogram).unwrap();
+        last = last.max(received.unwrap_or(start));
+    }
+    let elapsed = last.duration_since(start);
+    handle.shutdown().unwrap();
+    Run { delivered, expected: expected * fanout as u64, elapsed, latency }
+}
 
 fn main() {
-    
-    
-    match Server::bind("localhost:5000", Topics::new()) {
-        Ok(_)  => println!("server ended ok"),
-        Err(e) => println!("{:?}", e)
-    };  
-}
\ No newline at end of file
+    let config = Config::parse();
+    println!("publishers: {}, messages per publisher: {}", config.publishers, config.messages);
+    println!("{:>6} {:>6} {:>16} {:>12} {:>10} {:>10} {:>10} {:>10}",
+        "size", "fanout", "delivered", "msg/s", "MB/s", "p50 us", "p99 us", "p999 us");
+    for size in &config.sizes {
+        for fanout in &config.fanouts {
+            let run  = run(&config, *size, *fanout);
+            let secs = run.elapsed.as_secs_f64();
+            let rate = if secs > 0.0 { run.delivered as f64 / secs } else { 0.0 };
+            println!("{:>6} {:>6} {:>16} {:>12.0} {:>10.1} {:>10.1} {:>10.1} {:>10.1}",
+                size, fanout,
+                format!("{}/{}", run.delivered, run.expected),
+                rate,
+                rate * *size as f64 / 1_000_000.0,
+                run.latency.value_at_quantile(0.5)   as f64 / 1000.0,
+                run.latency.value_at_quantile(0.99)  as f64 / 1000.0,
+                run.latency.value_at_quantile(0.999) as f64 / 1000.0);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+extern crate pubsub;
+
+use pubsub::protocol::Packet;
+use pubsub::server::{Subscriber, Topics};
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::io::Result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+/// counts allocations made by the process.
+struct Counter;
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+unsafe impl GlobalAlloc for Counter {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+#[global_allocator]
+static GLOBAL: Counter = Counter;
+
+/// subscriber that discards everything it receives.
+struct Null;
+impl Subscriber for Null {
+    fn send(&mut self, packet: &Packet) -> Result<()> {
+        let _ = packet.bytes().len();
+        Ok(())
+    }
+    fn alive(&self) -> bool {
+        true
+    }
+}
+
+/// subscriber that encodes its own copy of every message, as
+/// subscribers did before packets were shared.
+struct Copied;
+impl Subscriber for Copied {
+    fn send(&mut self, packet: &Packet) -> Result<()> {
+        let bytes = packet.message().serialize().into_bytes();
+        let _ = bytes.len();
+        Ok(())
+    }
+    fn alive(&self) -> bool {
+        true
+    }
+}
+
+const PUBLISHES: usize = 10000;
+
+/// measures allocations per publish through the topic store with
+/// subscribers created by this function.
+fn measure<F: Fn() -> Box<dyn Subscriber>>(fanout: usize, subscriber: F) -> (f64, f64) {
+    let topics = Topics::new();
+    for index in 0..fanout {
+        topics.subscribe("bench".to_string(), index.to_string(), subscriber());
+    }
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start  = Instant::now();
+    for _ in 0..PUBLISHES {
+        topics.publish("bench".to_string(), "user".to_string(), "hello world".to_string());
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    let count   = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    (count as f64 / PUBLISHES as f64, PUBLISHES as f64 / elapsed)
+}
+
+/// compares allocations per publish for packets shared across all
+/// subscribers against encoding a copy for each subscriber.
+fn main() {
+    println!("{:>8} {:>18} {:>18} {:>14} {:>14}", "fanout", "allocs/pub shared", "allocs/pub copied", "pub/s shared", "pub/s copied");
+    for fanout in &[1, 10, 100, 1000] {
+        let (shared_allocs, shared_rate) = measure(*fanout, || Box::new(Null));
+        let (copied_allocs, copied_rate) = measure(*fanout, || Box::new(Copied));
+        println!("{:>8} {:>18.1} {:>18.1} {:>14.0} {:>14.0}", fanout, shared_allocs, copied_allocs, shared_rate, copied_rate);
+    }
+}
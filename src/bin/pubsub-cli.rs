@@ -0,0 +1,227 @@
+extern crate pubsub;
+
+use pubsub::client::Client;
+use pubsub::protocol::{json_string, Command};
+use pubsub::server::sys::SYS_PREFIX;
+
+use std::env;
+use std::io::prelude::*;
+use std::collections::HashMap;
+use std::io::{self, Error, ErrorKind, Result};
+use std::process;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "usage: pubsub-cli [options] <command>
+
+commands:
+  pub <topic> <message>        publish a message to this topic.
+  sub <topic>...               print messages published to these topics.
+  pipe <topic>                 publish each line read from stdin to this topic.
+  stats                        print the broker statistics published to $SYS topics.
+  ping                         measure round trip latency to the server.
+  admin <action>               run an admin action as an admin user: reload, topics,
+                               clients, queue:<id> or kick:<id>.
+
+options:
+  -a, --addr <addr>            server address, defaults to 127.0.0.1:5000.
+  -u, --user <name>            identify as this user.
+  -p, --password <password>    authenticate as --user with this password.
+      --json                   print received messages as json lines.
+      --count <count>          number of round trips measured by ping.
+      --timeout-ms <ms>        time stats waits for the statistics, defaults to 15000.
+  -h, --help                   print this message.";
+
+/// Options
+///
+/// Options shared by all commands.
+struct Options {
+    addr     : String,
+    user     : Option<String>,
+    password : Option<String>,
+    json     : bool,
+    count    : usize,
+    timeout  : Duration
+}
+
+/// statistics printed by stats, as $SYS topics and labels.
+const STATISTICS: [(&str, &str); 7] = [
+    ("clients/count",      "clients:           "),
+    ("clients/total",      "connections:       "),
+    ("topics/count",       "topics:            "),
+    ("messages/published", "messages:          "),
+    ("messages/rate",      "messages/s:        "),
+    ("bytes/published",    "bytes:             "),
+    ("bytes/rate",         "bytes/s:           ")
+];
+
+/// exits with this error and the usage.
+fn usage_error(message: &str) -> ! {
+    eprintln!("pubsub-cli: {}\n\n{}", message, USAGE);
+    process::exit(2);
+}
+
+/// connects and identifies or authenticates the client.
+fn connect(options: &Options) -> Result<Client> {
+    let mut client = Client::connect(options.addr.as_str())?;
+    match (&options.user, &options.password) {
+        (Some(user), Some(password)) => client.authenticate(user, password)?,
+        (Some(user), None)           => client.identity(user)?,
+        (None, Some(_))              => usage_error("--password requires --user"),
+        (None, None)                 => {}
+    }
+    client.sync()?;
+    Ok(client)
+}
+
+fn publish(options: &Options, topic: &str, message: &str) -> Result<()> {
+    let mut client = connect(options)?;
+    client.publish(topic, message)?;
+    client.sync()
+}
+
+fn subscribe(options: &Options, topics: &[String]) -> Result<()> {
+    let mut client = connect(options)?;
+    for topic in topics {
+        client.subscribe(topic)?;
+    }
+    client.sync()?;
+    let stdout = io::stdout();
+    while let Some(command) = client.receive()? {
+        let mut stdout = stdout.lock();
+        match command {
+            Command::Message(topic, user, message) => {
+                if options.json {
+                    writeln!(stdout, "{{\"topic\":{},\"user\":{},\"message\":{}}}", json_string(&topic), json_string(&user), json_string(&message))?;
+                } else {
+                    writeln!(stdout, "[{}] {}: {}", topic, user, message)?;
+                }
+                stdout.flush()?;
+            },
+            Command::Error(reason)   => eprintln!("pubsub-cli: server error: {}", reason),
+            Command::Goodbye(reason) => {
+                eprintln!("pubsub-cli: server closed connection: {}", reason);
+                break;
+            },
+            _ => {}
+        }
+    } Ok(())
+}
+
+fn pipe(options: &Options, topic: &str) -> Result<()> {
+    let mut client = connect(options)?;
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        client.publish(topic, &line?)?;
+    }
+    client.sync()
+}
+
+/// prints the next value published to each statistics topic. The
+/// server only publishes these with a sys interval configured.
+fn stats(options: &Options) -> Result<()> {
+    let mut client = connect(options)?;
+    for &(name, _) in STATISTICS.iter() {
+        client.subscribe(&format!("{}{}", SYS_PREFIX, name))?;
+    }
+    client.sync()?;
+    client.set_read_timeout(Some(options.timeout))?;
+    let mut values = HashMap::new();
+    while values.len() < STATISTICS.len() {
+        match client.receive() {
+            Ok(Some(Command::Message(topic, _, message))) => {
+                values.insert(topic, message);
+            },
+            Ok(Some(Command::Goodbye(reason))) => {
+                return Err(Error::new(ErrorKind::ConnectionAborted, format!("server closed connection: {}", reason)));
+            },
+            Ok(Some(_)) => {},
+            Ok(None) => return Err(Error::new(ErrorKind::UnexpectedEof, "server closed connection")),
+            Err(ref error) if error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut => {
+                return Err(Error::new(ErrorKind::TimedOut, "no statistics received, is the server's [sys] interval_ms set?"));
+            },
+            Err(error) => return Err(error)
+        }
+    }
+    println!("server:            {}", options.addr);
+    for &(name, label) in STATISTICS.iter() {
+        println!("{}{}", label, values[&format!("{}{}", SYS_PREFIX, name)]);
+    } Ok(())
+}
+
+/// measures the round trip latency to the server.
+fn ping(options: &Options) -> Result<()> {
+    let mut client = connect(options)?;
+    let mut rtts   = Vec::with_capacity(options.count);
+    for _ in 0..options.count {
+        let start = Instant::now();
+        client.sync()?;
+        rtts.push(start.elapsed());
+    }
+    rtts.sort();
+    let total: Duration = rtts.iter().sum();
+    println!("server:      {}", options.addr);
+    println!("round trips: {}", rtts.len());
+    if !rtts.is_empty() {
+        println!("min:         {:?}", rtts[0]);
+        println!("avg:         {:?}", total / rtts.len() as u32);
+        println!("max:         {:?}", rtts[rtts.len() - 1]);
+    } Ok(())
+}
+
+fn admin(options: &Options, action: &str) -> Result<()> {
+    let mut client = connect(options)?;
+    for line in client.admin(action)? {
+        println!("{}", line);
+    } Ok(())
+}
+
+fn main() {
+    let mut options = Options {
+        addr     : "127.0.0.1:5000".to_string(),
+        user     : None,
+        password : None,
+        json     : false,
+        count    : 10,
+        timeout  : Duration::from_secs(15)
+    };
+    let mut positional = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| args.next().unwrap_or_else(|| usage_error(&format!("{} expects a value", flag)));
+        match arg.as_str() {
+            "-a" | "--addr"     => options.addr     = value(&arg),
+            "-u" | "--user"     => options.user     = Some(value(&arg)),
+            "-p" | "--password" => options.password = Some(value(&arg)),
+            "--json"            => options.json     = true,
+            "--count"           => {
+                let count = value(&arg);
+                options.count = count.parse().unwrap_or_else(|_| usage_error(&format!("--count expects a number, got '{}'", count)));
+            },
+            "--timeout-ms"      => {
+                let ms = value(&arg);
+                let ms = ms.parse().unwrap_or_else(|_| usage_error(&format!("--timeout-ms expects a number, got '{}'", ms)));
+                options.timeout = Duration::from_millis(ms);
+            },
+            "-h" | "--help"     => {
+                println!("{}", USAGE);
+                return;
+            },
+            flag if flag.starts_with('-') && flag.len() > 1 => usage_error(&format!("unknown option '{}'", flag)),
+            _ => positional.push(arg)
+        }
+    }
+    let result = match positional.first().map(|command| command.as_str()) {
+        Some("pub")   if positional.len() == 3 => publish(&options, &positional[1], &positional[2]),
+        Some("sub")   if positional.len() >= 2 => subscribe(&options, &positional[1..]),
+        Some("pipe")  if positional.len() == 2 => pipe(&options, &positional[1]),
+        Some("stats") if positional.len() == 1 => stats(&options),
+        Some("ping")  if positional.len() == 1 => ping(&options),
+        Some("admin") if positional.len() == 2 => admin(&options, &positional[1]),
+        Some(command) => usage_error(&format!("invalid arguments for '{}'", command)),
+        None          => usage_error("missing command")
+    };
+    if let Err(error) = result {
+        eprintln!("pubsub-cli: {}", error);
+        process::exit(1);
+    }
+}
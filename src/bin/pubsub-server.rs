@@ -0,0 +1,406 @@
+#[macro_use]
+extern crate log;
+extern crate pubsub;
+#[cfg(unix)]
+extern crate signal_hook;
+
+use pubsub::server::{Bridge, Config, ConfigError, Gateway, HttpHandle, Logger, Metrics, Mqtt, MqttHandle, Redis, RedisHandle, Server, ServerHandle, Settings, Stomp, StompHandle, Sys, Topics, User};
+use pubsub::server::config::parse_rate_action;
+use pubsub::server::logger::{parse_log_format, parse_log_output};
+
+use std::convert::TryFrom;
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+const USAGE: &str = "usage: pubsub-server [options]
+
+options:
+  -c, --config <path>              load settings from this toml file.
+  -l, --listen <addr>              listen on this address, may be repeated.
+      --name <name>                name this broker in the routes of bridged messages.
+      --websocket <addr>           accept websocket clients on this address, may be repeated.
+      --mqtt <addr>                accept mqtt 3.1.1 clients on this address, may be repeated.
+      --redis <addr>               accept redis pub/sub clients on this address, may be repeated.
+      --stomp <addr>               accept stomp clients on this address, may be repeated.
+      --persist <topic>            keep this topic when empty, may be repeated.
+      --metrics <addr>             serve prometheus metrics over http on this address.
+      --gateway <addr>             serve the http publish gateway on this address.
+      --log-level <level>          off, error, warn, info, debug or trace.
+      --log-format <format>        text or json.
+      --log-output <output>        stderr, stdout or a file path.
+      --user <name:password>       allow this user to authenticate, may be repeated.
+      --admin <name:password>      allow this admin to authenticate, may be repeated.
+      --ping-interval-ms <ms>      ping clients idle for this long.
+      --ping-misses <count>        disconnect after this many unanswered pings.
+      --max-connections <count>
+      --max-connections-per-ip <count>
+      --max-subscriptions <count>
+      --max-name-length <bytes>
+      --max-line-length <bytes>
+      --queue-capacity <lines>
+      --flush-latency-ms <ms>
+      --sys-interval-ms <ms>       publish broker statistics to $SYS topics.
+      --rate-action <action>       reject, delay or disconnect.
+      --check                      validate the configuration and exit.
+  -h, --help                       print this message.
+
+command line options override those in the config file. on SIGHUP, or
+the admin:reload command from an admin user, the config file is read
+again and applied to connected clients with the same overrides. messages
+are not stored, so there is no data directory: --persist only keeps a
+topic registered while it has no subscribers. SIGINT or SIGTERM stops
+the server, sending each client a goodbye line.";
+
+/// exits with this error and the usage.
+fn usage_error(message: &str) -> ! {
+    eprintln!("pubsub-server: {}\n\n{}", message, USAGE);
+    process::exit(2);
+}
+
+/// parses a flag value as a number.
+fn number(flag: &str, value: &str) -> usize {
+    value.parse().unwrap_or_else(|_| usage_error(&format!("{} expects a number, got '{}'", flag, value)))
+}
+
+/// parses a flag value as a count.
+fn count(flag: &str, value: &str) -> u32 {
+    u32::try_from(number(flag, value)).unwrap_or_else(|_| usage_error(&format!("{} expects at most {}, got '{}'", flag, u32::MAX, value)))
+}
+
+/// parses a flag value as name:password.
+fn user(flag: &str, value: &str, admin: bool) -> (String, User) {
+    let split = value.splitn(2, ':').collect::<Vec<_>>();
+    if split.len() != 2 || split[0].is_empty() {
+        usage_error(&format!("{} expects name:password, got '{}'", flag, value));
+    }
+    (split[0].to_string(), User::new(split[1].to_string(), admin))
+}
+
+/// loads the config file named by -c or --config, if any.
+fn load(args: &[String]) -> Result<Config, ConfigError> {
+    let mut config = Config::default();
+    let mut index  = 0;
+    while index < args.len() {
+        if args[index] == "-c" || args[index] == "--config" {
+            let path = args.get(index + 1).unwrap_or_else(|| usage_error("--config expects a path"));
+            config = Config::load(path)?;
+        }
+        index += 1;
+    } Ok(config)
+}
+
+/// applies the command line options over this config, returning
+/// whether --check was given.
+fn apply(args: &[String], config: &mut Config) -> bool {
+    let mut listen    = Vec::new();
+    let mut websocket = Vec::new();
+    let mut mqtt      = Vec::new();
+    let mut redis     = Vec::new();
+    let mut stomp     = Vec::new();
+    let mut check  = false;
+    let mut index  = 0;
+    while index < args.len() {
+        let flag = args[index].as_str();
+        let takes_value = !matches!(flag, "--check" | "-h" | "--help");
+        let value = if takes_value {
+            index += 1;
+            args.get(index).map(|value| value.as_str()).unwrap_or_else(|| usage_error(&format!("{} expects a value", flag)))
+        } else { "" };
+        let options = &mut config.options;
+        match flag {
+            "-c" | "--config"             => {},
+            "-l" | "--listen"             => listen.push(value.to_string()),
+            "--websocket"                 => websocket.push(value.to_string()),
+            "--mqtt"                      => mqtt.push(value.to_string()),
+            "--redis"                     => redis.push(value.to_string()),
+            "--stomp"                     => stomp.push(value.to_string()),
+            "--persist"                   => config.persist.push(value.to_string()),
+            "--metrics"                   => config.metrics = Some(value.to_string()),
+            "--gateway"                   => config.gateway = Some(value.to_string()),
+            "--name"                      => config.name = Some(value.to_string()),
+            "--log-level"                 => config.log.level  = value.parse().unwrap_or_else(|_| {
+                usage_error(&format!("--log-level expects off, error, warn, info, debug or trace, got '{}'", value))
+            }),
+            "--log-format"                => config.log.format = parse_log_format(value).unwrap_or_else(|| {
+                usage_error(&format!("--log-format expects text or json, got '{}'", value))
+            }),
+            "--log-output"                => config.log.output = parse_log_output(value),
+            "--user" | "--admin"          => {
+                let (name, user) = user(flag, value, flag == "--admin");
+                options.users.get_or_insert_with(Default::default).insert(name, user);
+            },
+            "--ping-interval-ms"          => options.ping_interval          = Some(Duration::from_millis(number(flag, value) as u64)),
+            "--ping-misses"               => options.ping_misses            = count(flag, value),
+            "--max-connections"           => options.max_connections        = Some(number(flag, value)),
+            "--max-connections-per-ip"    => options.max_connections_per_ip = Some(number(flag, value)),
+            "--max-subscriptions"         => options.max_subscriptions      = Some(number(flag, value)),
+            "--max-name-length"           => options.max_name_length        = Some(number(flag, value)),
+            "--max-line-length"           => options.max_line_length        = Some(number(flag, value)),
+            "--queue-capacity"            => options.queue_capacity         = number(flag, value),
+            "--flush-latency-ms"          => options.flush_latency          = Some(Duration::from_millis(number(flag, value) as u64)),
+            "--sys-interval-ms"           => options.sys_interval           = Some(Duration::from_millis(number(flag, value) as u64)),
+            "--rate-action"               => options.rate_action            = parse_rate_action(value).unwrap_or_else(|| {
+                usage_error(&format!("--rate-action expects reject, delay or disconnect, got '{}'", value))
+            }),
+            "--check"                     => check = true,
+            "-h" | "--help"               => {
+                println!("{}", USAGE);
+                process::exit(0);
+            },
+            _ => usage_error(&format!("unknown option '{}'", flag))
+        }
+        index += 1;
+    }
+    if !listen.is_empty() {
+        config.listen = listen;
+    }
+    if !websocket.is_empty() {
+        config.websocket = websocket;
+    }
+    if !mqtt.is_empty() {
+        config.mqtt = mqtt;
+    }
+    if !redis.is_empty() {
+        config.redis = redis;
+    }
+    if !stomp.is_empty() {
+        config.stomp = stomp;
+    } check
+}
+
+///------------------------------------
+/// Service
+///
+/// A started listener of any protocol,
+/// stopped when the process exits.
+///------------------------------------
+trait Service {
+    fn local_addr(&self) -> SocketAddr;
+    fn stop(self: Box<Self>);
+}
+impl Service for ServerHandle {
+    fn local_addr(&self) -> SocketAddr {
+        ServerHandle::local_addr(self)
+    }
+    fn stop(self: Box<Self>) {
+        if let Err(error) = self.shutdown() {
+            error!(error:% = error; "server failed");
+        }
+    }
+}
+
+impl Service for HttpHandle {
+    fn local_addr(&self) -> SocketAddr {
+        HttpHandle::local_addr(self)
+    }
+    fn stop(self: Box<Self>) {
+        self.shutdown();
+    }
+}
+
+impl Service for MqttHandle {
+    fn local_addr(&self) -> SocketAddr {
+        MqttHandle::local_addr(self)
+    }
+    fn stop(self: Box<Self>) {
+        self.shutdown();
+    }
+}
+
+impl Service for RedisHandle {
+    fn local_addr(&self) -> SocketAddr {
+        RedisHandle::local_addr(self)
+    }
+    fn stop(self: Box<Self>) {
+        self.shutdown();
+    }
+}
+
+impl Service for StompHandle {
+    fn local_addr(&self) -> SocketAddr {
+        StompHandle::local_addr(self)
+    }
+    fn stop(self: Box<Self>) {
+        self.shutdown();
+    }
+}
+
+/// starts a service on each of these addresses, exiting if any of
+/// them cannot be bound.
+fn serve<'a, I, S, F>(name: &str, addrs: I, start: F) -> Vec<Box<dyn Service>>
+    where I: IntoIterator<Item = &'a String>, S: Service + 'static, F: Fn(&str) -> io::Result<S> {
+    addrs.into_iter().map(|addr| match start(addr) {
+        Ok(service) => {
+            info!(service = name, addr:% = service.local_addr(); "listening");
+            Box::new(service) as Box<dyn Service>
+        },
+        Err(error) => {
+            error!(service = name, addr:% = addr, error:% = error; "failed to start");
+            process::exit(1);
+        }
+    }).collect()
+}
+
+/// blocks until the process is asked to stop with SIGINT or SIGTERM.
+#[cfg(unix)]
+fn wait_for_termination() {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+    match Signals::new([SIGINT, SIGTERM]) {
+        Ok(mut signals) => {
+            signals.forever().next();
+        },
+        Err(error) => {
+            error!(error:% = error; "cannot handle SIGINT or SIGTERM");
+            loop {
+                thread::park();
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn wait_for_termination() {
+    loop {
+        thread::park();
+    }
+}
+
+/// reloads the settings on each SIGHUP.
+#[cfg(unix)]
+fn reload_on_hangup(settings: Settings, topics: Topics) {
+    use signal_hook::consts::SIGHUP;
+    use signal_hook::iterator::Signals;
+    let mut signals = match Signals::new([SIGHUP]) {
+        Ok(signals) => signals,
+        Err(error)  => {
+            error!(error:% = error; "cannot handle SIGHUP");
+            return;
+        }
+    };
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            match settings.reload(&topics) {
+                Ok(warnings) => {
+                    info!("configuration reloaded");
+                    for warning in warnings {
+                        warn!(warning:% = warning; "configuration reloaded with warnings");
+                    }
+                },
+                Err(error) => error!(error:% = error; "configuration reload rejected")
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn reload_on_hangup(_: Settings, _: Topics) {}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    
+    // the config file is loaded first so flags can override it,
+    // and the result validated once both are applied.
+    let mut config = load(&args).unwrap_or_else(|error| {
+        eprintln!("pubsub-server: {}", error);
+        process::exit(2);
+    });
+    let check = apply(&args, &mut config);
+    if let Err(error) = config.validate() {
+        eprintln!("pubsub-server: {}", error);
+        process::exit(2);
+    }
+    if check {
+        println!("configuration ok");
+        return;
+    }
+    if let Err(error) = Logger::init(&config.log) {
+        eprintln!("pubsub-server: cannot open log output: {}", error);
+        process::exit(2);
+    }
+    
+    // start a server on each address, sharing one topic store.
+    let topics = Topics::new();
+    for topic_key in &config.persist {
+        topics.persist(topic_key.clone());
+    }
+    let listen    = config.listen.clone();
+    let websocket = config.websocket.clone();
+    let mqtt      = config.mqtt.clone();
+    let redis     = config.redis.clone();
+    let stomp     = config.stomp.clone();
+    let bridges   = config.bridges.clone();
+    let metrics   = config.metrics.clone();
+    let gateway   = config.gateway.clone();
+    let settings  = Settings::with_loader(config, move || {
+        let mut config = load(&args)?;
+        apply(&args, &mut config);
+        Ok(config)
+    });
+    reload_on_hangup(settings.clone(), topics.clone());
+    let sys      = Sys::spawn(topics.clone(), settings.clone());
+    let mut services = serve("pubsub", &listen, |addr| Server::start_with_settings(addr, topics.clone(), settings.clone()));
+    services.extend(serve("websocket", &websocket, |addr| Server::start_websocket(addr, topics.clone(), settings.clone())));
+    services.extend(serve("metrics", &metrics, |addr| Metrics::start(addr, topics.clone())));
+    services.extend(serve("gateway", &gateway, |addr| Gateway::start(addr, topics.clone(), settings.clone())));
+    services.extend(serve("mqtt", &mqtt, |addr| Mqtt::start(addr, topics.clone(), settings.clone())));
+    services.extend(serve("redis", &redis, |addr| Redis::start(addr, topics.clone(), settings.clone())));
+    services.extend(serve("stomp", &stomp, |addr| Stomp::start(addr, topics.clone(), settings.clone())));
+    let bridges = bridges.into_iter().map(|bridge| {
+        let (remote, address) = (bridge.remote.clone(), bridge.address.clone());
+        match Bridge::start(bridge, topics.clone(), settings.clone()) {
+            Ok(handle) => {
+                info!(remote:% = remote, addr:% = address; "bridging");
+                handle
+            },
+            Err(error) => {
+                error!(remote:% = remote, error:% = error; "failed to bridge");
+                process::exit(1);
+            }
+        }
+    }).collect::<Vec<_>>();
+    
+    // stop every service, saying goodbye to clients, on SIGINT or
+    // SIGTERM.
+    wait_for_termination();
+    info!("shutting down");
+    for bridge in bridges {
+        bridge.shutdown();
+    }
+    for service in services {
+        service.stop();
+    }
+    sys.stop();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+    
+    #[test]
+    fn flags_override_the_config_file() {
+        let mut config = Config::parse("listen = []\npersist = [\"news\"]\n[heartbeat]\nmisses = 2\n[limits]\nmax_connections = 10").unwrap();
+        let check = apply(&args(&["--listen", "127.0.0.1:0", "--persist", "sport", "--ping-misses", "5", "--admin", "root:secret"]), &mut config);
+        assert!(!check);
+        assert_eq!(config.listen, vec!["127.0.0.1:0".to_string()]);
+        assert_eq!(config.persist, vec!["news".to_string(), "sport".to_string()]);
+        assert_eq!((config.options.ping_misses, config.options.max_connections), (5, Some(10)));
+        assert!(config.options.users.as_ref().unwrap()["root"].admin);
+        assert!(config.validate().is_ok());
+    }
+    
+    #[test]
+    fn config_values_are_kept_without_flags() {
+        let mut config = Config::parse("listen = [\"127.0.0.1:0\"]").unwrap();
+        assert!(apply(&args(&["--config", "ignored.toml", "--check"]), &mut config));
+        assert_eq!(config.listen, vec!["127.0.0.1:0".to_string()]);
+    }
+}
@@ -23,3 +23,170 @@
  OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
  THE SOFTWARE.
 ---------------------------------------------------------------------------*/
+
+use std::collections::VecDeque;
+use std::io::prelude::*;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use super::super::protocol::Command;
+
+/// Client
+///
+/// A tcp client speaking the pubsub protocol. Server heartbeats are
+/// answered automatically while receiving.
+pub struct Client {
+    stream  : TcpStream,
+    reader  : BufReader<TcpStream>,
+    pending : VecDeque<Command>,
+    syncs   : usize
+}
+impl Client {
+    
+    /// connects to the server at this addr.
+    pub fn connect<T: ToSocketAddrs>(addr: T) -> Result<Client> {
+        let stream = TcpStream::connect(addr)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Client {
+            stream,
+            reader,
+            pending : VecDeque::new(),
+            syncs   : 0
+        })
+    }
+    
+    /// identifies this client as this user.
+    pub fn identity(&mut self, user: &str) -> Result<()> {
+        Client::check_field("user", user)?;
+        self.send(&Command::Identity(user.to_string()))
+    }
+    
+    /// authenticates this client as this user.
+    pub fn authenticate(&mut self, user: &str, password: &str) -> Result<()> {
+        Client::check_field("user", user)?;
+        Client::check_line("password", password)?;
+        self.send(&Command::Authenticate(user.to_string(), password.to_string()))
+    }
+    
+    /// subscribes to this topic.
+    pub fn subscribe(&mut self, topic: &str) -> Result<()> {
+        Client::check_field("topic", topic)?;
+        self.send(&Command::Subscribe(topic.to_string()))
+    }
+    
+    /// unsubscribes from this topic.
+    pub fn unsubscribe(&mut self, topic: &str) -> Result<()> {
+        Client::check_field("topic", topic)?;
+        self.send(&Command::Unsubscribe(topic.to_string()))
+    }
+    
+    /// publishes this message to this topic.
+    pub fn publish(&mut self, topic: &str, message: &str) -> Result<()> {
+        Client::check_field("topic", topic)?;
+        Client::check_line("message", message)?;
+        self.send(&Command::Publish(topic.to_string(), message.to_string()))
+    }
+    
+    /// runs this admin action on the server, returning its result
+    /// lines. Other commands received meanwhile are kept for
+    /// receive().
+    pub fn admin(&mut self, action: &str) -> Result<Vec<String>> {
+        Client::check_line("action", action)?;
+        self.send(&Command::Admin(action.to_string()))?;
+        let mut lines = Vec::new();
+        loop {
+            match self.read()? {
+                Some(Command::Reply(ref line)) if line == "end" => return Ok(lines),
+                Some(Command::Reply(line))   => lines.push(line),
+                Some(Command::Error(reason)) => return Err(Error::other(reason)),
+                Some(Command::Goodbye(reason)) => {
+                    return Err(Error::new(ErrorKind::ConnectionAborted, format!("server closed connection: {}", reason)));
+                },
+                Some(command) => self.pending.push_back(command),
+                None => return Err(Error::new(ErrorKind::UnexpectedEof, "server closed connection"))
+            }
+        }
+    }
+    
+    /// sets how long receive() waits for the server before failing
+    /// with WouldBlock or TimedOut. None waits forever.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+    
+    /// sends this command as a single line.
+    pub fn send(&mut self, command: &Command) -> Result<()> {
+        let line = format!("{}\n", command.serialize());
+        self.stream.write_all(line.as_bytes())
+    }
+    
+    /// receives the next command from the server, or None once the
+    /// server closes the connection.
+    pub fn receive(&mut self) -> Result<Option<Command>> {
+        if let Some(command) = self.pending.pop_front() {
+            return Ok(Some(command));
+        }
+        self.read()
+    }
+    
+    /// waits until the server has processed every command sent so
+    /// far. Fails with the first error the server reported in the
+    /// meantime. Other commands received are kept for receive().
+    pub fn sync(&mut self) -> Result<()> {
+        self.syncs += 1;
+        let token = format!("sync-{}", self.syncs);
+        self.send(&Command::Ping(token.clone()))?;
+        let mut error = None;
+        loop {
+            match self.read()? {
+                Some(Command::Pong(ref received)) if *received == token => break,
+                Some(Command::Error(reason)) => {
+                    if error.is_none() {
+                        error = Some(reason);
+                    }
+                },
+                Some(Command::Goodbye(reason)) => {
+                    return Err(Error::new(ErrorKind::ConnectionAborted, format!("server closed connection: {}", reason)));
+                },
+                Some(command) => self.pending.push_back(command),
+                None => return Err(Error::new(ErrorKind::UnexpectedEof, "server closed connection"))
+            }
+        }
+        match error {
+            Some(reason) => Err(Error::other(reason)),
+            None => Ok(())
+        }
+    }
+    
+    /// reads the next command, answering heartbeats.
+    fn read(&mut self) -> Result<Option<Command>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            match Command::parse(&line) {
+                Ok(Command::Ping(token)) => self.send(&Command::Pong(token))?,
+                Ok(command) => return Ok(Some(command)),
+                Err(error)  => return Err(Error::new(ErrorKind::InvalidData, error.to_string()))
+            }
+        }
+    }
+    
+    /// checks a value that must not contain delimiters.
+    fn check_field(name: &str, value: &str) -> Result<()> {
+        if value.contains(':') {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{} must not contain ':'", name)));
+        }
+        Client::check_line(name, value)
+    }
+    
+    /// checks a value that must fit on one line.
+    fn check_line(name: &str, value: &str) -> Result<()> {
+        if value.contains('\n') || value.contains('\r') {
+            return Err(Error::new(ErrorKind::InvalidInput, format!("{} must not contain line breaks", name)));
+        } Ok(())
+    }
+}
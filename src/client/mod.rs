@@ -23,3 +23,8 @@
  OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
  THE SOFTWARE.
 ---------------------------------------------------------------------------*/
+
+#[allow(clippy::module_inception)]
+pub mod client;
+
+pub use self::client::Client;
\ No newline at end of file
@@ -23,8 +23,13 @@
  OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
  THE SOFTWARE.
 ---------------------------------------------------------------------------*/
+#[macro_use]
+extern crate log;
+extern crate toml;
 extern crate uuid;
 
 pub mod protocol;
 
+pub mod client;
+
 pub mod server;
\ No newline at end of file
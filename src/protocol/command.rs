@@ -38,7 +38,7 @@ pub struct ParseError {
 }
 impl ParseError {
     pub fn new(input: String) -> ParseError {
-        ParseError { input: input }
+        ParseError { input }
     } 
 }
 impl fmt::Display for ParseError {
@@ -59,18 +59,38 @@ impl error::Error for ParseError {
 /// delimited by ':'. the following outlines the protocol.
 /// 
 ///  i:user               - (client->server) identifies this user.
+///  a:user:password      - (client->server) authenticates as this user.
 ///  s:topic              - (client->server) subscribes to this topic.
 ///  u:topic              - (client->server) unsubscribes from this topic.
 ///  p:topic:message      - (client->server) publishes this message to this topic.
+///  f:topic:route:user:message
+///                       - (client->server) publishes a message bridged from
+///                         another broker on behalf of its user. route names
+///                         the brokers it passed through, comma separated.
 ///  m:topic:user:message - (server->client) a published message sent to this topic.
+///  g:reason             - (server->client) the server is closing this connection.
+///  e:reason             - (server->client) the last command was rejected.
+///  admin:action         - (client->server) runs this admin action, e.g. reload.
+///  r:result             - (server->client) a result line of an admin action,
+///                         the last being r:end.
+///  ping:token           - (either) heartbeat request, answered with pong:token.
+///  pong:token           - (either) heartbeat response.
 ///
 #[derive(Debug)]
 pub enum Command {
   Identity      (String),       
+  Authenticate  (String, String),
   Subscribe     (String),        
   Unsubscribe   (String),        
   Publish       (String, String),
-  Message       (String, String, String)
+  Forward       (String, Vec<String>, String, String),
+  Message       (String, String, String),
+  Goodbye       (String),
+  Error         (String),
+  Admin         (String),
+  Reply         (String),
+  Ping          (String),
+  Pong          (String)
 }
 
 impl Command {
@@ -78,19 +98,31 @@ impl Command {
   pub fn serialize(&self) -> String {
     match *self {
         Command::Identity    (ref user)    => format!("i:{}", user),
+        Command::Authenticate(ref user,
+                              ref password) => format!("a:{}:{}", user, password),
         Command::Subscribe   (ref topic)   => format!("s:{}", topic),
         Command::Unsubscribe (ref topic)   => format!("u:{}", topic),
         Command::Publish     (ref topic, 
                               ref message) => format!("p:{}:{}", topic, message),
+        Command::Forward     (ref topic,
+                              ref route,
+                              ref user,
+                              ref message) => format!("f:{}:{}:{}:{}", topic, route.join(","), user, message),
         Command::Message     (ref topic, 
                               ref user, 
-                              ref message) => format!("m:{}:{}:{}", topic, user, message)
+                              ref message) => format!("m:{}:{}:{}", topic, user, message),
+        Command::Goodbye     (ref reason)  => format!("g:{}", reason),
+        Command::Error       (ref reason)  => format!("e:{}", reason),
+        Command::Admin       (ref action)  => format!("admin:{}", action),
+        Command::Reply       (ref result)  => format!("r:{}", result),
+        Command::Ping        (ref token)   => format!("ping:{}", token),
+        Command::Pong        (ref token)   => format!("pong:{}", token)
     }
   }
   
   /// parse this line into a protocol command.
   pub fn parse(command: &str) -> Result<Command, ParseError> {
-    let command = command.trim_right();
+    let command = command.trim_end();
     let split   = command.splitn(2, ":").collect::<Vec<_>>();
     if split.len() == 2 {
       match split[0] {
@@ -109,6 +141,45 @@ impl Command {
            let command = Command::Unsubscribe(topic);
            return Ok(command); 
         },
+        "g" => {
+           let reason  = split[1].to_string();
+           let command = Command::Goodbye(reason);
+           return Ok(command); 
+        },
+        "e" => {
+           let reason  = split[1].to_string();
+           let command = Command::Error(reason);
+           return Ok(command); 
+        },
+        "admin" => {
+           let action  = split[1].to_string();
+           let command = Command::Admin(action);
+           return Ok(command); 
+        },
+        "r" => {
+           let result  = split[1].to_string();
+           let command = Command::Reply(result);
+           return Ok(command); 
+        },
+        "ping" => {
+           let token   = split[1].to_string();
+           let command = Command::Ping(token);
+           return Ok(command); 
+        },
+        "pong" => {
+           let token   = split[1].to_string();
+           let command = Command::Pong(token);
+           return Ok(command); 
+        },
+        "a" => {
+            let split = split[1].splitn(2, ":").collect::<Vec<_>>();
+            if split.len() == 2 {
+                let user     = split[0].to_string();
+                let password = split[1].to_string();
+                let command  = Command::Authenticate(user, password);
+                return Ok(command);
+            }
+        },
         "p" => {
             let split = split[1].splitn(2, ":").collect::<Vec<_>>();
             if split.len() == 2 {
@@ -118,6 +189,17 @@ impl Command {
                 return Ok(command);
             }
         },
+        "f" => {
+            let split = split[1].splitn(4, ":").collect::<Vec<_>>();
+            if split.len() == 4 {
+                let topic   = split[0].to_string();
+                let route   = split[1].split(',').filter(|name| !name.is_empty()).map(|name| name.to_string()).collect();
+                let user    = split[2].to_string();
+                let message = split[3].to_string();
+                let command = Command::Forward(topic, route, user, message);
+                return Ok(command);
+            }
+        },
         "m" => {
             let split = split[1].splitn(2, ":").collect::<Vec<_>>();
             if split.len() == 2 {
@@ -132,6 +214,6 @@ impl Command {
             }
         }, _ => { /* defer to error.*/ }
       }
-    } return Err(ParseError::new(command.to_string()));
+    } Err(ParseError::new(command.to_string()))
   }
 }
@@ -0,0 +1,54 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+/// Message:
+///
+/// A message published to a topic, as delivered to in-process
+/// subscribers. This is the decoded form of Command::Message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub topic   : String,
+    pub user    : String,
+    pub message : String,
+    /// names of the brokers this message was bridged through, the
+    /// broker it was published on first. Empty for local messages.
+    pub route   : Vec<String>
+}
+impl Message {
+    pub fn new(topic: String, user: String, message: String) -> Message {
+        Message {
+            topic,
+            user,
+            message,
+            route: Vec::new()
+        }
+    }
+    
+    /// serializes this message as a Command::Message.
+    pub fn serialize(&self) -> String {
+        format!("m:{}:{}:{}", self.topic, self.user, self.message)
+    }
+}
@@ -25,6 +25,12 @@
 ---------------------------------------------------------------------------*/
 
 pub mod command;
+pub mod json;
+pub mod message;
+pub mod packet;
 
 pub use self::command::ParseError;
-pub use self::command::Command;
\ No newline at end of file
+pub use self::command::Command;
+pub use self::json::json_string;
+pub use self::message::Message;
+pub use self::packet::Packet;
\ No newline at end of file
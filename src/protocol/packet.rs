@@ -0,0 +1,59 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use std::sync::Arc;
+
+use super::message::Message;
+
+/// Packet:
+///
+/// A published message encoded once for delivery. Cloning a packet
+/// shares both the message and its encoded bytes, so fanning out to
+/// any number of subscribers costs no further allocation.
+#[derive(Debug, Clone)]
+pub struct Packet {
+    message : Arc<Message>,
+    bytes   : Arc<[u8]>
+}
+impl Packet {
+    pub fn new(message: Message) -> Packet {
+        let bytes = message.serialize();
+        Packet {
+            message : Arc::new(message),
+            bytes   : Arc::from(bytes.into_bytes())
+        }
+    }
+    
+    /// returns the message carried by this packet.
+    pub fn message(&self) -> &Message {
+        &self.message
+    }
+    
+    /// returns the encoded Command::Message for this packet.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
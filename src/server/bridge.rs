@@ -0,0 +1,272 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use uuid::Uuid;
+
+use std::io::prelude::*;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::super::protocol::{Command, Message};
+use super::settings::Settings;
+use super::subscriber::QueueSubscriber;
+use super::topics::Topics;
+
+/// number of brokers a message may pass through by default.
+pub const DEFAULT_HOPS: usize = 4;
+
+/// time allowed to connect and authenticate.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// delays between reconnection attempts, doubling up to the last.
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(30);
+
+/// interval at which the forwarder checks for shutdown.
+const POLL: Duration = Duration::from_millis(200);
+
+/// BridgeConfig
+///
+/// A remote broker that local topics are forwarded to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BridgeConfig {
+    /// name of the remote broker, as configured there.
+    pub remote   : String,
+    /// address of a native listener of the remote broker.
+    pub address  : String,
+    /// patterns of the local topics forwarded.
+    pub topics   : Vec<String>,
+    /// number of brokers a message may pass through, its origin
+    /// included.
+    pub hops     : usize,
+    /// credentials of an admin user of the remote broker, required
+    /// when it has users configured.
+    pub user     : Option<String>,
+    pub password : Option<String>
+}
+
+/// BridgeHandle
+///
+/// Handle to a running bridge.
+pub struct BridgeHandle {
+    remote  : String,
+    stop    : Sender<()>,
+    current : Arc<Mutex<Option<TcpStream>>>,
+    worker  : JoinHandle<()>
+}
+impl BridgeHandle {
+    
+    /// returns the name of the remote broker.
+    pub fn remote(&self) -> &str {
+        &self.remote
+    }
+    
+    /// stops forwarding and disconnects from the remote broker.
+    pub fn shutdown(self) {
+        drop(self.stop);
+        if let Some(ref stream) = *self.current.lock().unwrap() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        let _ = self.worker.join();
+    }
+}
+
+/// Bridge
+///
+/// Forwards local topics matching the configured patterns to a
+/// remote broker, connecting to it as a native client and
+/// reconnecting when the connection is lost. Messages are sent with
+/// f: commands carrying their route, the names of the brokers they
+/// passed through, so a message is never sent to a broker on its
+/// route nor along more than the configured hops. Brokers drop
+/// messages whose route already names them, so bridging in both
+/// directions, or around a ring, does not loop.
+///
+/// Messages published while disconnected are queued up to the queue
+/// capacity and sent once reconnected.
+pub struct Bridge;
+impl Bridge {
+    
+    /// starts forwarding to the broker in this config in the
+    /// background. This broker must have a name.
+    pub fn start(config: BridgeConfig, topics: Topics, settings: Settings) -> Result<BridgeHandle> {
+        let name = match settings.get().name {
+            Some(ref name) => name.clone(),
+            None => return Err(Error::new(ErrorKind::InvalidInput, "a broker name is required to bridge"))
+        };
+        let user_key = Uuid::new_v4().to_hyphenated_string();
+        let (sender, receiver) = sync_channel(settings.get().options.queue_capacity);
+        for pattern in &config.topics {
+            topics.psubscribe(pattern.clone(), user_key.clone(), Box::new(QueueSubscriber::new(sender.clone())));
+        }
+        drop(sender);
+        let (stop, stopped) = channel();
+        let current = Arc::new(Mutex::new(None));
+        let remote  = config.remote.clone();
+        let worker  = {
+            let current = current.clone();
+            thread::spawn(move || {
+                Bridge::run(&name, &config, &receiver, &stopped, &current);
+                topics.delete_user_key(user_key);
+            })
+        };
+        Ok(BridgeHandle { remote, stop, current, worker })
+    }
+    
+    /// connects and forwards until stopped, retrying failed
+    /// connections with a growing delay.
+    fn run(name: &str, config: &BridgeConfig, receiver: &Receiver<Message>, stopped: &Receiver<()>, current: &Mutex<Option<TcpStream>>) {
+        let mut delay = RETRY_MIN;
+        loop {
+            match Bridge::connect(config, current) {
+                Ok((stream, alive, reader)) => {
+                    info!(remote:% = config.remote, addr:% = config.address; "bridge connected");
+                    delay = RETRY_MIN;
+                    let reason = Bridge::forward(name, config, &stream, &alive, receiver, stopped);
+                    let _ = stream.lock().unwrap().shutdown(Shutdown::Both);
+                    let _ = reader.join();
+                    match reason {
+                        Some(reason) => warn!(remote:% = config.remote, reason:% = reason; "bridge disconnected"),
+                        None => return
+                    }
+                },
+                Err(error) => warn!(remote:% = config.remote, addr:% = config.address, error:% = error; "bridge connect failed")
+            }
+            *current.lock().unwrap() = None;
+            match stopped.recv_timeout(delay) {
+                Err(RecvTimeoutError::Timeout) => delay = (delay * 2).min(RETRY_MAX),
+                _ => return
+            }
+        }
+    }
+    
+    /// connects and authenticates to the remote broker, returning
+    /// the stream for writing, whether it is still connected and
+    /// the thread reading from it.
+    #[allow(clippy::type_complexity)]
+    fn connect(config: &BridgeConfig, current: &Mutex<Option<TcpStream>>) -> Result<(Arc<Mutex<TcpStream>>, Arc<AtomicBool>, JoinHandle<()>)> {
+        let mut last = Error::new(ErrorKind::InvalidInput, "address resolved to nothing");
+        let mut connected = None;
+        for addr in config.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    connected = Some(stream);
+                    break;
+                },
+                Err(error) => last = error
+            }
+        }
+        let mut stream = connected.ok_or(last)?;
+        *current.lock().unwrap() = Some(stream.try_clone()?);
+        
+        // authenticate, then wait for a pong to learn the outcome.
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        if let (Some(user), Some(password)) = (config.user.as_ref(), config.password.as_ref()) {
+            stream.write_all(format!("{}\n", Command::Authenticate(user.clone(), password.clone()).serialize()).as_bytes())?;
+        }
+        stream.write_all(format!("{}\n", Command::Ping("bridge".to_string()).serialize()).as_bytes())?;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "closed by remote"));
+            }
+            match Command::parse(&line) {
+                Ok(Command::Pong(ref token)) if token == "bridge" => break,
+                Ok(Command::Ping(token)) => stream.write_all(format!("{}\n", Command::Pong(token).serialize()).as_bytes())?,
+                Ok(Command::Error(reason)) | Ok(Command::Goodbye(reason)) => return Err(Error::new(ErrorKind::PermissionDenied, reason)),
+                _ => {}
+            }
+        }
+        stream.set_read_timeout(None)?;
+        
+        // answer heartbeats and report rejected forwards until the
+        // connection closes.
+        let alive  = Arc::new(AtomicBool::new(true));
+        let stream = Arc::new(Mutex::new(stream));
+        let reader = {
+            let (stream, alive, remote) = (stream.clone(), alive.clone(), config.remote.clone());
+            thread::spawn(move || {
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    match reader.read_line(&mut line) {
+                        Ok(0) | Err(_) => break,
+                        Ok(_) => {}
+                    }
+                    match Command::parse(&line) {
+                        Ok(Command::Ping(token)) => {
+                            let pong = format!("{}\n", Command::Pong(token).serialize());
+                            if stream.lock().unwrap().write_all(pong.as_bytes()).is_err() {
+                                break;
+                            }
+                        },
+                        Ok(Command::Error(reason)) => warn!(remote:% = remote, reason:% = reason; "bridged message rejected"),
+                        Ok(Command::Goodbye(reason)) => info!(remote:% = remote, reason:% = reason; "bridge closed by remote"),
+                        _ => {}
+                    }
+                }
+                alive.store(false, Ordering::SeqCst);
+            })
+        };
+        Ok((stream, alive, reader))
+    }
+    
+    /// forwards queued messages until stopped, returning None, or
+    /// until disconnected, returning the reason. Messages are held
+    /// in the queue while disconnected.
+    fn forward(name: &str, config: &BridgeConfig, stream: &Mutex<TcpStream>, alive: &AtomicBool, receiver: &Receiver<Message>, stopped: &Receiver<()>) -> Option<String> {
+        loop {
+            if stopped.try_recv() != Err(TryRecvError::Empty) {
+                return None;
+            }
+            if !alive.load(Ordering::SeqCst) {
+                return Some("closed by remote".to_string());
+            }
+            let message = match receiver.recv_timeout(POLL) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return None
+            };
+            if message.route.contains(&config.remote) || message.route.len() >= config.hops {
+                debug!(remote:% = config.remote, topic:% = message.topic, route:% = message.route.join(","); "bridged message not forwarded");
+                continue;
+            }
+            let mut route = message.route.clone();
+            route.push(name.to_string());
+            let line = format!("{}\n", Command::Forward(message.topic, route, message.user, message.message).serialize());
+            if let Err(error) = stream.lock().unwrap().write_all(line.as_bytes()) {
+                return Some(format!("write failed: {}", error));
+            }
+        }
+    }
+}
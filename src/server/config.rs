@@ -0,0 +1,537 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use log::LevelFilter;
+use toml::Value;
+use toml::value::Table;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
+use super::bridge::{BridgeConfig, DEFAULT_HOPS};
+use super::limiter::{Rate, RateAction};
+use super::logger::{parse_log_format, parse_log_output, LogConfig};
+use super::options::Options;
+use super::users::User;
+
+///---------------------------------------------------------------------
+///
+/// ConfigError:
+///
+///---------------------------------------------------------------------
+#[derive(Debug)]
+pub struct ConfigError {
+    message: String
+}
+impl ConfigError {
+    pub fn new(message: String) -> ConfigError {
+        ConfigError { message }
+    }
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ConfigError: - {}", self.message)
+    }
+}
+impl error::Error for ConfigError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+/// Config:
+///
+/// Server configuration as loaded from a toml file. Every key is
+/// optional; the following outlines the format.
+///
+///  name      = "site-a"           - this broker's name in bridged routes.
+///  listen    = ["127.0.0.1:5000"] - addresses to listen on.
+///  websocket = ["127.0.0.1:5001"] - addresses accepting websocket clients.
+///  mqtt      = ["127.0.0.1:1883"] - addresses accepting mqtt clients.
+///  redis     = ["127.0.0.1:6379"] - addresses accepting redis clients.
+///  stomp     = ["0.0.0.0:61613"]  - addresses accepting stomp clients.
+///  persist   = ["news"]           - topics kept when empty.
+///  metrics   = "127.0.0.1:9100"   - address serving /metrics over http.
+///  gateway   = "127.0.0.1:8080"   - address serving the http gateway.
+///
+///  [log]
+///  level  = "info"                - off, error, warn, info, debug or trace.
+///  format = "text"                - text or json.
+///  output = "stderr"              - stderr, stdout or a file path.
+///
+///  [heartbeat]
+///  interval_ms = 30000            - Options::ping_interval
+///  misses      = 2                - Options::ping_misses
+///
+///  [limits]
+///  max_connections        = 1000
+///  max_connections_per_ip = 16
+///  max_subscriptions      = 256
+///  max_name_length        = 256
+///  max_line_length        = 65536
+///
+///  [output]
+///  queue_capacity   = 1024
+///  flush_latency_ms = 1
+///
+///  [sys]
+///  interval_ms = 10000            - Options::sys_interval
+///
+///  [rates]
+///  action     = "reject"          - reject, delay or disconnect.
+///  connection = { messages = 100, bytes = 65536 }
+///  user       = { messages = 1000 }
+///  topic      = { messages = 10000 }
+///
+///  [users.alice]
+///  password = "secret"
+///  admin    = false
+///
+///  [bridges.site-b]               - keyed by the remote broker's name.
+///  address  = "10.0.0.2:5000"     - a native listener of the remote.
+///  topics   = ["orders/*"]        - patterns of the topics forwarded.
+///  hops     = 4                   - brokers a message may pass through.
+///  user     = "bridge"            - an admin user of the remote, if it
+///  password = "secret"              has users configured.
+///
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub name      : Option<String>,
+    pub listen    : Vec<String>,
+    pub websocket : Vec<String>,
+    pub mqtt      : Vec<String>,
+    pub redis     : Vec<String>,
+    pub stomp     : Vec<String>,
+    pub persist : Vec<String>,
+    pub metrics : Option<String>,
+    pub gateway : Option<String>,
+    pub bridges : Vec<BridgeConfig>,
+    pub log     : LogConfig,
+    pub options : Options
+}
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            name      : None,
+            listen    : vec!["127.0.0.1:5000".to_string()],
+            websocket : Vec::new(),
+            mqtt      : Vec::new(),
+            redis     : Vec::new(),
+            stomp     : Vec::new(),
+            persist : Vec::new(),
+            metrics : None,
+            gateway : None,
+            bridges : Vec::new(),
+            log     : LogConfig::default(),
+            options : Options::default()
+        }
+    }
+}
+impl Config {
+    
+    /// loads the config file at this path. The config is not
+    /// validated, so overrides can be applied before validate.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|error| {
+            ConfigError::new(format!("{}: {}", path.display(), error))
+        })?;
+        Config::parse(&text).map_err(|error| {
+            ConfigError::new(format!("{}: {}", path.display(), error.message))
+        })
+    }
+    
+    /// parses this toml config. The config is not validated, so
+    /// overrides can be applied before validate.
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let root = text.parse::<Value>().map_err(|error| ConfigError::new(error.to_string()))?;
+        let root = match root {
+            Value::Table(table) => table,
+            _ => return Err(ConfigError::new("expected a table".to_string()))
+        };
+        let mut config = Config::default();
+        let root = Section::new(String::new(), &root);
+        root.check_keys(&["name", "listen", "websocket", "mqtt", "redis", "stomp", "persist", "metrics", "gateway", "bridges", "log", "heartbeat", "limits", "output", "sys", "rates", "users"])?;
+        if let Some(listen) = root.strings("listen")? {
+            config.listen = listen;
+        }
+        if let Some(websocket) = root.strings("websocket")? {
+            config.websocket = websocket;
+        }
+        if let Some(mqtt) = root.strings("mqtt")? {
+            config.mqtt = mqtt;
+        }
+        if let Some(redis) = root.strings("redis")? {
+            config.redis = redis;
+        }
+        if let Some(stomp) = root.strings("stomp")? {
+            config.stomp = stomp;
+        }
+        if let Some(persist) = root.strings("persist")? {
+            config.persist = persist;
+        }
+        config.metrics = root.string("metrics")?;
+        config.gateway = root.string("gateway")?;
+        config.name    = root.string("name")?;
+        if let Some(bridges) = root.section("bridges")? {
+            for remote in bridges.table.keys() {
+                let bridge = bridges.section(remote)?.ok_or_else(|| bridges.error(remote, "expected a table"))?;
+                bridge.check_keys(&["address", "topics", "hops", "user", "password"])?;
+                config.bridges.push(BridgeConfig {
+                    remote   : remote.clone(),
+                    address  : bridge.string("address")?.ok_or_else(|| bridge.error("address", "is required"))?,
+                    topics   : bridge.strings("topics")?.unwrap_or_default(),
+                    hops     : bridge.number("hops")?.unwrap_or(DEFAULT_HOPS),
+                    user     : bridge.string("user")?,
+                    password : bridge.string("password")?
+                });
+            }
+        }
+        if let Some(log) = root.section("log")? {
+            log.check_keys(&["level", "format", "output"])?;
+            if let Some(level) = log.string("level")? {
+                config.log.level = level.parse::<LevelFilter>().map_err(|_| {
+                    log.error("level", "expected one of off, error, warn, info, debug or trace")
+                })?;
+            }
+            if let Some(format) = log.string("format")? {
+                config.log.format = parse_log_format(&format).ok_or_else(|| log.error("format", "expected text or json"))?;
+            }
+            if let Some(output) = log.string("output")? {
+                config.log.output = parse_log_output(&output);
+            }
+        }
+        {
+            let options = &mut config.options;
+            if let Some(heartbeat) = root.section("heartbeat")? {
+                heartbeat.check_keys(&["interval_ms", "misses"])?;
+                options.ping_interval = heartbeat.number("interval_ms")?.map(|ms| Duration::from_millis(ms as u64));
+                if let Some(misses) = heartbeat.count("misses")? {
+                    options.ping_misses = misses;
+                }
+            }
+            if let Some(limits) = root.section("limits")? {
+                limits.check_keys(&["max_connections", "max_connections_per_ip", "max_subscriptions", "max_name_length", "max_line_length"])?;
+                options.max_connections        = limits.number("max_connections")?;
+                options.max_connections_per_ip = limits.number("max_connections_per_ip")?;
+                options.max_subscriptions      = limits.number("max_subscriptions")?;
+                options.max_name_length        = limits.number("max_name_length")?;
+                options.max_line_length        = limits.number("max_line_length")?;
+            }
+            if let Some(output) = root.section("output")? {
+                output.check_keys(&["queue_capacity", "flush_latency_ms"])?;
+                if let Some(capacity) = output.number("queue_capacity")? {
+                    options.queue_capacity = capacity;
+                }
+                options.flush_latency = output.number("flush_latency_ms")?.map(|ms| Duration::from_millis(ms as u64));
+            }
+            if let Some(sys) = root.section("sys")? {
+                sys.check_keys(&["interval_ms"])?;
+                options.sys_interval = sys.number("interval_ms")?.map(|ms| Duration::from_millis(ms as u64));
+            }
+            if let Some(rates) = root.section("rates")? {
+                rates.check_keys(&["action", "connection", "user", "topic"])?;
+                if let Some(action) = rates.string("action")? {
+                    options.rate_action = parse_rate_action(&action).ok_or_else(|| {
+                        rates.error("action", "expected one of reject, delay or disconnect")
+                    })?;
+                }
+                options.connection_rate = rates.rate("connection")?;
+                options.user_rate       = rates.rate("user")?;
+                options.topic_rate      = rates.rate("topic")?;
+            }
+            if let Some(users) = root.section("users")? {
+                let mut dict = HashMap::new();
+                for name in users.table.keys() {
+                    let user = users.section(name)?.ok_or_else(|| users.error(name, "expected a table"))?;
+                    user.check_keys(&["password", "admin"])?;
+                    let password = user.string("password")?.ok_or_else(|| user.error("password", "is required"))?;
+                    let admin    = user.boolean("admin")?.unwrap_or(false);
+                    dict.insert(name.clone(), User::new(password, admin));
+                }
+                options.users = Some(dict);
+            }
+        } Ok(config)
+    }
+    
+    /// validates this config, checking values that cannot be used
+    /// to start a server.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.listen.is_empty() {
+            return Err(ConfigError::new("listen: expected at least one address".to_string()));
+        }
+        let addrs = self.listen.iter().map(|addr| ("listen", addr))
+            .chain(self.websocket.iter().map(|addr| ("websocket", addr)))
+            .chain(self.mqtt.iter().map(|addr| ("mqtt", addr)))
+            .chain(self.redis.iter().map(|addr| ("redis", addr)))
+            .chain(self.stomp.iter().map(|addr| ("stomp", addr)))
+            .chain(self.metrics.iter().map(|addr| ("metrics", addr)))
+            .chain(self.gateway.iter().map(|addr| ("gateway", addr)));
+        for (key, addr) in addrs {
+            let resolved = addr.to_socket_addrs().map(|addrs| addrs.collect::<Vec<SocketAddr>>());
+            match resolved {
+                Ok(ref addrs) if !addrs.is_empty() => {},
+                _ => return Err(ConfigError::new(format!("{}: invalid address '{}'", key, addr)))
+            }
+        }
+        let invalid_name = |name: &str| name.is_empty() || name.contains(':') || name.contains(',') || name.contains('\n') || name.contains('\r');
+        if let Some(ref name) = self.name {
+            if invalid_name(name) {
+                return Err(ConfigError::new("name: must be non empty without ':', ',' or line breaks".to_string()));
+            }
+        }
+        for bridge in &self.bridges {
+            let key = format!("bridges.{}", bridge.remote);
+            if self.name.is_none() {
+                return Err(ConfigError::new("name: is required when bridges are configured".to_string()));
+            }
+            if invalid_name(&bridge.remote) {
+                return Err(ConfigError::new(format!("{}: remote names must be non empty without ':', ',' or line breaks", key)));
+            }
+            if self.name.as_ref() == Some(&bridge.remote) {
+                return Err(ConfigError::new(format!("{}: must not name this broker", key)));
+            }
+            if bridge.hops == 0 {
+                return Err(ConfigError::new(format!("{}.hops: must be greater than 0", key)));
+            }
+            if bridge.user.is_some() != bridge.password.is_some() {
+                return Err(ConfigError::new(format!("{}: user and password must be given together", key)));
+            }
+        }
+        let options = &self.options;
+        if options.ping_interval == Some(Duration::from_millis(0)) {
+            return Err(ConfigError::new("heartbeat.interval_ms: must be greater than 0".to_string()));
+        }
+        if options.sys_interval == Some(Duration::from_millis(0)) {
+            return Err(ConfigError::new("sys.interval_ms: must be greater than 0".to_string()));
+        }
+        if options.queue_capacity == 0 {
+            return Err(ConfigError::new("output.queue_capacity: must be greater than 0".to_string()));
+        }
+        if options.max_line_length == Some(0) {
+            return Err(ConfigError::new("limits.max_line_length: must be greater than 0".to_string()));
+        }
+        let rates = [("connection", options.connection_rate), ("user", options.user_rate), ("topic", options.topic_rate)];
+        for &(name, rate) in &rates {
+            if let Some(rate) = rate {
+                if rate.messages == Some(0) || rate.bytes == Some(0) {
+                    return Err(ConfigError::new(format!("rates.{}: rates must be greater than 0", name)));
+                }
+            }
+        } Ok(())
+    }
+}
+
+/// parses a rate action name.
+pub fn parse_rate_action(action: &str) -> Option<RateAction> {
+    match action {
+        "reject"     => Some(RateAction::Reject),
+        "delay"      => Some(RateAction::Delay),
+        "disconnect" => Some(RateAction::Disconnect),
+        _ => None
+    }
+}
+
+///------------------------------------
+/// Section
+///
+/// A toml table with its dotted path,
+/// used to report errors against the
+/// key that caused them.
+///------------------------------------
+struct Section<'a> {
+    path  : String,
+    table : &'a Table
+}
+impl<'a> Section<'a> {
+    fn new(path: String, table: &'a Table) -> Section<'a> {
+        Section { path, table }
+    }
+    
+    fn key(&self, key: &str) -> String {
+        if self.path.is_empty() { key.to_string() } else { format!("{}.{}", self.path, key) }
+    }
+    
+    fn error(&self, key: &str, message: &str) -> ConfigError {
+        ConfigError::new(format!("{}: {}", self.key(key), message))
+    }
+    
+    fn check_keys(&self, allowed: &[&str]) -> Result<(), ConfigError> {
+        for key in self.table.keys() {
+            if !allowed.contains(&key.as_str()) {
+                return Err(self.error(key, "unknown key"));
+            }
+        } Ok(())
+    }
+    
+    fn section(&self, key: &str) -> Result<Option<Section<'a>>, ConfigError> {
+        match self.table.get(key) {
+            None                       => Ok(None),
+            Some(Value::Table(table)) => Ok(Some(Section::new(self.key(key), table))),
+            Some(_)                    => Err(self.error(key, "expected a table"))
+        }
+    }
+    
+    fn number(&self, key: &str) -> Result<Option<usize>, ConfigError> {
+        match self.table.get(key) {
+            None                                     => Ok(None),
+            Some(&Value::Integer(value)) if value >= 0 => Ok(Some(value as usize)),
+            Some(_)                                  => Err(self.error(key, "expected a non-negative integer"))
+        }
+    }
+    
+    fn count(&self, key: &str) -> Result<Option<u32>, ConfigError> {
+        match self.number(key)? {
+            None        => Ok(None),
+            Some(value) => u32::try_from(value).map(Some).map_err(|_| {
+                self.error(key, &format!("expected an integer no greater than {}", u32::MAX))
+            })
+        }
+    }
+    
+    fn boolean(&self, key: &str) -> Result<Option<bool>, ConfigError> {
+        match self.table.get(key) {
+            None                          => Ok(None),
+            Some(&Value::Boolean(value)) => Ok(Some(value)),
+            Some(_)                       => Err(self.error(key, "expected a boolean"))
+        }
+    }
+    
+    fn string(&self, key: &str) -> Result<Option<String>, ConfigError> {
+        match self.table.get(key) {
+            None                         => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            Some(_)                      => Err(self.error(key, "expected a string"))
+        }
+    }
+    
+    fn strings(&self, key: &str) -> Result<Option<Vec<String>>, ConfigError> {
+        match self.table.get(key) {
+            None                        => Ok(None),
+            Some(Value::Array(values)) => values.iter().map(|value| match *value {
+                Value::String(ref value) => Ok(value.clone()),
+                _ => Err(self.error(key, "expected an array of strings"))
+            }).collect::<Result<Vec<_>, _>>().map(Some),
+            Some(_)                     => Err(self.error(key, "expected an array of strings"))
+        }
+    }
+    
+    fn rate(&self, key: &str) -> Result<Option<Rate>, ConfigError> {
+        match self.section(key)? {
+            None       => Ok(None),
+            Some(rate) => {
+                rate.check_keys(&["messages", "bytes"])?;
+                Ok(Some(Rate {
+                    messages : rate.count("messages")?,
+                    bytes    : rate.count("bytes")?
+                }))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    /// returns the message of the error parsing this config.
+    fn parse_error(text: &str) -> String {
+        Config::parse(text).unwrap_err().message
+    }
+    
+    #[test]
+    fn parse() {
+        let config = Config::parse(r#"
+            listen  = ["127.0.0.1:0", "127.0.0.1:1"]
+            persist = ["news"]
+            
+            [heartbeat]
+            interval_ms = 250
+            misses      = 3
+            
+            [limits]
+            max_connections = 10
+            max_line_length = 1024
+            
+            [output]
+            queue_capacity   = 8
+            flush_latency_ms = 2
+            
+            [rates]
+            action     = "delay"
+            connection = { messages = 100, bytes = 65536 }
+            
+            [users.alice]
+            password = "secret"
+            admin    = true
+        "#).unwrap();
+        assert_eq!(config.listen, vec!["127.0.0.1:0".to_string(), "127.0.0.1:1".to_string()]);
+        assert_eq!(config.persist, vec!["news".to_string()]);
+        let options = &config.options;
+        assert_eq!((options.ping_interval, options.ping_misses), (Some(Duration::from_millis(250)), 3));
+        assert_eq!((options.max_connections, options.max_connections_per_ip, options.max_line_length), (Some(10), None, Some(1024)));
+        assert_eq!((options.queue_capacity, options.flush_latency), (8, Some(Duration::from_millis(2))));
+        assert_eq!(options.rate_action, RateAction::Delay);
+        assert_eq!(options.connection_rate, Some(Rate { messages: Some(100), bytes: Some(65536) }));
+        assert_eq!(options.user_rate, None);
+        let alice = &options.users.as_ref().unwrap()["alice"];
+        assert!(alice.admin && alice.verify("secret"));
+        assert!(config.validate().is_ok());
+    }
+    
+    #[test]
+    fn parse_errors_name_the_key() {
+        assert_eq!(parse_error("port = 5000"), "port: unknown key");
+        assert_eq!(parse_error("listen = \"127.0.0.1:5000\""), "listen: expected an array of strings");
+        assert_eq!(parse_error("[limits]\nmax_connections = -1"), "limits.max_connections: expected a non-negative integer");
+        assert_eq!(parse_error("[rates]\naction = \"drop\""), "rates.action: expected one of reject, delay or disconnect");
+        assert_eq!(parse_error("[users.alice]\nadmin = true"), "users.alice.password: is required");
+        assert_eq!(parse_error("[heartbeat]\nmisses = 4294967296"), "heartbeat.misses: expected an integer no greater than 4294967295");
+        assert_eq!(parse_error("[rates.user]\nbytes = 4294967296"), "rates.user.bytes: expected an integer no greater than 4294967295");
+    }
+    
+    #[test]
+    fn validation_is_left_to_the_caller() {
+        let mut config = Config::parse("listen = []\n[output]\nqueue_capacity = 0").unwrap();
+        assert_eq!(config.validate().unwrap_err().message, "listen: expected at least one address");
+        config.listen = vec!["127.0.0.1:0".to_string()];
+        assert_eq!(config.validate().unwrap_err().message, "output.queue_capacity: must be greater than 0");
+        config.options.queue_capacity = 1;
+        assert!(config.validate().is_ok());
+        
+        config.listen = vec!["not an address".to_string()];
+        assert_eq!(config.validate().unwrap_err().message, "listen: invalid address 'not an address'");
+        config.listen = vec!["127.0.0.1:0".to_string()];
+        config.options.user_rate = Some(Rate { messages: Some(0), bytes: None });
+        assert_eq!(config.validate().unwrap_err().message, "rates.user: rates must be greater than 0");
+    }
+}
@@ -0,0 +1,357 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::super::protocol::Command;
+use super::options::Options;
+use super::users::User;
+use super::writer::Writer;
+
+/// the user a client authenticated as and the credentials it
+/// presented, shared between its reader and the registry.
+pub type Identity = Arc<Mutex<Option<(String, User)>>>;
+
+///------------------------------------
+/// Connection
+///
+/// A client connection tracked by the
+/// server. The listener is the address
+/// it was accepted on. The key holds the
+/// client's subscriptions and the user
+/// name is shared with the client so
+/// identity changes are seen here.
+///------------------------------------
+pub struct Connection {
+    pub stream   : TcpStream,
+    pub addr     : SocketAddr,
+    pub listener : SocketAddr,
+    pub writer   : Writer,
+    pub identity : Identity,
+    pub key      : String,
+    pub user     : Arc<Mutex<String>>
+}
+
+///------------------------------------
+/// ClientInfo
+///
+/// A snapshot of a client connection
+/// for introspection.
+///------------------------------------
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub id       : usize,
+    pub addr     : SocketAddr,
+    pub listener : SocketAddr,
+    pub key      : String,
+    pub user     : String,
+    pub depth    : usize,
+    pub dropped  : usize
+}
+
+//------------------------------------
+// Connections
+//
+// The clients of every listener sharing
+// a topic store, so connection limits
+// apply to the broker as a whole.
+//------------------------------------
+#[derive(Clone, Default)]
+pub struct Connections {
+    next : Arc<AtomicUsize>,
+    dict : Arc<Mutex<HashMap<usize, Connection>>>
+}
+impl Connections {
+    pub fn new() -> Connections {
+        Connections {
+            next: Arc::new(AtomicUsize::new(0)),
+            dict: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+    
+    ///-----------------------------------------
+    /// registers this connection, returning
+    /// its id, or the reason it is refused if
+    /// it would exceed the connection limits of
+    /// these options. Limits are checked under
+    /// the same lock as the insert, so listeners
+    /// accepting at once cannot exceed them.
+    ///-----------------------------------------
+    pub fn insert(&self, options: &Options, connection: Connection) -> Result<usize, String> {
+        let mut dict = self.dict.lock().unwrap();
+        if let Some(max) = options.max_connections {
+            if dict.len() >= max {
+                return Err(format!("connection limit of {} reached", max));
+            }
+        }
+        if let Some(max) = options.max_connections_per_ip {
+            let ip = connection.addr.ip();
+            if dict.values().filter(|other| other.addr.ip() == ip).count() >= max {
+                return Err(format!("connection limit of {} for {} reached", max, ip));
+            }
+        }
+        let id = self.next.fetch_add(1, Ordering::SeqCst);
+        dict.insert(id, connection);
+        Ok(id)
+    }
+    
+    ///-----------------------------------------
+    /// removes this connection.
+    ///-----------------------------------------
+    pub fn remove(&self, id: usize) {
+        let mut dict = self.dict.lock().unwrap();
+        dict.remove(&id);
+    }
+    
+    ///-----------------------------------------
+    /// returns the number of connections.
+    ///-----------------------------------------
+    pub fn len(&self) -> usize {
+        self.dict.lock().unwrap().len()
+    }
+    
+    ///-----------------------------------------
+    /// returns the number of connections
+    /// accepted on this listener.
+    ///-----------------------------------------
+    pub fn count(&self, listener: SocketAddr) -> usize {
+        let dict = self.dict.lock().unwrap();
+        dict.values().filter(|connection| connection.listener == listener).count()
+    }
+    
+    ///-----------------------------------------
+    /// returns a snapshot of this client.
+    ///-----------------------------------------
+    pub fn get(&self, id: usize) -> Option<ClientInfo> {
+        let dict = self.dict.lock().unwrap();
+        dict.get(&id).map(|connection| Connections::info(id, connection))
+    }
+    
+    ///-----------------------------------------
+    /// returns a snapshot of every client,
+    /// ordered by id.
+    ///-----------------------------------------
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        let dict = self.dict.lock().unwrap();
+        let mut clients = dict.iter().map(|(id, connection)| Connections::info(*id, connection)).collect::<Vec<_>>();
+        clients.sort_by_key(|client| client.id);
+        clients
+    }
+    
+    ///-----------------------------------------
+    /// disconnects this client, sending it a
+    /// goodbye line after any queued output.
+    /// Output still pending when the timeout
+    /// elapses is abandoned. Returns false if
+    /// there is no such client.
+    ///-----------------------------------------
+    pub fn kick(&self, id: usize, reason: &str, timeout: Duration) -> bool {
+        let (stream, writer) = {
+            let dict = self.dict.lock().unwrap();
+            match dict.get(&id) {
+                Some(connection) => match connection.stream.try_clone() {
+                    Ok(stream) => (stream, connection.writer.clone()),
+                    Err(_)     => return false
+                },
+                None => return false
+            }
+        };
+        // queue the goodbye before the client sees end of input and
+        // closes its writer.
+        let _ = writer.send(Command::Goodbye(reason.to_string()));
+        writer.try_close();
+        let _ = stream.shutdown(Shutdown::Read);
+        writer.wait(Instant::now() + timeout);
+        let _ = stream.shutdown(Shutdown::Both);
+        true
+    }
+    
+    /// returns a snapshot of this connection.
+    fn info(id: usize, connection: &Connection) -> ClientInfo {
+        ClientInfo {
+            id,
+            addr     : connection.addr,
+            listener : connection.listener,
+            key      : connection.key.clone(),
+            user     : connection.user.lock().unwrap().clone(),
+            depth    : connection.writer.depth(),
+            dropped  : connection.writer.dropped()
+        }
+    }
+    
+    ///-----------------------------------------
+    /// returns true if there are no connections.
+    ///-----------------------------------------
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    
+    ///-----------------------------------------
+    /// disconnects every client authenticated
+    /// as a user these users no longer contain,
+    /// or whose password has changed, returning
+    /// how many were disconnected. Each is sent
+    /// a goodbye line and its reader stopped.
+    /// Nothing is revoked when authentication
+    /// is disabled.
+    ///-----------------------------------------
+    pub fn revoke(&self, users: Option<&HashMap<String, User>>) -> usize {
+        let users = match users {
+            Some(users) => users,
+            None        => return 0
+        };
+        let dict    = self.dict.lock().unwrap();
+        let mut revoked = 0;
+        for (id, connection) in dict.iter() {
+            let reason = match *connection.identity.lock().unwrap() {
+                Some((ref name, ref user)) => match revoked_reason(users, name, user) {
+                    Some(reason) => {
+                        info!(conn = id, user:% = name, reason:% = reason; "client revoked");
+                        reason
+                    },
+                    None => continue
+                },
+                None => continue
+            };
+            let _ = connection.writer.send(Command::Goodbye(reason.to_string()));
+            let _ = connection.stream.shutdown(Shutdown::Read);
+            revoked += 1;
+        } revoked
+    }
+    
+    ///-----------------------------------------
+    /// closes every connection accepted on this
+    /// listener, sending each a goodbye line
+    /// after any queued output. Output still
+    /// pending when the timeout elapses is
+    /// abandoned.
+    ///-----------------------------------------
+    pub fn close(&self, listener: SocketAddr, reason: &str, timeout: Duration) {
+        let connections = {
+            let mut dict = self.dict.lock().unwrap();
+            let ids = dict.iter().filter(|&(_, connection)| connection.listener == listener).map(|(id, _)| *id).collect::<Vec<_>>();
+            ids.iter().filter_map(|id| dict.remove(id)).collect::<Vec<_>>()
+        };
+        let deadline = Instant::now() + timeout;
+        for connection in &connections {
+            let _ = connection.writer.send(Command::Goodbye(reason.to_string()));
+            connection.writer.try_close();
+            let _ = connection.stream.shutdown(Shutdown::Read);
+        }
+        for connection in &connections {
+            connection.writer.wait(deadline);
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// returns why a client authenticated as this user with these
+/// credentials is no longer allowed by these users, if it is not.
+pub fn revoked_reason(users: &HashMap<String, User>, name: &str, user: &User) -> Option<&'static str> {
+    match users.get(name) {
+        None => Some("user removed"),
+        Some(current) if current.password != user.password => Some("credentials changed"),
+        Some(_) => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use super::super::stats::Stats;
+    use super::super::writer::Framing;
+    
+    /// returns a connection over loopback accepted on this listener.
+    fn connection(listener: &TcpListener) -> Connection {
+        let local  = listener.local_addr().unwrap();
+        let stream = TcpStream::connect(local).unwrap();
+        let writer = Writer::spawn(stream.try_clone().unwrap(), Framing::Lines, 16, None, Stats::new()).0;
+        Connection { addr: stream.local_addr().unwrap(), stream, listener: local, writer, identity: Identity::default(), key: String::new(), user: Arc::default() }
+    }
+    
+    /// returns users with these names and passwords.
+    fn users(users: &[(&str, &str)]) -> HashMap<String, User> {
+        users.iter().map(|&(name, password)| (name.to_string(), User::new(password.to_string(), false))).collect()
+    }
+    
+    #[test]
+    fn limits() {
+        let listener    = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connections = Connections::new();
+        let options     = Options { max_connections: Some(2), max_connections_per_ip: Some(1), ..Options::default() };
+        let first = connections.insert(&options, connection(&listener)).unwrap();
+        assert_eq!(connections.insert(&options, connection(&listener)), Err("connection limit of 1 for 127.0.0.1 reached".to_string()));
+        
+        let options = Options { max_connections: Some(2), ..Options::default() };
+        assert!(connections.insert(&options, connection(&listener)).is_ok());
+        assert_eq!(connections.insert(&options, connection(&listener)), Err("connection limit of 2 reached".to_string()));
+        
+        connections.remove(first);
+        assert!(connections.insert(&options, connection(&listener)).is_ok());
+        assert_eq!(connections.len(), 2);
+    }
+    
+    #[test]
+    fn close_by_listener() {
+        let (one, two)  = (TcpListener::bind("127.0.0.1:0").unwrap(), TcpListener::bind("127.0.0.1:0").unwrap());
+        let connections = Connections::new();
+        let options     = Options::default();
+        for listener in [&one, &two, &two].iter() {
+            connections.insert(&options, connection(listener)).unwrap();
+        }
+        assert_eq!(connections.count(two.local_addr().unwrap()), 2);
+        connections.close(two.local_addr().unwrap(), "shutdown", Duration::from_millis(100));
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections.count(one.local_addr().unwrap()), 1);
+    }
+    
+    #[test]
+    fn revoke() {
+        let listener    = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connections = Connections::new();
+        let before      = users(&[("anne", "a"), ("bill", "b"), ("carl", "c")]);
+        for name in &["anne", "bill", "carl", ""] {
+            let connection = connection(&listener);
+            if let Some(user) = before.get(*name) {
+                *connection.identity.lock().unwrap() = Some((name.to_string(), user.clone()));
+            }
+            connections.insert(&Options::default(), connection).unwrap();
+        }
+        assert_eq!(connections.revoke(None), 0);
+        assert_eq!(connections.revoke(Some(&before)), 0);
+        assert_eq!(connections.revoke(Some(&users(&[("bill", "changed"), ("carl", "c")]))), 2);
+        
+        let after = users(&[("carl", "c")]);
+        assert_eq!(revoked_reason(&after, "anne", &before["anne"]), Some("user removed"));
+        assert_eq!(revoked_reason(&users(&[("bill", "changed")]), "bill", &before["bill"]), Some("credentials changed"));
+        assert_eq!(revoked_reason(&after, "carl", &before["carl"]), None);
+    }
+}
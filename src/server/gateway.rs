@@ -0,0 +1,475 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use std::io::prelude::*;
+use std::io::{ErrorKind, Result};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use super::super::protocol::{json_string, Packet};
+
+use super::connections::Connection;
+use super::http::{self, base64_decode, decode, HttpHandle, Request, Response};
+use super::limiter::RateAction;
+use super::options::Options;
+use super::settings::Settings;
+use super::subscriber::Subscriber;
+use super::sys::is_reserved;
+use super::topics::Topics;
+use super::writer::{Framing, Writer};
+
+/// user publishing when no identity is given.
+const DEFAULT_USER: &str = "http";
+
+/// header naming the publishing user when authentication is off.
+const USER_HEADER: &str = "x-pubsub-user";
+
+/// interval between keepalive comments on idle event streams.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Gateway
+///
+/// Serves a topic store over http for clients that cannot hold a
+/// tcp session. The following outlines the routes.
+///
+///  POST /topics/{topic} - publishes the body to this topic.
+///  GET  /topics         - lists topics and their subscribers.
+///  GET  /topics/{topic} - shows the subscribers of this topic.
+///  GET  /subscribe       - streams messages as server-sent events.
+///
+/// Topics are percent decoded and may contain '/'. Publishes follow
+/// the same limits and rates as tcp clients, except connection
+/// rates. When users are configured the publisher authenticates
+/// with basic auth, otherwise it is named by the X-Pubsub-User
+/// header, defaulting to "http".
+///
+/// Event streams subscribe to each topic=[topic] query parameter,
+/// authenticating like publishers. Each message is sent as an event
+/// named after its topic, idle streams receive a keepalive comment.
+///
+///  event: [topic]
+///  data: {"topic":[topic],"user":[user],"message":[message]}
+pub struct Gateway;
+impl Gateway {
+    
+    /// starts a gateway on this addr in the background.
+    pub fn start<T: ToSocketAddrs>(addr: T, topics: Topics, settings: Settings) -> Result<HttpHandle> {
+        http::serve(addr, topics.connections().clone(), move |request, peer| {
+            let config   = settings.get();
+            let response = Gateway::route(&config.options, &topics, request);
+            debug!(peer:% = peer, method:% = request.method, path:% = request.path, status = response.status; "http request");
+            response
+        })
+    }
+    
+    /// answers this request.
+    fn route(options: &Options, topics: &Topics, request: &Request) -> Response {
+        let path = request.path.split('?').next().unwrap_or("");
+        let topic_key = match path {
+            "/subscribe" if request.method == "GET" => return Gateway::subscribe(options, topics, request),
+            "/subscribe" => return Response::text(405, "method not allowed").header("Allow", "GET".to_string()),
+            "/topics" | "/topics/" => None,
+            _ if path.starts_with("/topics/") => match decode(&path["/topics/".len()..]) {
+                Some(topic_key) => Some(topic_key),
+                None => return Response::text(400, "malformed topic")
+            },
+            _ => return Response::text(404, "not found")
+        };
+        match (request.method.as_str(), topic_key) {
+            ("GET", None) => {
+                let mut subscriptions = topics.subscriptions();
+                subscriptions.sort();
+                let entries = subscriptions.iter()
+                    .map(|&(ref topic_key, count)| format!("{{\"topic\":{},\"subscribers\":{}}}", json_string(topic_key), count))
+                    .collect::<Vec<_>>();
+                Response::json(200, format!("[{}]\n", entries.join(",")))
+            },
+            ("GET", Some(topic_key)) => match topics.subscriptions().into_iter().find(|entry| entry.0 == topic_key) {
+                Some((_, count)) => Response::json(200, format!("{{\"topic\":{},\"subscribers\":{}}}\n", json_string(&topic_key), count)),
+                None => Response::text(404, "topic not found")
+            },
+            ("POST", Some(topic_key)) => Gateway::publish(options, topics, request, topic_key),
+            (_, None) => Response::text(405, "method not allowed").header("Allow", "GET".to_string()),
+            (_, Some(_)) => Response::text(405, "method not allowed").header("Allow", "GET, POST".to_string())
+        }
+    }
+    
+    /// publishes the body of this request to this topic.
+    fn publish(options: &Options, topics: &Topics, request: &Request, topic_key: String) -> Response {
+        let user_key = match Gateway::identity(options, request) {
+            Ok(user_key) => user_key,
+            Err(response) => return response
+        };
+        if let Some(reason) = options.check_name("topic", &topic_key) {
+            return Response::text(400, &reason);
+        }
+        if is_reserved(&topic_key) {
+            return Response::text(403, &format!("topic {} is reserved", topic_key));
+        }
+        let message = match String::from_utf8(request.body.clone()) {
+            Ok(message) => message,
+            Err(_) => return Response::text(400, "message must be utf-8")
+        };
+        if message.contains('\n') || message.contains('\r') {
+            return Response::text(400, "message must not contain line breaks");
+        }
+        if let Some(max) = options.max_line_length {
+            if message.len() > max {
+                return Response::text(413, &format!("message exceeds {} bytes", max));
+            }
+        }
+        if let Some(wait) = topics.limiter().acquire(options, &mut None, &user_key, &topic_key, message.len()) {
+            match options.rate_action {
+                RateAction::Delay => ::std::thread::sleep(wait),
+                _ => {
+                    let seconds = wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 };
+                    return Response::text(429, &format!("publish rate exceeded for {}", topic_key))
+                        .header("Retry-After", seconds.to_string());
+                }
+            }
+        }
+        topics.publish(topic_key, user_key, message);
+        Response::new(204, "text/plain; charset=utf-8", String::new())
+    }
+    
+    /// subscribes to the topics of this request, streaming their
+    /// messages as server-sent events.
+    fn subscribe(options: &Options, topics: &Topics, request: &Request) -> Response {
+        let user_key = match Gateway::identity(options, request) {
+            Ok(user_key) => user_key,
+            Err(response) => return response
+        };
+        let mut topic_keys = request.query("topic");
+        topic_keys.sort();
+        topic_keys.dedup();
+        if topic_keys.is_empty() {
+            return Response::text(400, "expected at least one topic parameter");
+        }
+        for topic_key in &topic_keys {
+            if let Some(reason) = options.check_name("topic", topic_key) {
+                return Response::text(400, &reason);
+            }
+        }
+        if let Some(max) = options.max_subscriptions {
+            if topic_keys.len() > max {
+                return Response::text(400, &format!("subscription limit of {} reached", max));
+            }
+        }
+        let options = options.clone();
+        let topics  = topics.clone();
+        Response::stream(200, "text/event-stream", move |head, stream, listener| {
+            Gateway::events(&options, &topics, head, stream, listener, user_key, topic_keys)
+        })
+            .header("Cache-Control", "no-cache".to_string())
+            .header("Connection", "close".to_string())
+    }
+    
+    /// registers this stream as a connection, refusing it if it would
+    /// exceed the connection limits, then subscribes it to these
+    /// topics until the client goes away. Streams authenticated as a
+    /// user are revoked with the user.
+    fn events(options: &Options, topics: &Topics, head: Response, stream: TcpStream, listener: SocketAddr, user_key: String, topic_keys: Vec<String>) {
+        let (registered, output, peer) = match (stream.try_clone(), stream.try_clone(), stream.peer_addr()) {
+            (Ok(registered), Ok(output), Ok(peer)) => (registered, output, peer),
+            _ => return
+        };
+        let (writer, flusher) = Writer::spawn(output, Framing::Lines, options.queue_capacity, options.flush_latency, topics.stats().clone());
+        let identity = options.users.as_ref()
+            .and_then(|users| users.get(&user_key))
+            .map(|user| (user_key.clone(), user.clone()));
+        let key = Uuid::new_v4().to_hyphenated_string();
+        let connection = Connection {
+            stream   : registered,
+            addr     : peer,
+            listener,
+            writer   : writer.clone(),
+            identity : Arc::new(Mutex::new(identity)),
+            key      : key.clone(),
+            user     : Arc::new(Mutex::new(user_key.clone()))
+        };
+        let id = match topics.connections().insert(options, connection) {
+            Ok(id)      => id,
+            Err(reason) => {
+                warn!(peer:% = peer, reason:% = reason; "event stream refused");
+                writer.close();
+                let _ = flusher.join();
+                let _ = Response::text(503, &reason).write(&stream);
+                return;
+            }
+        };
+        
+        // the head is queued on the writer so it precedes every event.
+        let _ = writer.send_text(head.head());
+        for topic_key in &topic_keys {
+            topics.subscribe(topic_key.clone(), key.clone(), Box::new(EventSubscriber::new(writer.clone())));
+        }
+        topics.stats().connect();
+        info!(conn = id, peer:% = peer, user:% = user_key, topics:% = topic_keys.join(","); "event stream opened");
+        let reason = Gateway::watch(&stream, &writer);
+        info!(conn = id, peer:% = peer, user:% = user_key, reason:% = reason; "event stream closed");
+        topics.delete_user_key(key);
+        topics.stats().disconnect();
+        writer.close();
+        let _ = flusher.join();
+        topics.connections().remove(id);
+    }
+    
+    /// reads from this event stream until the client goes away,
+    /// sending a keepalive comment whenever it has been idle, and
+    /// returns why the stream ended. Clients are not expected to
+    /// send anything, so anything read is discarded.
+    fn watch(mut stream: &TcpStream, writer: &Writer) -> String {
+        if let Err(error) = stream.set_read_timeout(Some(KEEPALIVE)) {
+            return format!("socket error: {}", error);
+        }
+        let mut buffer = [0; 512];
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(0) => return "closed by client".to_string(),
+                Ok(_) => continue,
+                Err(ref error) if error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut => {
+                    if writer.depth() > 0 {
+                        continue;
+                    }
+                    match writer.send_text(": keepalive\n\n".to_string()) {
+                        Err(ref error) if error.kind() == ErrorKind::BrokenPipe => return "writer closed".to_string(),
+                        _ => continue
+                    }
+                },
+                Err(error) => return format!("read failed: {}", error)
+            }
+        }
+    }
+    
+    /// returns the user publishing this request, authenticating with
+    /// basic auth when users are configured.
+    fn identity(options: &Options, request: &Request) -> ::std::result::Result<String, Response> {
+        let users = match options.users {
+            Some(ref users) => users,
+            None => {
+                let user_key = request.header(USER_HEADER).unwrap_or(DEFAULT_USER).to_string();
+                if let Some(reason) = options.check_name("user", &user_key) {
+                    return Err(Response::text(400, &reason));
+                }
+                if is_reserved(&user_key) {
+                    return Err(Response::text(403, &format!("user {} is reserved", user_key)));
+                }
+                return Ok(user_key);
+            }
+        };
+        let unauthorized = || Response::text(401, "authentication required").header("WWW-Authenticate", "Basic realm=\"pubsub\"".to_string());
+        let credentials = request.header("authorization")
+            .and_then(|value| if value.len() > 6 && value[..6].eq_ignore_ascii_case("basic ") { Some(&value[6..]) } else { None })
+            .and_then(|value| base64_decode(value.trim()))
+            .and_then(|value| String::from_utf8(value).ok())
+            .ok_or_else(unauthorized)?;
+        let split = credentials.splitn(2, ':').collect::<Vec<_>>();
+        match (users.get(split[0]), split.get(1)) {
+            (Some(user), Some(password)) if user.verify(password) => Ok(split[0].to_string()),
+            _ => {
+                warn!(user:% = split[0]; "http authentication failed");
+                Err(unauthorized())
+            }
+        }
+    }
+}
+
+/// EventSubscriber
+///
+/// Subscriber that queues messages on the writer of an event stream
+/// as server-sent events. The subscriber is alive for as long as the
+/// writer is.
+struct EventSubscriber {
+    writer : Writer
+}
+impl EventSubscriber {
+    fn new(writer: Writer) -> EventSubscriber {
+        EventSubscriber {
+            writer
+        }
+    }
+}
+impl Subscriber for EventSubscriber {
+    fn send(&mut self, packet: &Packet) -> Result<()> {
+        let message = packet.message();
+        self.writer.send_text(format!("event: {}\ndata: {{\"topic\":{},\"user\":{},\"message\":{}}}\n\n",
+            message.topic, json_string(&message.topic), json_string(&message.user), json_string(&message.message)))
+    }
+    
+    fn alive(&self) -> bool {
+        self.writer.alive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::BufReader;
+    use std::time::Instant;
+    use super::super::config::Config;
+    use super::super::limiter::Rate;
+    use super::super::users::User;
+    
+    fn request(method: &str, path: &str, headers: &[(&str, &str)], body: &str) -> Request {
+        Request {
+            method  : method.to_string(),
+            path    : path.to_string(),
+            headers : headers.iter().map(|&(name, value)| (name.to_string(), value.to_string())).collect(),
+            body    : body.as_bytes().to_vec()
+        }
+    }
+    
+    #[test]
+    fn publishes_and_lists_topics() {
+        let options = Options::default();
+        let topics  = Topics::new();
+        let news    = topics.subscribe_channel("news/today");
+        
+        let response = Gateway::route(&options, &topics, &request("POST", "/topics/news%2Ftoday", &[("X-Pubsub-User", "dave")], "hello"));
+        assert_eq!(response.status, 204);
+        let message = news.recv().unwrap();
+        assert_eq!((message.topic.as_str(), message.user.as_str(), message.message.as_str()), ("news/today", "dave", "hello"));
+        
+        let response = Gateway::route(&options, &topics, &request("POST", "/topics/news/today", &[], "again"));
+        assert_eq!(response.status, 204);
+        assert_eq!(news.recv().unwrap().user, DEFAULT_USER);
+        
+        let response = Gateway::route(&options, &topics, &request("GET", "/topics", &[], ""));
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains("{\"topic\":\"news/today\",\"subscribers\":1}"));
+        let response = Gateway::route(&options, &topics, &request("GET", "/topics/news/today?verbose", &[], ""));
+        assert_eq!((response.status, response.body.as_str()), (200, "{\"topic\":\"news/today\",\"subscribers\":1}\n"));
+    }
+    
+    #[test]
+    fn refuses_malformed_requests() {
+        let options = Options { max_name_length: Some(8), max_line_length: Some(4), ..Options::default() };
+        let topics  = Topics::new();
+        let status  = |method: &str, path: &str, headers: &[(&str, &str)], body: &str| Gateway::route(&options, &topics, &request(method, path, headers, body)).status;
+        
+        assert_eq!(status("GET", "/", &[], ""), 404);
+        assert_eq!(status("GET", "/topics/missing", &[], ""), 404);
+        assert_eq!(status("GET", "/topics/%zz", &[], ""), 400);
+        assert_eq!(status("DELETE", "/topics", &[], ""), 405);
+        assert_eq!(status("DELETE", "/topics/news", &[], ""), 405);
+        assert_eq!(status("POST", "/topics/a:b", &[], "hi"), 400);
+        assert_eq!(status("POST", "/topics/toolongname", &[], "hi"), 400);
+        assert_eq!(status("POST", "/topics/news", &[("X-Pubsub-User", "a:b")], "hi"), 400);
+        assert_eq!(status("POST", "/topics/news", &[], "two\nlines"), 400);
+        assert_eq!(status("POST", "/topics/news", &[], "hello"), 413);
+        assert_eq!(status("POST", "/topics/$SYS", &[], "hi"), 403);
+        assert_eq!(status("POST", "/topics/news", &[("X-Pubsub-User", "$SYS")], "hi"), 403);
+        
+        let invalid = Request { body: vec![0xff], ..request("POST", "/topics/news", &[], "") };
+        assert_eq!(Gateway::route(&options, &topics, &invalid).status, 400);
+    }
+    
+    #[test]
+    fn authenticates_with_basic_auth_when_users_are_configured() {
+        let mut users = HashMap::new();
+        users.insert("dave".to_string(), User::new("secret".to_string(), false));
+        let options = Options { users: Some(users), ..Options::default() };
+        let topics  = Topics::new();
+        let news    = topics.subscribe_channel("news");
+        let publish = |headers: &[(&str, &str)]| Gateway::route(&options, &topics, &request("POST", "/topics/news", headers, "hello"));
+        
+        let response = publish(&[("X-Pubsub-User", "dave")]);
+        assert_eq!(response.status, 401);
+        assert!(response.headers.iter().any(|header| header.0 == "WWW-Authenticate"));
+        assert_eq!(publish(&[("Authorization", "Basic ZGF2ZTp3cm9uZw==")]).status, 401);
+        assert_eq!(publish(&[("Authorization", "Bearer ZGF2ZTpzZWNyZXQ=")]).status, 401);
+        assert_eq!(publish(&[("Authorization", "basic ZGF2ZTpzZWNyZXQ=")]).status, 204);
+        assert_eq!(news.recv().unwrap().user, "dave");
+    }
+    
+    #[test]
+    fn rejects_publishes_over_the_shared_rate() {
+        let options = Options { topic_rate: Some(Rate { messages: Some(1), bytes: None }), ..Options::default() };
+        let topics  = Topics::new();
+        assert!(topics.limiter().acquire(&options, &mut None, "dave", "news", 5).is_none());
+        
+        let response = Gateway::route(&options, &topics, &request("POST", "/topics/news", &[], "hello"));
+        assert_eq!(response.status, 429);
+        assert_eq!(response.headers, vec![("Retry-After", "1".to_string())]);
+    }
+    
+    /// opens an event stream on this gateway for these topics,
+    /// returning the reader positioned after the head and the head.
+    fn open(addr: SocketAddr, topics: &str) -> (BufReader<TcpStream>, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(stream, "GET /subscribe?{} HTTP/1.1\r\n\r\n", topics).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut head   = String::new();
+        while !head.ends_with("\r\n\r\n") && reader.read_line(&mut head).unwrap() > 0 {}
+        (reader, head)
+    }
+    
+    /// waits until this many streams are registered with the topics,
+    /// subscribed to this many topics.
+    fn wait_for(topics: &Topics, streams: usize, subscriptions: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while topics.connections().len() != streams || topics.subscriptions().len() != subscriptions {
+            assert!(Instant::now() < deadline, "expected {} streams", streams);
+            ::std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+    
+    #[test]
+    fn streams_events_within_the_connection_limits() {
+        let options  = Options { max_connections_per_ip: Some(1), ..Options::default() };
+        let topics   = Topics::new();
+        let handle   = Gateway::start("127.0.0.1:0", topics.clone(), Settings::new(Config { options, ..Config::default() })).unwrap();
+        let (mut events, head) = open(handle.local_addr(), "topic=news&topic=sport");
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n"));
+        wait_for(&topics, 1, 2);
+        assert_eq!(topics.stats().clients(), 1);
+        assert_eq!(topics.connections().clients()[0].listener, handle.local_addr());
+        
+        let (mut refused, head) = open(handle.local_addr(), "topic=news");
+        assert!(head.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert_eq!(refused.read_line(&mut String::new()).unwrap(), "connection limit of 1 for 127.0.0.1 reached\n".len());
+        
+        topics.publish("sport".to_string(), "dave".to_string(), "goal".to_string());
+        let mut event = String::new();
+        while !event.ends_with("\n\n") {
+            events.read_line(&mut event).unwrap();
+        }
+        assert_eq!(event, "event: sport\ndata: {\"topic\":\"sport\",\"user\":\"dave\",\"message\":\"goal\"}\n\n");
+        
+        // shutdown closes the stream, unsubscribing it.
+        handle.shutdown();
+        let mut rest = String::new();
+        events.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "g:shutdown\n");
+        wait_for(&topics, 0, 0);
+        assert_eq!(topics.stats().clients(), 0);
+    }
+}
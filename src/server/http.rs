@@ -0,0 +1,448 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use std::io::prelude::*;
+use std::io::{BufReader, Result};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::connections::Connections;
+use super::server::wake;
+
+/// maximum length in bytes of a request line or header line.
+const MAX_LINE: u64 = 8192;
+
+/// maximum number of request headers.
+const MAX_HEADERS: usize = 100;
+
+/// maximum length in bytes of a request body.
+pub const MAX_BODY: usize = 1 << 20;
+
+/// maximum number of requests answered at once. Streams stop counting
+/// once handed their connection, as connection limits apply to them.
+const MAX_REQUESTS: usize = 64;
+
+/// Request
+///
+/// A http request read from a client. Only requests with a
+/// Content-Length body or no body are supported.
+#[derive(Debug)]
+pub struct Request {
+    pub method  : String,
+    pub path    : String,
+    pub headers : Vec<(String, String)>,
+    pub body    : Vec<u8>
+}
+impl Request {
+    
+    /// reads a request from this stream, returning the response to
+    /// send instead if the request cannot be read.
+    pub fn read<R: Read>(stream: R) -> ::std::result::Result<Request, Response> {
+        let mut reader = BufReader::new(stream);
+        let line = Request::line(&mut reader)?;
+        let mut parts = line.split_whitespace();
+        let (method, path) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/1.") => (method.to_string(), path.to_string()),
+            _ => return Err(Response::text(400, "malformed request line"))
+        };
+        let mut headers = Vec::new();
+        loop {
+            let line = Request::line(&mut reader)?;
+            if line.is_empty() {
+                break;
+            }
+            if headers.len() >= MAX_HEADERS {
+                return Err(Response::text(431, "too many headers"));
+            }
+            match line.find(':') {
+                Some(index) => headers.push((line[..index].trim().to_string(), line[index + 1..].trim().to_string())),
+                None => return Err(Response::text(400, "malformed header"))
+            }
+        }
+        let mut request = Request { method, path, headers, body: Vec::new() };
+        if request.header("transfer-encoding").is_some() {
+            return Err(Response::text(501, "transfer encodings are not supported"));
+        }
+        let length = match request.header("content-length") {
+            Some(length) => length.parse::<usize>().map_err(|_| Response::text(400, "invalid content-length"))?,
+            None => 0
+        };
+        if length > MAX_BODY {
+            return Err(Response::text(413, &format!("body exceeds {} bytes", MAX_BODY)));
+        }
+        request.body.resize(length, 0);
+        reader.read_exact(&mut request.body).map_err(|_| Response::text(400, "incomplete body"))?;
+        Ok(request)
+    }
+    
+    /// returns the value of this header, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|header| header.0.eq_ignore_ascii_case(name))
+            .map(|header| header.1.as_str())
+    }
+    
+    /// returns the percent decoded values of this query parameter,
+    /// in the order given.
+    pub fn query(&self, name: &str) -> Vec<String> {
+        let query = match self.path.find('?') {
+            Some(index) => &self.path[index + 1..],
+            None => return Vec::new()
+        };
+        query.split('&')
+            .filter_map(|pair| {
+                let mut split = pair.splitn(2, '=');
+                match (split.next(), split.next()) {
+                    (Some(key), Some(value)) if key == name => decode(&value.replace('+', "%20")),
+                    _ => None
+                }
+            })
+            .collect()
+    }
+    
+    /// reads one crlf or lf terminated line.
+    fn line<R: BufRead>(reader: &mut R) -> ::std::result::Result<String, Response> {
+        let mut line = String::new();
+        match reader.take(MAX_LINE).read_line(&mut line) {
+            Ok(_) if !line.ends_with('\n') => Err(Response::text(400, "line too long or truncated")),
+            Ok(_)  => Ok(line.trim_end().to_string()),
+            Err(_) => Err(Response::text(400, "unreadable request"))
+        }
+    }
+}
+
+/// function a streaming response hands its connection to.
+type Stream = Box<dyn FnOnce(Response, TcpStream, SocketAddr) + Send>;
+
+/// Response
+///
+/// A http response to send to a client. A streaming response has no
+/// body and is not written by the listener; the connection is handed
+/// to its stream function along with the response and the address
+/// of the listener. The function writes the head once it accepts the
+/// stream, and owns the connection until it returns.
+pub struct Response {
+    pub status       : u16,
+    pub content_type : &'static str,
+    pub headers      : Vec<(&'static str, String)>,
+    pub body         : String,
+    stream           : Option<Stream>
+}
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: String) -> Response {
+        Response { status, content_type, headers: Vec::new(), body, stream: None }
+    }
+    
+    /// creates a streaming response that hands the connection to
+    /// this function. Content-Length and Connection headers are left
+    /// to the caller.
+    pub fn stream<F>(status: u16, content_type: &'static str, stream: F) -> Response where F: FnOnce(Response, TcpStream, SocketAddr) + Send + 'static {
+        Response { status, content_type, headers: Vec::new(), body: String::new(), stream: Some(Box::new(stream)) }
+    }
+    
+    /// creates a plain text response, adding a trailing newline.
+    pub fn text(status: u16, body: &str) -> Response {
+        Response::new(status, "text/plain; charset=utf-8", format!("{}\n", body))
+    }
+    
+    /// creates a json response.
+    pub fn json(status: u16, body: String) -> Response {
+        Response::new(status, "application/json", body)
+    }
+    
+    /// adds this header to the response.
+    pub fn header(mut self, name: &'static str, value: String) -> Response {
+        self.headers.push((name, value));
+        self
+    }
+    
+    /// returns the status line and headers of this response.
+    pub fn head(&self) -> String {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason(self.status));
+        if !self.content_type.is_empty() {
+            head.push_str(&format!("Content-Type: {}\r\n", self.content_type));
+        }
+        if self.stream.is_none() {
+            head.push_str(&format!("Content-Length: {}\r\nConnection: close\r\n", self.body.len()));
+        }
+        for &(name, ref value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        head
+    }
+    
+    /// writes this response to this stream.
+    pub fn write<W: Write>(&self, mut stream: W) -> Result<()> {
+        stream.write_all(self.head().as_bytes())?;
+        stream.write_all(self.body.as_bytes())?;
+        stream.flush()
+    }
+}
+
+/// returns the reason phrase of this status code.
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _   => "Unknown"
+    }
+}
+
+/// decodes this percent encoded path segment, returning None if
+/// it is malformed or not valid utf-8.
+pub fn decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut output = Vec::with_capacity(bytes.len());
+    let mut index  = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = bytes.get(index + 1..index + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let hex = ::std::str::from_utf8(hex).ok()?;
+            output.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            output.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(output).ok()
+}
+
+/// standard base64 alphabet.
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// encodes these bytes as standard padded base64.
+pub fn base64_encode(bytes: &[u8]) -> String {
+    let mut output = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let buffer = chunk.iter().enumerate().fold(0u32, |buffer, (index, &byte)| buffer | (byte as u32) << (16 - index * 8));
+        for index in 0..4 {
+            if index <= chunk.len() {
+                output.push(BASE64[(buffer >> (18 - index * 6)) as usize & 0x3F] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    } output
+}
+
+/// decodes this standard base64 value.
+pub fn base64_decode(value: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(value.len() * 3 / 4);
+    let mut buffer = 0u32;
+    let mut bits   = 0;
+    for byte in value.trim_end_matches('=').bytes() {
+        let digit = BASE64.iter().position(|&digit| digit == byte)? as u32;
+        buffer = (buffer << 6) | digit;
+        bits  += 6;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    } Some(output)
+}
+
+/// HttpHandle
+///
+/// Handle to a running http listener.
+pub struct HttpHandle {
+    addr        : SocketAddr,
+    running     : Arc<AtomicBool>,
+    connections : Connections,
+    acceptor    : JoinHandle<()>
+}
+impl HttpHandle {
+    
+    /// returns the address this listener is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+    
+    /// stops accepting requests, waits for the listener to finish
+    /// and closes the streams registered on it. Requests in progress
+    /// are left to complete.
+    pub fn shutdown(self) {
+        self.running.store(false, Ordering::SeqCst);
+        wake(self.addr);
+        let _ = self.acceptor.join();
+        self.connections.close(self.addr, "shutdown", Duration::from_secs(5));
+    }
+}
+
+/// starts a http listener on this addr in the background, answering
+/// each request with this handler on its own thread. Connections are
+/// closed after one request. Requests beyond MAX_REQUESTS are refused
+/// until others are answered. Streams register with these connections
+/// and are closed with the listener.
+pub fn serve<T, F>(addr: T, connections: Connections, handler: F) -> Result<HttpHandle> where T: ToSocketAddrs, F: Fn(&Request, SocketAddr) -> Response + Send + Sync + 'static {
+    let listener = TcpListener::bind(addr)?;
+    let addr     = listener.local_addr()?;
+    let running  = Arc::new(AtomicBool::new(true));
+    let handler  = Arc::new(handler);
+    let active   = Arc::new(AtomicUsize::new(0));
+    let acceptor = {
+        let running = running.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if !running.load(Ordering::SeqCst) {
+                    break;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        warn!(error:% = error; "http accept failed");
+                        continue;
+                    }
+                };
+                if active.load(Ordering::SeqCst) >= MAX_REQUESTS {
+                    debug!(peer:? = stream.peer_addr().ok(); "http request refused");
+                    let _ = stream.set_write_timeout(Some(Duration::from_secs(1)));
+                    let _ = Response::text(503, "too many requests in progress").write(&stream);
+                    continue;
+                }
+                active.fetch_add(1, Ordering::SeqCst);
+                let handler = handler.clone();
+                let active  = active.clone();
+                thread::spawn(move || {
+                    let result = respond(stream, addr, &*handler);
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    match result {
+                        Ok(Some(stream)) => stream(),
+                        Ok(None)         => {},
+                        Err(error)       => debug!(error:% = error; "http request failed")
+                    }
+                });
+            }
+        })
+    };
+    Ok(HttpHandle { addr, running, connections, acceptor })
+}
+
+/// reads one request from this stream accepted on this listener and
+/// answers it, returning the stream function to hand the stream over
+/// to if the response is streaming.
+fn respond<F>(stream: TcpStream, listener: SocketAddr, handler: &F) -> Result<Option<Box<dyn FnOnce() + Send>>> where F: Fn(&Request, SocketAddr) -> Response {
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+    let mut response = match Request::read(&stream) {
+        Ok(request)   => handler(&request, peer),
+        Err(response) => response
+    };
+    match response.stream.take() {
+        Some(body) => Ok(Some(Box::new(move || body(response, stream, listener)))),
+        None       => response.write(&stream).map(|_| None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn read(request: &str) -> ::std::result::Result<Request, u16> {
+        Request::read(request.as_bytes()).map_err(|response| response.status)
+    }
+    
+    #[test]
+    fn reads_requests_with_and_without_a_body() {
+        let request = read("GET /topics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("GET", "/topics"));
+        assert_eq!(request.header("host"), Some("localhost"));
+        assert!(request.body.is_empty());
+        
+        let request = read("POST /topics/news HTTP/1.0\nContent-Length: 5\nX-Pubsub-User:  dave \n\nhello, and more").unwrap();
+        assert_eq!(request.header("X-PUBSUB-USER"), Some("dave"));
+        assert_eq!(request.body, b"hello");
+    }
+    
+    #[test]
+    fn refuses_malformed_requests() {
+        assert_eq!(read("GET /topics\r\n\r\n").unwrap_err(), 400);
+        assert_eq!(read("GET /topics SPDY/3\r\n\r\n").unwrap_err(), 400);
+        assert_eq!(read("GET /topics HTTP/1.1\r\nno colon\r\n\r\n").unwrap_err(), 400);
+        assert_eq!(read("GET /topics HTTP/1.1\r\n").unwrap_err(), 400);
+        assert_eq!(read("POST /topics HTTP/1.1\r\nContent-Length: five\r\n\r\n").unwrap_err(), 400);
+        assert_eq!(read("POST /topics HTTP/1.1\r\nContent-Length: 5\r\n\r\nhi").unwrap_err(), 400);
+        assert_eq!(read("POST /topics HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").unwrap_err(), 501);
+        assert_eq!(read(&format!("POST /topics HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1)).unwrap_err(), 413);
+        assert_eq!(read(&format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE as usize))).unwrap_err(), 400);
+        assert_eq!(read(&format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(MAX_HEADERS + 1))).unwrap_err(), 431);
+    }
+    
+    #[test]
+    fn writes_responses_with_their_headers() {
+        let mut output = Vec::new();
+        Response::text(429, "slow down").header("Retry-After", "2".to_string()).write(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 429 Too Many Requests\r\n"));
+        assert!(output.contains("\r\nRetry-After: 2\r\n"));
+        assert!(output.ends_with("\r\n\r\nslow down\n"));
+    }
+    
+    #[test]
+    fn base64_vectors() {
+        let vectors = [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foob", "Zm9vYg=="), ("fooba", "Zm9vYmE="), ("foobar", "Zm9vYmFy")];
+        for &(plain, encoded) in vectors.iter() {
+            assert_eq!(base64_encode(plain.as_bytes()), encoded);
+            assert_eq!(base64_decode(encoded), Some(plain.as_bytes().to_vec()));
+        }
+        assert_eq!(base64_encode(&[0xFB, 0xFF, 0xFE]), "+//+");
+        assert_eq!(base64_decode("+//+"), Some(vec![0xFB, 0xFF, 0xFE]));
+        assert_eq!(base64_decode("Zm9vYg"), Some(b"foob".to_vec()));
+        assert_eq!(base64_decode("Zm9v!"), None);
+        assert_eq!(base64_decode("Zm 9v"), None);
+    }
+    
+    #[test]
+    fn decodes_percent_encoded_segments() {
+        assert_eq!(decode("news%2Ftoday"), Some("news/today".to_string()));
+        assert_eq!(decode("caf%C3%A9"), Some("café".to_string()));
+        assert_eq!(decode("plain"), Some("plain".to_string()));
+        assert_eq!(decode("%2"), None);
+        assert_eq!(decode("%zz"), None);
+        assert_eq!(decode("%ff"), None);
+    }
+}
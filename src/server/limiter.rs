@@ -0,0 +1,381 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use super::options::Options;
+
+/// Rate
+///
+/// A publish rate limit in messages and bytes per second. Either
+/// limit may be omitted. Bursts of up to one second of traffic are
+/// permitted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub messages : Option<u32>,
+    pub bytes    : Option<u32>
+}
+
+/// RateAction
+///
+/// What the server does with a publish that exceeds a rate limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateAction {
+    /// drop the message and answer with an error line.
+    Reject,
+    /// hold the client until the message conforms, then publish it.
+    Delay,
+    /// answer with an error line and close the connection.
+    Disconnect
+}
+
+///------------------------------------
+/// Bucket
+///
+/// Token bucket holding at most one
+/// second worth of tokens.
+///------------------------------------
+struct Bucket {
+    rate   : f64,
+    tokens : f64,
+    last   : Instant
+}
+impl Bucket {
+    fn new(rate: u32, now: Instant) -> Bucket {
+        Bucket {
+            rate   : rate as f64,
+            tokens : rate as f64,
+            last   : now
+        }
+    }
+    
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last   = now;
+    }
+    
+    /// returns how long until this amount is available. Amounts larger
+    /// than the bucket only require a full bucket.
+    fn wait(&self, amount: f64) -> Duration {
+        let amount = amount.min(self.rate);
+        if self.tokens >= amount || self.rate <= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64((amount - self.tokens) / self.rate)
+        }
+    }
+    
+    fn full(&self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens + elapsed * self.rate >= self.rate
+    }
+}
+
+///------------------------------------
+/// RateLimit
+///
+/// Message and byte buckets for a
+/// single connection, user or topic.
+///------------------------------------
+pub struct RateLimit {
+    rate     : Rate,
+    messages : Option<Bucket>,
+    bytes    : Option<Bucket>
+}
+impl RateLimit {
+    pub fn new(rate: Rate) -> RateLimit {
+        RateLimit::start(rate, Instant::now())
+    }
+    
+    /// returns a limit for this rate with full buckets at this time.
+    fn start(rate: Rate, now: Instant) -> RateLimit {
+        RateLimit {
+            rate,
+            messages : rate.messages.map(|rate| Bucket::new(rate, now)),
+            bytes    : rate.bytes.map(|rate| Bucket::new(rate, now))
+        }
+    }
+    
+    /// returns the limit for this rate, replacing the given limit
+    /// if its rate has changed.
+    fn update(limit: &mut Option<RateLimit>, rate: Option<Rate>, now: Instant) -> Option<&mut RateLimit> {
+        let current = limit.as_ref().map(|limit| limit.rate);
+        if current != rate {
+            *limit = rate.map(|rate| RateLimit::start(rate, now));
+        }
+        limit.as_mut()
+    }
+    
+    /// returns how long a publish of this many bytes must wait.
+    fn wait(&mut self, bytes: usize, now: Instant) -> Duration {
+        let mut wait = Duration::from_secs(0);
+        if let Some(ref mut bucket) = self.messages {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait(1.0));
+        }
+        if let Some(ref mut bucket) = self.bytes {
+            bucket.refill(now);
+            wait = wait.max(bucket.wait(bytes as f64));
+        } wait
+    }
+    
+    /// consumes a publish of this many bytes.
+    fn take(&mut self, bytes: usize) {
+        if let Some(ref mut bucket) = self.messages {
+            bucket.tokens -= 1.0;
+        }
+        if let Some(ref mut bucket) = self.bytes {
+            bucket.tokens -= bytes as f64;
+        }
+    }
+    
+    fn full(&self, now: Instant) -> bool {
+        self.messages.as_ref().map(|bucket| bucket.full(now)).unwrap_or(true) &&
+        self.bytes.as_ref().map(|bucket| bucket.full(now)).unwrap_or(true)
+    }
+}
+
+///------------------------------------
+/// Limits
+///
+/// Shared limits by key, with the
+/// number left after the last prune.
+///------------------------------------
+#[derive(Default)]
+struct Limits {
+    dict   : HashMap<String, RateLimit>,
+    pruned : usize
+}
+
+//------------------------------------
+// Limiter
+//------------------------------------
+#[derive(Clone, Default)]
+pub struct Limiter {
+    users  : Arc<Mutex<Limits>>,
+    topics : Arc<Mutex<Limits>>
+}
+impl Limiter {
+    pub fn new() -> Limiter {
+        Limiter {
+            users  : Arc::new(Mutex::new(Limits::default())),
+            topics : Arc::new(Mutex::new(Limits::default()))
+        }
+    }
+    
+    ///-----------------------------------------
+    /// acquires a publish of this many bytes
+    /// against the connection, user and topic
+    /// rates in these options. Limits whose
+    /// rate has changed since the last publish
+    /// start over at the new rate. Returns None
+    /// if the publish conforms, otherwise how
+    /// long it must wait. Under
+    /// RateAction::Delay the tokens are
+    /// consumed regardless and the caller is
+    /// expected to wait.
+    ///-----------------------------------------
+    pub fn acquire(&self, options: &Options, connection: &mut Option<RateLimit>, user_key: &str, topic_key: &str, bytes: usize) -> Option<Duration> {
+        self.acquire_at(options, connection, user_key, topic_key, bytes, Instant::now())
+    }
+    
+    /// acquires a publish as acquire does, at this time.
+    fn acquire_at(&self, options: &Options, connection: &mut Option<RateLimit>, user_key: &str, topic_key: &str, bytes: usize, now: Instant) -> Option<Duration> {
+        let mut users  = self.users.lock().unwrap();
+        let mut topics = self.topics.lock().unwrap();
+        let mut limits = Vec::new();
+        if let Some(connection) = RateLimit::update(connection, options.connection_rate, now) {
+            limits.push(connection);
+        }
+        if let Some(limit) = Limiter::limit(&mut users, options.user_rate, user_key, now) {
+            limits.push(limit);
+        }
+        if let Some(limit) = Limiter::limit(&mut topics, options.topic_rate, topic_key, now) {
+            limits.push(limit);
+        }
+        let wait = limits.iter_mut().map(|limit| limit.wait(bytes, now)).max().unwrap_or_default();
+        if wait > Duration::from_secs(0) && options.rate_action != RateAction::Delay {
+            return Some(wait);
+        }
+        for limit in limits {
+            limit.take(bytes);
+        }
+        if wait > Duration::from_secs(0) { Some(wait) } else { None }
+    }
+    
+    /// returns the shared limit for this key at this rate.
+    fn limit<'a>(limits: &'a mut Limits, rate: Option<Rate>, key: &str, now: Instant) -> Option<&'a mut RateLimit> {
+        let rate = match rate {
+            Some(rate) => rate,
+            None => {
+                limits.dict.clear();
+                return None;
+            }
+        };
+        Limiter::prune(limits, now);
+        let limit = limits.dict.entry(key.to_string()).or_insert_with(|| RateLimit::start(rate, now));
+        if limit.rate != rate {
+            *limit = RateLimit::start(rate, now);
+        }
+        Some(limit)
+    }
+    
+    /// drops idle limits, as a full bucket is indistinguishable from
+    /// a new one. Runs once the map is large and has doubled since the
+    /// last prune, so a map of busy keys is not rescanned per publish.
+    fn prune(limits: &mut Limits, now: Instant) {
+        if limits.dict.len() >= 1024.max(limits.pruned * 2) {
+            limits.dict.retain(|_, limit| !limit.full(now));
+            limits.pruned = limits.dict.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn options(action: RateAction, user_rate: Option<Rate>, topic_rate: Option<Rate>) -> Options {
+        Options { user_rate, topic_rate, rate_action: action, ..Options::default() }
+    }
+    
+    fn messages(messages: u32) -> Option<Rate> {
+        Some(Rate { messages: Some(messages), bytes: None })
+    }
+    
+    fn bytes(bytes: u32) -> Option<Rate> {
+        Some(Rate { messages: None, bytes: Some(bytes) })
+    }
+    
+    fn millis(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+    
+    /// asserts this wait is within a microsecond of the expected one,
+    /// leaving room for float rounding.
+    fn assert_wait(wait: Option<Duration>, expected: Duration) {
+        let wait = wait.expect("expected the publish to wait");
+        assert!(wait.max(expected) - wait.min(expected) < Duration::from_micros(1), "{:?} != {:?}", wait, expected);
+    }
+    
+    #[test]
+    fn refill() {
+        let (limiter, t0) = (Limiter::new(), Instant::now());
+        let options = options(RateAction::Reject, messages(2), None);
+        let acquire = |now| limiter.acquire_at(&options, &mut None, "dave", "news", 1, now);
+        assert_eq!(acquire(t0), None);
+        assert_eq!(acquire(t0), None);
+        assert_wait(acquire(t0), millis(500));
+        assert_wait(acquire(t0 + millis(250)), millis(250));
+        assert_eq!(acquire(t0 + millis(500)), None);
+        assert_wait(acquire(t0 + millis(500)), millis(500));
+        
+        let later = t0 + Duration::from_secs(60);
+        assert_eq!(acquire(later), None);
+        assert_eq!(acquire(later), None);
+        assert_wait(acquire(later), millis(500));
+    }
+    
+    #[test]
+    fn bytes_beyond_the_bucket() {
+        let (limiter, t0) = (Limiter::new(), Instant::now());
+        let options = options(RateAction::Reject, None, bytes(100));
+        let acquire = |bytes, now| limiter.acquire_at(&options, &mut None, "dave", "news", bytes, now);
+        assert_eq!(acquire(150, t0), None);
+        assert_wait(acquire(10, t0), millis(600));
+        assert_wait(acquire(150, t0 + millis(500)), millis(1000));
+        assert_eq!(acquire(10, t0 + millis(600)), None);
+    }
+    
+    #[test]
+    fn delay_debt() {
+        let (limiter, t0) = (Limiter::new(), Instant::now());
+        let options = options(RateAction::Delay, messages(1), None);
+        let acquire = |now| limiter.acquire_at(&options, &mut None, "dave", "news", 1, now);
+        assert_eq!(acquire(t0), None);
+        assert_wait(acquire(t0), millis(1000));
+        assert_wait(acquire(t0), millis(2000));
+        assert_wait(acquire(t0 + millis(1500)), millis(1500));
+        assert_eq!(acquire(t0 + millis(4000)), None);
+        assert_wait(acquire(t0 + millis(4000)), millis(1000));
+    }
+    
+    #[test]
+    fn shared_limits() {
+        let (limiter, t0) = (Limiter::new(), Instant::now());
+        let options = options(RateAction::Reject, messages(1), messages(2));
+        let mut connection = None;
+        assert_eq!(limiter.acquire_at(&options, &mut connection, "dave", "news", 1, t0), None);
+        assert_eq!(limiter.acquire_at(&options, &mut connection, "anne", "news", 1, t0), None);
+        assert_wait(limiter.acquire_at(&options, &mut connection, "bill", "news", 1, t0), millis(500));
+        assert_wait(limiter.acquire_at(&options, &mut connection, "dave", "sport", 1, t0), millis(1000));
+        assert!(connection.is_none());
+        
+        let options = Options { connection_rate: messages(1), ..options };
+        assert_eq!(limiter.acquire_at(&options, &mut connection, "bill", "sport", 1, t0), None);
+        assert_wait(limiter.acquire_at(&options, &mut connection, "carl", "weather", 1, t0), millis(1000));
+    }
+    
+    #[test]
+    fn rate_changes_start_over() {
+        let (limiter, t0) = (Limiter::new(), Instant::now());
+        let slow = options(RateAction::Reject, messages(1), None);
+        let fast = options(RateAction::Reject, messages(10), None);
+        assert_eq!(limiter.acquire_at(&slow, &mut None, "dave", "news", 1, t0), None);
+        assert!(limiter.acquire_at(&slow, &mut None, "dave", "news", 1, t0).is_some());
+        assert_eq!(limiter.acquire_at(&fast, &mut None, "dave", "news", 1, t0), None);
+        
+        let none = options(RateAction::Reject, None, None);
+        assert_eq!(limiter.acquire_at(&none, &mut None, "dave", "news", 1, t0), None);
+        assert!(limiter.users.lock().unwrap().dict.is_empty());
+    }
+    
+    #[test]
+    fn prune() {
+        let (limiter, t0) = (Limiter::new(), Instant::now());
+        let options = options(RateAction::Reject, messages(1), None);
+        let acquire = |user_key: &str, now| limiter.acquire_at(&options, &mut None, user_key, "news", 1, now);
+        for index in 0..1024 {
+            assert_eq!(acquire(&format!("user{}", index), t0), None);
+        }
+        assert_eq!(acquire("dave", t0 + millis(500)), None);
+        assert_eq!(limiter.users.lock().unwrap().dict.len(), 1025);
+        
+        assert_eq!(acquire("anne", t0 + millis(1000)), None);
+        assert_eq!(limiter.users.lock().unwrap().dict.len(), 1026);
+        for index in 1026..2048 {
+            assert_eq!(acquire(&format!("late{}", index), t0 + millis(1000)), None);
+        }
+        assert_eq!(acquire("bill", t0 + millis(1500)), None);
+        let users = limiter.users.lock().unwrap();
+        assert_eq!(users.dict.len(), 1024);
+        assert!(users.dict.contains_key("anne") && users.dict.contains_key("bill"));
+        assert!(!users.dict.contains_key("dave"));
+    }
+}
@@ -0,0 +1,267 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use log::{self, LevelFilter, Log, Metadata, Record};
+use log::kv::{Error as KvError, Key, Value, VisitSource};
+
+use std::fmt::Write as FmtWrite;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{self, Result};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::super::protocol::json_string;
+
+/// LogFormat
+///
+/// How each log record is written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// a timestamp, level and message followed by key=value fields.
+    Text,
+    /// one json object per line, fields as top level keys.
+    Json
+}
+
+/// LogOutput
+///
+/// Where log records are written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogOutput {
+    Stderr,
+    Stdout,
+    File(String)
+}
+
+/// LogConfig
+///
+/// Logging settings of the server binary. Servers log through the
+/// log facade, so embedded servers can use any logger instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogConfig {
+    pub level  : LevelFilter,
+    pub format : LogFormat,
+    pub output : LogOutput
+}
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level  : LevelFilter::Info,
+            format : LogFormat::Text,
+            output : LogOutput::Stderr
+        }
+    }
+}
+
+/// parses a log format name.
+pub fn parse_log_format(format: &str) -> Option<LogFormat> {
+    match format {
+        "text" => Some(LogFormat::Text),
+        "json" => Some(LogFormat::Json),
+        _ => None
+    }
+}
+
+/// parses a log output, either stderr, stdout or a file path.
+pub fn parse_log_output(output: &str) -> LogOutput {
+    match output {
+        "stderr" => LogOutput::Stderr,
+        "stdout" => LogOutput::Stdout,
+        path     => LogOutput::File(path.to_string())
+    }
+}
+
+//------------------------------------
+// Sink
+//------------------------------------
+enum Sink {
+    Stderr,
+    Stdout,
+    File(File)
+}
+
+/// Logger
+///
+/// A log facade logger writing one line per record.
+pub struct Logger {
+    format : LogFormat,
+    sink   : Mutex<Sink>
+}
+impl Logger {
+    
+    /// installs a logger with this config as the global logger.
+    pub fn init(config: &LogConfig) -> Result<()> {
+        let sink = match config.output {
+            LogOutput::Stderr         => Sink::Stderr,
+            LogOutput::Stdout         => Sink::Stdout,
+            LogOutput::File(ref path) => Sink::File(OpenOptions::new().create(true).append(true).open(path)?)
+        };
+        let logger = Box::new(Logger { format: config.format, sink: Mutex::new(sink) });
+        log::set_logger(Box::leak(logger)).map_err(|error| io::Error::other(error.to_string()))?;
+        log::set_max_level(config.level);
+        Ok(())
+    }
+    
+    /// formats this record as a single line.
+    fn format(&self, record: &Record) -> String {
+        let mut line = String::new();
+        match self.format {
+            LogFormat::Text => {
+                let _ = write!(line, "{} {:<5} {}", timestamp(SystemTime::now()), record.level(), record.args());
+                let _ = record.key_values().visit(&mut TextFields(&mut line));
+            },
+            LogFormat::Json => {
+                let _ = write!(line, "{{\"ts\":\"{}\",\"level\":\"{}\",\"target\":{},\"msg\":{}",
+                    timestamp(SystemTime::now()), record.level(), json_string(record.target()), json_string(&record.args().to_string()));
+                let _ = record.key_values().visit(&mut JsonFields(&mut line));
+                line.push('}');
+            }
+        }
+        line.push('\n');
+        line
+    }
+}
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+    
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = self.format(record);
+        let mut sink = self.sink.lock().unwrap();
+        let _ = match *sink {
+            Sink::Stderr        => io::stderr().write_all(line.as_bytes()),
+            Sink::Stdout        => io::stdout().write_all(line.as_bytes()),
+            Sink::File(ref mut file) => file.write_all(line.as_bytes())
+        };
+    }
+    
+    fn flush(&self) {
+        let mut sink = self.sink.lock().unwrap();
+        let _ = match *sink {
+            Sink::Stderr        => io::stderr().flush(),
+            Sink::Stdout        => io::stdout().flush(),
+            Sink::File(ref mut file) => file.flush()
+        };
+    }
+}
+
+//------------------------------------
+// TextFields
+//------------------------------------
+struct TextFields<'a>(&'a mut String);
+impl<'a, 'kvs> VisitSource<'kvs> for TextFields<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> std::result::Result<(), KvError> {
+        let value = value.to_string();
+        if value.is_empty() || value.contains(char::is_whitespace) || value.contains('"') {
+            let _ = write!(self.0, " {}={:?}", key, value);
+        } else {
+            let _ = write!(self.0, " {}={}", key, value);
+        } Ok(())
+    }
+}
+
+//------------------------------------
+// JsonFields
+//------------------------------------
+struct JsonFields<'a>(&'a mut String);
+impl<'a, 'kvs> VisitSource<'kvs> for JsonFields<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> std::result::Result<(), KvError> {
+        let _ = write!(self.0, ",{}:", json_string(key.as_str()));
+        if let Some(number) = value.to_u64() {
+            let _ = write!(self.0, "{}", number);
+        } else if let Some(number) = value.to_i64() {
+            let _ = write!(self.0, "{}", number);
+        } else if let Some(flag) = value.to_bool() {
+            let _ = write!(self.0, "{}", flag);
+        } else {
+            self.0.push_str(&json_string(&value.to_string()));
+        } Ok(())
+    }
+}
+
+/// returns this utc time in rfc 3339 format.
+fn timestamp(time: SystemTime) -> String {
+    let now     = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = now.as_secs();
+    let (days, rest) = ((seconds / 86400) as i64, seconds % 86400);
+    
+    // civil date from days since the epoch.
+    let z   = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp  = (5 * doy + 2) / 153;
+    let day   = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year  = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, rest / 3600, rest / 60 % 60, rest % 60, now.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    
+    /// returns the timestamp of this many milliseconds after the epoch.
+    fn at(millis: u64) -> String {
+        timestamp(UNIX_EPOCH + Duration::from_millis(millis))
+    }
+    
+    #[test]
+    fn timestamp_at_the_epoch() {
+        assert_eq!(at(0), "1970-01-01T00:00:00.000Z");
+    }
+    
+    #[test]
+    fn timestamp_on_a_leap_day() {
+        assert_eq!(at(951_782_400_000), "2000-02-29T00:00:00.000Z");
+        assert_eq!(at(1_709_251_199_999), "2024-02-29T23:59:59.999Z");
+        assert_eq!(at(1_709_251_200_000), "2024-03-01T00:00:00.000Z");
+    }
+    
+    #[test]
+    fn timestamp_at_a_month_boundary() {
+        assert_eq!(at(1_698_796_799_123), "2023-10-31T23:59:59.123Z");
+        assert_eq!(at(1_698_796_800_000), "2023-11-01T00:00:00.000Z");
+        assert_eq!(at(1_704_067_199_000), "2023-12-31T23:59:59.000Z");
+        assert_eq!(at(1_704_067_200_000), "2024-01-01T00:00:00.000Z");
+    }
+    
+    #[test]
+    fn log_formats_and_outputs_parse() {
+        assert_eq!(parse_log_format("json"), Some(LogFormat::Json));
+        assert_eq!(parse_log_format("xml"), None);
+        assert_eq!(parse_log_output("stdout"), LogOutput::Stdout);
+        assert_eq!(parse_log_output("/var/log/pubsub.log"), LogOutput::File("/var/log/pubsub.log".to_string()));
+    }
+}
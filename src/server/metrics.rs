@@ -0,0 +1,169 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use std::fmt::Write as FmtWrite;
+use std::io::Result;
+use std::net::ToSocketAddrs;
+
+use super::http::{self, HttpHandle, Response};
+use super::stats::LATENCY_BUCKETS;
+use super::topics::Topics;
+
+/// Metrics
+///
+/// Serves the broker statistics of a topic store over http in the
+/// Prometheus text format. Only GET /metrics is answered.
+pub struct Metrics;
+impl Metrics {
+    
+    /// starts a metrics listener on this addr in the background.
+    pub fn start<T: ToSocketAddrs>(addr: T, topics: Topics) -> Result<HttpHandle> {
+        http::serve(addr, topics.connections().clone(), move |request, _| {
+            match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/metrics") => Response::new(200, "text/plain; version=0.0.4", Metrics::render(&topics)),
+                ("GET", _)          => Response::text(404, "not found"),
+                _                   => Response::text(405, "method not allowed")
+            }
+        })
+    }
+    
+    /// renders the statistics of this topic store.
+    pub fn render(topics: &Topics) -> String {
+        let stats  = topics.stats();
+        let mut output = String::new();
+        let counters = [
+            ("pubsub_connections_total",        "counter", "Connections accepted.",                      stats.connections()),
+            ("pubsub_messages_published_total", "counter", "Messages published by clients.",             stats.messages()),
+            ("pubsub_messages_delivered_total", "counter", "Messages queued for subscribers.",           stats.delivered()),
+            ("pubsub_messages_dropped_total",   "counter", "Messages subscribers failed to take.",       stats.dropped()),
+            ("pubsub_published_bytes_total",    "counter", "Message bytes published by clients.",        stats.bytes()),
+            ("pubsub_received_bytes_total",     "counter", "Bytes read from clients.",                   stats.bytes_in()),
+            ("pubsub_sent_bytes_total",         "counter", "Bytes written to clients.",                  stats.bytes_out()),
+            ("pubsub_parse_errors_total",       "counter", "Client lines that could not be parsed.",     stats.parse_errors()),
+            ("pubsub_clients",                  "gauge",   "Clients currently connected.",               stats.clients()),
+            ("pubsub_topics",                   "gauge",   "Topics currently held.",                     topics.len() as u64),
+            ("pubsub_queue_depth",              "gauge",   "Lines waiting in client output queues.",     stats.queued())
+        ];
+        for &(name, kind, help, value) in counters.iter() {
+            let _ = write!(output, "# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value);
+        }
+        
+        output.push_str("# HELP pubsub_subscriptions Subscribers of each topic.\n");
+        output.push_str("# TYPE pubsub_subscriptions gauge\n");
+        let mut subscriptions = topics.subscriptions();
+        subscriptions.sort();
+        for (topic_key, count) in subscriptions {
+            let _ = writeln!(output, "pubsub_subscriptions{{topic=\"{}\"}} {}", Metrics::escape(&topic_key), count);
+        }
+        
+        output.push_str("# HELP pubsub_publish_duration_seconds Time taken to fan a message out to subscribers.\n");
+        output.push_str("# TYPE pubsub_publish_duration_seconds histogram\n");
+        let (buckets, sum) = stats.latency();
+        let mut count = 0;
+        for (index, bucket) in buckets.iter().enumerate() {
+            count += bucket;
+            let bound = match LATENCY_BUCKETS.get(index) {
+                Some(micros) => format!("{}", *micros as f64 / 1_000_000.0),
+                None => "+Inf".to_string()
+            };
+            let _ = writeln!(output, "pubsub_publish_duration_seconds_bucket{{le=\"{}\"}} {}", bound, count);
+        }
+        let _ = writeln!(output, "pubsub_publish_duration_seconds_sum {}", sum.as_secs_f64());
+        let _ = writeln!(output, "pubsub_publish_duration_seconds_count {}", count);
+        output
+    }
+    
+    /// escapes this value as a label value.
+    fn escape(value: &str) -> String {
+        value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::prelude::*;
+    use std::net::TcpStream;
+    
+    /// returns the sample of this metric and labels in this output.
+    fn sample<'a>(output: &'a str, metric: &str) -> Option<&'a str> {
+        output.lines().find(|line| line.starts_with(metric) && line[metric.len()..].starts_with(' ')).map(|line| &line[metric.len() + 1..])
+    }
+    
+    #[test]
+    fn render_counts_messages_and_subscriptions() {
+        let topics   = Topics::new();
+        let _news    = topics.subscribe_channel("news");
+        let _sport   = topics.subscribe_channel("sp\"ort");
+        let _again   = topics.subscribe_channel("news");
+        topics.publish("news".to_string(), "dave".to_string(), "hello".to_string());
+        topics.publish("weather".to_string(), "dave".to_string(), "rain".to_string());
+        
+        let output = Metrics::render(&topics);
+        assert!(output.contains("# TYPE pubsub_messages_published_total counter\n"));
+        assert!(output.contains("# TYPE pubsub_topics gauge\n"));
+        assert_eq!(sample(&output, "pubsub_messages_published_total"), Some("2"));
+        assert_eq!(sample(&output, "pubsub_messages_delivered_total"), Some("2"));
+        assert_eq!(sample(&output, "pubsub_published_bytes_total"), Some("9"));
+        assert_eq!(sample(&output, "pubsub_topics"), Some("2"));
+        assert_eq!(sample(&output, "pubsub_subscriptions{topic=\"news\"}"), Some("2"));
+        assert_eq!(sample(&output, "pubsub_subscriptions{topic=\"sp\\\"ort\"}"), Some("1"));
+    }
+    
+    #[test]
+    fn render_accumulates_latency_buckets() {
+        let topics = Topics::new();
+        let _news  = topics.subscribe_channel("news");
+        topics.publish("news".to_string(), "dave".to_string(), "hello".to_string());
+        
+        let output  = Metrics::render(&topics);
+        let buckets = output.lines().filter(|line| line.starts_with("pubsub_publish_duration_seconds_bucket")).collect::<Vec<_>>();
+        assert_eq!(buckets.len(), LATENCY_BUCKETS.len() + 1);
+        assert!(buckets[0].starts_with("pubsub_publish_duration_seconds_bucket{le=\"0.00001\"} "));
+        let counts = buckets.iter().map(|line| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap()).collect::<Vec<_>>();
+        assert!(counts.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(buckets.last(), Some(&"pubsub_publish_duration_seconds_bucket{le=\"+Inf\"} 1"));
+        assert_eq!(sample(&output, "pubsub_publish_duration_seconds_count"), Some("1"));
+    }
+    
+    #[test]
+    fn only_get_metrics_is_served() {
+        let handle  = Metrics::start("127.0.0.1:0", Topics::new()).unwrap();
+        let request = |request: &str| {
+            let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+        let response = request("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\n\r\n# HELP pubsub_connections_total"));
+        assert!(request("GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(request("POST /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+        handle.shutdown();
+    }
+}
@@ -24,8 +24,44 @@
  THE SOFTWARE.
 ---------------------------------------------------------------------------*/
 
+pub mod bridge;
+pub mod config;
+pub mod connections;
+pub mod gateway;
+pub mod http;
+pub mod limiter;
+pub mod logger;
+pub mod metrics;
+pub mod mqtt;
+pub mod options;
+pub mod redis;
+pub mod settings;
+pub mod stats;
+pub mod stomp;
+pub mod subscriber;
+pub mod sys;
+pub mod writer;
 pub mod topics;
+pub mod users;
+pub mod websocket;
+#[allow(clippy::module_inception)]
 pub mod server;
 
+pub use self::bridge::{Bridge, BridgeConfig, BridgeHandle};
+pub use self::config::{Config, ConfigError};
+pub use self::gateway::Gateway;
+pub use self::http::HttpHandle;
+pub use self::limiter::{Rate, RateAction};
+pub use self::logger::{LogConfig, LogFormat, LogOutput, Logger};
+pub use self::metrics::Metrics;
+pub use self::mqtt::{Mqtt, MqttHandle};
+pub use self::options::Options;
+pub use self::redis::{Redis, RedisHandle};
+pub use self::settings::Settings;
+pub use self::stats::Stats;
+pub use self::stomp::{Stomp, StompHandle};
+pub use self::subscriber::Subscriber;
+pub use self::sys::Sys;
 pub use self::topics::Topics;
-pub use self::server::Server;
\ No newline at end of file
+pub use self::users::User;
+pub use self::server::{Server, ServerHandle};
\ No newline at end of file
@@ -0,0 +1,1013 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use uuid::Uuid;
+
+use std::collections::{HashMap, HashSet};
+use std::io::prelude::*;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::super::protocol::Message;
+use super::connections::{revoked_reason, Connection, Connections, Identity};
+use super::limiter::{RateAction, RateLimit};
+use super::options::Options;
+use super::server::wake;
+use super::settings::Settings;
+use super::subscriber::QueueSubscriber;
+use super::sys::{is_reserved, SYS_PREFIX};
+use super::topics::Topics;
+use super::users::User;
+use super::writer::{Framing, Writer};
+
+/// maximum remaining length of a packet.
+const MAX_PACKET: usize = 16 << 20;
+
+/// time allowed for a client to send its CONNECT packet.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// highest quality of service granted to subscribers.
+const MAX_QOS: u8 = 1;
+
+/// most QoS 2 publishes a client may leave awaiting PUBREL.
+const MAX_UNRELEASED: usize = 1024;
+
+// control packet types.
+const CONNECT     : u8 = 1;
+const CONNACK     : u8 = 2;
+const PUBLISH     : u8 = 3;
+const PUBACK      : u8 = 4;
+const PUBREC      : u8 = 5;
+const PUBREL      : u8 = 6;
+const PUBCOMP     : u8 = 7;
+const SUBSCRIBE   : u8 = 8;
+const SUBACK      : u8 = 9;
+const UNSUBSCRIBE : u8 = 10;
+const UNSUBACK    : u8 = 11;
+const PINGREQ     : u8 = 12;
+const PINGRESP    : u8 = 13;
+const DISCONNECT  : u8 = 14;
+
+// CONNACK return codes.
+const ACCEPTED            : u8 = 0;
+const BAD_PROTOCOL        : u8 = 1;
+const BAD_IDENTIFIER      : u8 = 2;
+const SERVER_UNAVAILABLE  : u8 = 3;
+const BAD_CREDENTIALS     : u8 = 4;
+const NOT_AUTHORIZED      : u8 = 5;
+
+///------------------------------------
+/// Input
+///
+/// Reads the fields of a packet body.
+///------------------------------------
+struct Input<'a> {
+    bytes  : &'a [u8],
+    offset : usize
+}
+impl<'a> Input<'a> {
+    fn new(bytes: &'a [u8]) -> Input<'a> {
+        Input { bytes, offset: 0 }
+    }
+    
+    fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() - self.offset < count {
+            return Err(Error::new(ErrorKind::InvalidData, "truncated packet"));
+        }
+        self.offset += count;
+        Ok(&self.bytes[self.offset - count..self.offset])
+    }
+    
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    
+    fn short(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+    
+    fn binary(&mut self) -> Result<&'a [u8]> {
+        let length = self.short()? as usize;
+        self.take(length)
+    }
+    
+    fn string(&mut self) -> Result<String> {
+        let bytes = self.binary()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| Error::new(ErrorKind::InvalidData, "string is not utf-8"))
+    }
+    
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.bytes[self.offset..];
+        self.offset = self.bytes.len();
+        rest
+    }
+    
+    fn is_empty(&self) -> bool {
+        self.offset >= self.bytes.len()
+    }
+}
+
+///------------------------------------
+/// Output
+///
+/// Queues packets for a client on its
+/// writer. Shared between the reading
+/// and delivering threads of a
+/// connection.
+///------------------------------------
+#[derive(Clone)]
+struct Output {
+    writer : Writer
+}
+impl Output {
+    
+    /// queues a packet with this fixed header byte and body. Like
+    /// the output of native clients, packets are dropped when the
+    /// queue is full.
+    fn send(&self, header: u8, body: &[u8]) -> Result<()> {
+        let mut packet = Vec::with_capacity(body.len() + 5);
+        packet.push(header);
+        let mut length = body.len();
+        loop {
+            let digit = (length % 128) as u8;
+            length /= 128;
+            packet.push(if length > 0 { digit | 0x80 } else { digit });
+            if length == 0 {
+                break;
+            }
+        }
+        packet.extend_from_slice(body);
+        match self.writer.send_bytes(packet) {
+            Err(ref error) if error.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result
+        }
+    }
+    
+    /// writes an acknowledgement packet for this packet id.
+    fn ack(&self, header: u8, packet_id: u16) -> Result<()> {
+        self.send(header, &packet_id.to_be_bytes())
+    }
+}
+
+///------------------------------------
+/// Connect
+///
+/// An accepted CONNECT packet. The
+/// session is the key taken over by
+/// a later client, the user names the
+/// client in messages and the account
+/// is the user it authenticated as.
+///------------------------------------
+struct Connect {
+    session    : String,
+    user       : String,
+    account    : Option<(String, User)>,
+    will       : Option<(String, String)>,
+    keep_alive : u16
+}
+
+///------------------------------------
+/// Session
+///
+/// A connected client. The receiver
+/// disconnects once the client has
+/// been cleaned up.
+///------------------------------------
+struct Session {
+    id     : usize,
+    stream : TcpStream,
+    done   : Receiver<()>
+}
+
+///------------------------------------
+/// Sessions
+///
+/// The connected clients of a listener
+/// by session. A client connecting
+/// with the session of a connected
+/// client takes over, disconnecting it.
+///------------------------------------
+#[derive(Clone, Default)]
+struct Sessions {
+    dict : Arc<Mutex<HashMap<String, Session>>>
+}
+impl Sessions {
+    
+    ///-----------------------------------------
+    /// registers this client by its connection
+    /// id, returning the sender to drop once it
+    /// is cleaned up. A client with the same
+    /// session is disconnected and waited for,
+    /// so its cleanup cannot remove the
+    /// subscriptions of this one.
+    ///-----------------------------------------
+    fn insert(&self, session: &str, id: usize, stream: TcpStream) -> Sender<()> {
+        let (sender, done) = channel();
+        let previous = self.dict.lock().unwrap().insert(session.to_string(), Session { id, stream, done });
+        if let Some(previous) = previous {
+            info!(session:% = session; "mqtt client taken over");
+            let _ = previous.stream.shutdown(Shutdown::Both);
+            let _ = previous.done.recv_timeout(CONNECT_TIMEOUT);
+        } sender
+    }
+    
+    /// removes this client unless it has been taken over.
+    fn remove(&self, session: &str, id: usize) {
+        let mut dict = self.dict.lock().unwrap();
+        if dict.get(session).map(|session| session.id) == Some(id) {
+            dict.remove(session);
+        }
+    }
+}
+
+/// MqttHandle
+///
+/// Handle to a running mqtt listener.
+pub struct MqttHandle {
+    addr        : SocketAddr,
+    running     : Arc<AtomicBool>,
+    connections : Connections,
+    acceptor    : JoinHandle<Vec<(JoinHandle<()>, TcpStream)>>
+}
+impl MqttHandle {
+    
+    /// returns the address this listener is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+    
+    /// stops accepting clients, disconnects those connected,
+    /// allowing their queued output 5 seconds to drain, and joins
+    /// the worker threads. Clients still to send CONNECT, which
+    /// are not registered when refused, are disconnected too.
+    pub fn shutdown(self) {
+        self.running.store(false, Ordering::SeqCst);
+        wake(self.addr);
+        let workers = self.acceptor.join().unwrap_or_default();
+        self.connections.close(self.addr, "shutdown", Duration::from_secs(5));
+        for (worker, stream) in workers {
+            let _ = stream.shutdown(Shutdown::Both);
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Mqtt
+///
+/// Serves a topic store to MQTT 3.1.1 clients, so they share topics
+/// with clients of the native protocol. The following outlines how
+/// the protocol is mapped.
+///
+///  CONNECT     - the client id becomes the user, an empty id is
+///                replaced by a generated one. When users are
+///                configured the username and password must match
+///                and the username becomes the user instead.
+///  PUBLISH     - published like p:, at QoS 0, 1 or 2. QoS 2 packet
+///                ids are held until PUBREL, so a retransmission is
+///                not published again. Payloads must be single
+///                line utf-8; retain is ignored.
+///  SUBSCRIBE   - subscribed like s:, granted QoS 0 or 1. Topic
+///                filters are matched exactly, so wildcard filters
+///                are refused, see is_filter.
+///  UNSUBSCRIBE - unsubscribed like u:.
+///
+/// Sessions are not persisted, so every session starts clean and
+/// QoS 1 messages are not retried. A will is published when a
+/// client disconnects without DISCONNECT, checked like a publish
+/// and dropped if it exceeds a rate. Limits and rates apply as for
+/// tcp clients, and clients are revoked like theirs on reload.
+pub struct Mqtt;
+impl Mqtt {
+    
+    /// starts a mqtt listener on this addr in the background.
+    pub fn start<T: ToSocketAddrs>(addr: T, topics: Topics, settings: Settings) -> Result<MqttHandle> {
+        let listener = TcpListener::bind(addr)?;
+        let addr     = listener.local_addr()?;
+        let running  = Arc::new(AtomicBool::new(true));
+        let connections = topics.connections().clone();
+        let acceptor = {
+            let running     = running.clone();
+            let connections = connections.clone();
+            let sessions    = Sessions::default();
+            thread::spawn(move || {
+                let mut workers: Vec<(JoinHandle<()>, TcpStream)> = Vec::new();
+                for stream in listener.incoming() {
+                    if !running.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let (stream, peer) = match stream.and_then(|stream| {
+                        let peer = stream.peer_addr()?;
+                        Ok((stream, peer))
+                    }) {
+                        Ok(accepted) => accepted,
+                        Err(error)   => {
+                            warn!(error:% = error; "mqtt accept failed");
+                            continue;
+                        }
+                    };
+                    let config  = settings.get();
+                    let options = &config.options;
+                    let (registered, output, closer) = match (stream.try_clone(), stream.try_clone(), stream.try_clone()) {
+                        (Ok(registered), Ok(output), Ok(closer)) => (registered, output, closer),
+                        _ => continue
+                    };
+                    
+                    // refused clients are answered once their CONNECT
+                    // packet is read, so they are given a writer too.
+                    let (writer, flusher) = Writer::spawn(output, Framing::Raw, options.queue_capacity, options.flush_latency, topics.stats().clone());
+                    let key      = Uuid::new_v4().to_hyphenated_string();
+                    let user     = Arc::new(Mutex::new(key.clone()));
+                    let identity = Identity::default();
+                    let registered = connections.insert(options, Connection {
+                        stream   : registered,
+                        addr     : peer,
+                        listener : addr,
+                        writer   : writer.clone(),
+                        identity : identity.clone(),
+                        key      : key.clone(),
+                        user     : user.clone()
+                    });
+                    let (topics, settings, connections, sessions) = (topics.clone(), settings.clone(), connections.clone(), sessions.clone());
+                    workers.retain(|(worker, _)| !worker.is_finished());
+                    workers.push((thread::spawn(move || {
+                        let id = registered.as_ref().ok().cloned();
+                        if let Err(error) = Mqtt::client(registered, key, user, identity, &stream, writer.clone(), &topics, &settings, &sessions) {
+                            debug!(peer:% = peer, error:% = error; "mqtt client failed");
+                        }
+                        writer.close();
+                        let _ = flusher.join();
+                        let _ = stream.shutdown(Shutdown::Both);
+                        if let Some(id) = id {
+                            connections.remove(id);
+                        }
+                    }), closer));
+                } workers
+            })
+        };
+        Ok(MqttHandle { addr, running, connections, acceptor })
+    }
+    
+    /// reads the next packet, returning its fixed header byte and
+    /// body, or None once the client closes the connection.
+    fn read<R: Read>(reader: &mut R) -> Result<Option<(u8, Vec<u8>)>> {
+        let mut header = [0; 1];
+        if reader.read(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let mut length = 0;
+        for shift in 0..4 {
+            let mut digit = [0; 1];
+            reader.read_exact(&mut digit)?;
+            length |= ((digit[0] & 0x7F) as usize) << (7 * shift);
+            if digit[0] & 0x80 == 0 {
+                if length > MAX_PACKET {
+                    return Err(Error::new(ErrorKind::InvalidData, format!("packet exceeds {} bytes", MAX_PACKET)));
+                }
+                // read as the bytes arrive, rather than allocating
+                // the length the client declares up front.
+                let mut body = Vec::new();
+                reader.take(length as u64).read_to_end(&mut body)?;
+                if body.len() < length {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "truncated packet"));
+                }
+                return Ok(Some((header[0], body)));
+            }
+        }
+        Err(Error::new(ErrorKind::InvalidData, "malformed remaining length"))
+    }
+    
+    ///-----------------------------------------
+    /// returns true if this topic holds the +
+    /// or # wildcards. Topics are matched
+    /// exactly by the store, which has no
+    /// notion of levels, so filters cannot be
+    /// subscribed and would never match. They
+    /// are refused rather than subscribed as
+    /// plain names, which a client would take
+    /// for a working wildcard.
+    ///-----------------------------------------
+    fn is_filter(topic_key: &str) -> bool {
+        topic_key.contains('+') || topic_key.contains('#')
+    }
+    
+    /// checks a publish or will against the delimiters of the native
+    /// protocol and the limits in these options, returning the
+    /// reason if it is dropped.
+    fn check_publish(options: &Options, topic_key: &str, message: &str) -> Option<String> {
+        if message.contains('\n') || message.contains('\r') {
+            return Some("payload contains line breaks".to_string());
+        }
+        if options.max_line_length.map(|max| message.len() > max).unwrap_or(false) {
+            return Some("payload too long".to_string());
+        }
+        if is_reserved(topic_key) {
+            return Some(format!("topic {} is reserved", topic_key));
+        }
+        options.check_name("topic", topic_key)
+    }
+    
+    /// reads the CONNECT packet and answers it, returning None if
+    /// the client is refused, as it is for this reason if one is
+    /// given.
+    fn connect<R: Read>(reader: &mut R, output: &Output, options: &Options, refused: Option<&String>, addr: SocketAddr) -> Result<Option<Connect>> {
+        let body = match Mqtt::read(reader)? {
+            Some((header, body)) if header >> 4 == CONNECT => body,
+            _ => return Err(Error::new(ErrorKind::InvalidData, "expected CONNECT"))
+        };
+        let mut input = Input::new(&body);
+        let protocol  = input.string()?;
+        let level     = input.byte()?;
+        if protocol != "MQTT" || level != 4 {
+            output.send(CONNACK << 4, &[0, BAD_PROTOCOL])?;
+            return Ok(None);
+        }
+        let flags      = input.byte()?;
+        let keep_alive = input.short()?;
+        let mut client_id = input.string()?;
+        let will = if flags & 0x04 != 0 {
+            let topic   = input.string()?;
+            let message = String::from_utf8(input.binary()?.to_vec()).map_err(|_| "payload is not utf-8".to_string());
+            let checked = message.and_then(|message| match Mqtt::check_publish(options, &topic, &message) {
+                _ if Mqtt::is_filter(&topic) => Err("topic is a filter".to_string()),
+                Some(reason) => Err(reason),
+                None => Ok(message)
+            });
+            match checked {
+                Ok(message) => Some((topic, message)),
+                Err(reason) => {
+                    debug!(peer:% = addr, topic:% = topic, reason:% = reason; "mqtt will dropped");
+                    None
+                }
+            }
+        } else { None };
+        let username = if flags & 0x80 != 0 { Some(input.string()?) } else { None };
+        let password = if flags & 0x40 != 0 { Some(String::from_utf8_lossy(input.binary()?).into_owned()) } else { None };
+        
+        // identify and authenticate the client.
+        if client_id.is_empty() {
+            if flags & 0x02 == 0 {
+                output.send(CONNACK << 4, &[0, BAD_IDENTIFIER])?;
+                return Ok(None);
+            }
+            client_id = Uuid::new_v4().to_hyphenated_string();
+        }
+        if options.check_name("client id", &client_id).is_some() || is_reserved(&client_id) {
+            output.send(CONNACK << 4, &[0, BAD_IDENTIFIER])?;
+            return Ok(None);
+        }
+        let account = match options.users {
+            Some(ref users) => {
+                let checked = match (username.as_ref(), password.as_ref()) {
+                    (Some(username), Some(password)) => match users.get(username) {
+                        Some(user) if user.verify(password) => Ok((username.clone(), user.clone())),
+                        _ => Err(BAD_CREDENTIALS)
+                    },
+                    _ => Err(NOT_AUTHORIZED)
+                };
+                match checked {
+                    Ok(account) => Some(account),
+                    Err(code)   => {
+                        warn!(peer:% = addr, user:% = username.unwrap_or_default(); "mqtt authentication failed");
+                        output.send(CONNACK << 4, &[0, code])?;
+                        return Ok(None);
+                    }
+                }
+            },
+            None => None
+        };
+        if let Some(reason) = refused {
+            warn!(peer:% = addr, reason:% = reason; "mqtt connection refused");
+            output.send(CONNACK << 4, &[0, SERVER_UNAVAILABLE])?;
+            return Ok(None);
+        }
+        output.send(CONNACK << 4, &[0, ACCEPTED])?;
+        
+        // authenticated clients are named by their username, and may
+        // only take over sessions of the same user.
+        let (session, user) = match account {
+            Some((ref username, _)) => (format!("{}:{}", username, client_id), username.clone()),
+            None => (client_id.clone(), client_id)
+        };
+        Ok(Some(Connect { session, user, account, will, keep_alive }))
+    }
+    
+    ///-----------------------------------------
+    /// serves a client until it disconnects.
+    /// Its subscriptions are held by the key
+    /// of its connection, which is registered
+    /// unless the connection limits refused it.
+    /// The name and identity of the connection
+    /// are set once CONNECT is accepted, so it
+    /// is listed and revoked like tcp clients.
+    ///-----------------------------------------
+    #[allow(clippy::too_many_arguments)]
+    fn client(registered: ::std::result::Result<usize, String>, user_key: String, name: Arc<Mutex<String>>, identity: Identity, stream: &TcpStream, writer: Writer, topics: &Topics, settings: &Settings, sessions: &Sessions) -> Result<()> {
+        let addr   = stream.peer_addr()?;
+        let output = Output { writer };
+        let mut reader = BufReader::new(stream.try_clone()?);
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+        let Connect { session, user, account, mut will, keep_alive } = match Mqtt::connect(&mut reader, &output, &settings.get().options, registered.as_ref().err(), addr)? {
+            Some(connect) => connect,
+            None => return Ok(())
+        };
+        let id = match registered {
+            Ok(id)  => id,
+            Err(_)  => return Ok(())
+        };
+        
+        // clients are dropped after one and a half keep alive
+        // intervals without a packet.
+        let keep_alive = if keep_alive > 0 { Some(Duration::from_millis(keep_alive as u64 * 1500)) } else { None };
+        stream.set_read_timeout(keep_alive)?;
+        let done = sessions.insert(&session, id, stream.try_clone()?);
+        topics.set_name(&user_key, &user);
+        *name.lock().unwrap() = user.clone();
+        *identity.lock().unwrap() = account.clone();
+        topics.stats().connect();
+        topics.publish(format!("{}clients/connected", SYS_PREFIX), user.clone(), addr.to_string());
+        info!(conn = id, peer:% = addr, user:% = user; "mqtt client connected");
+        
+        // deliver messages from a queue shared by every subscription.
+        let granted = Arc::new(Mutex::new(HashMap::new()));
+        let (sender, receiver) = sync_channel(settings.get().options.queue_capacity);
+        let deliverer = {
+            let (output, granted) = (output.clone(), granted.clone());
+            thread::spawn(move || Mqtt::deliver(&output, &receiver, &granted))
+        };
+        let mut rate_limit = None;
+        let reason = Mqtt::serve(&mut reader, &output, topics, settings, &user_key, &user, account.as_ref(), &sender, &granted, &mut will, &mut rate_limit);
+        
+        // unsubscribe, which ends the deliverer once the queue drains.
+        let _ = stream.shutdown(Shutdown::Read);
+        drop(sender);
+        topics.delete_user_key(user_key.clone());
+        let _ = deliverer.join();
+        sessions.remove(&session, id);
+        
+        // the will shares the rates of the connection, and is dropped
+        // when it exceeds one as there is no client left to slow down.
+        if let Some((topic_key, message)) = will {
+            match topics.limiter().acquire(&settings.get().options, &mut rate_limit, &user, &topic_key, message.len()) {
+                None    => {
+                    topics.publish(topic_key, user.clone(), message);
+                },
+                Some(_) => debug!(user:% = user, topic:% = topic_key; "mqtt will rate exceeded")
+            }
+        }
+        info!(conn = id, peer:% = addr, user:% = user, reason:% = reason; "mqtt client disconnected");
+        topics.stats().disconnect();
+        topics.publish(format!("{}clients/disconnected", SYS_PREFIX), user, addr.to_string());
+        drop(done);
+        Ok(())
+    }
+    
+    /// handles packets after CONNECT, returning why the client was
+    /// disconnected. Subscriptions are held by the connection key,
+    /// publishes are named by the user. A DISCONNECT clears the will.
+    #[allow(clippy::too_many_arguments)]
+    fn serve<R: Read>(reader: &mut R, output: &Output, topics: &Topics, settings: &Settings, user_key: &str, user: &str, account: Option<&(String, User)>, sender: &SyncSender<Message>, granted: &Mutex<HashMap<String, u8>>, will: &mut Option<(String, String)>, rate_limit: &mut Option<RateLimit>) -> String {
+        let mut received = HashSet::new();
+        loop {
+            let (header, body) = match Mqtt::read(reader) {
+                Ok(Some(packet)) => packet,
+                Ok(None) => return "closed by client".to_string(),
+                Err(ref error) if error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut => return "keep alive timed out".to_string(),
+                Err(error) => return format!("read failed: {}", error)
+            };
+            topics.stats().receive(body.len() + 2);
+            let config  = settings.get();
+            let options = &config.options;
+            if let (Some(users), Some((name, account))) = (options.users.as_ref(), account) {
+                if let Some(reason) = revoked_reason(users, name, account) {
+                    return reason.to_string();
+                }
+            }
+            let mut input = Input::new(&body);
+            let result = match header >> 4 {
+                PUBLISH => {
+                    let qos = (header >> 1) & 0x03;
+                    let published = input.string().and_then(|topic_key| {
+                        let packet_id = if qos > 0 { Some(input.short()?) } else { None };
+                        Ok((topic_key, packet_id, input.rest()))
+                    });
+                    let (topic_key, packet_id, payload) = match published {
+                        Ok(published) if qos < 3 && !Mqtt::is_filter(&published.0) => published,
+                        _ => return "malformed PUBLISH".to_string()
+                    };
+                    let message = String::from_utf8(payload.to_vec()).ok();
+                    let rejected = match message {
+                        Some(ref message) => Mqtt::check_publish(options, &topic_key, message),
+                        None => Some("payload is not utf-8".to_string())
+                    };
+                    
+                    // a QoS 2 packet id is released by PUBREL, until then
+                    // a retransmission is acknowledged without publishing.
+                    // Clients leaving too many unreleased are dropped, so
+                    // the ids held stay bounded.
+                    let repeated = match (qos, packet_id) {
+                        (2, Some(packet_id)) if received.len() >= MAX_UNRELEASED && !received.contains(&packet_id) => {
+                            return format!("more than {} QoS 2 publishes awaiting PUBREL", MAX_UNRELEASED);
+                        },
+                        (2, Some(packet_id)) => !received.insert(packet_id),
+                        _ => false
+                    };
+                    match (rejected, message) {
+                        _ if repeated => debug!(user:% = user, topic:% = topic_key; "mqtt publish repeated"),
+                        (Some(reason), _) => debug!(user:% = user, topic:% = topic_key, reason:% = reason; "mqtt publish dropped"),
+                        (None, Some(message)) => {
+                            let wait = topics.limiter().acquire(options, rate_limit, user, &topic_key, message.len());
+                            match (wait, options.rate_action) {
+                                (None, _) => {
+                                    topics.publish(topic_key, user.to_string(), message);
+                                },
+                                (Some(wait), RateAction::Delay) => {
+                                    thread::sleep(wait);
+                                    topics.publish(topic_key, user.to_string(), message);
+                                },
+                                (Some(_), RateAction::Reject) => debug!(user:% = user, topic:% = topic_key; "mqtt publish rate exceeded"),
+                                (Some(_), RateAction::Disconnect) => return format!("publish rate exceeded for {}", topic_key)
+                            }
+                        },
+                        (None, None) => {}
+                    }
+                    match (qos, packet_id) {
+                        (1, Some(packet_id)) => output.ack(PUBACK << 4, packet_id),
+                        (2, Some(packet_id)) => output.ack(PUBREC << 4, packet_id),
+                        _ => Ok(())
+                    }
+                },
+                PUBREL => input.short().and_then(|packet_id| {
+                    received.remove(&packet_id);
+                    output.ack(PUBCOMP << 4, packet_id)
+                }),
+                PUBACK | PUBREC | PUBCOMP => Ok(()),
+                SUBSCRIBE if header & 0x0F == 0x02 => {
+                    let packet_id = match input.short() {
+                        Ok(packet_id) => packet_id,
+                        Err(_) => return "malformed SUBSCRIBE".to_string()
+                    };
+                    let mut codes = packet_id.to_be_bytes().to_vec();
+                    while !input.is_empty() {
+                        let (topic_key, qos) = match input.string().and_then(|topic_key| Ok((topic_key, input.byte()?))) {
+                            Ok(filter) => filter,
+                            Err(_) => return "malformed SUBSCRIBE".to_string()
+                        };
+                        let subscribed = topics.topics_of(user_key);
+                        let full = options.max_subscriptions.map(|max| subscribed.len() >= max && !subscribed.contains(&topic_key)).unwrap_or(false);
+                        if qos > 2 || full || Mqtt::is_filter(&topic_key) || options.check_name("topic", &topic_key).is_some() {
+                            debug!(user:% = user, topic:% = topic_key; "mqtt subscription refused");
+                            codes.push(0x80);
+                            continue;
+                        }
+                        let qos = qos.min(MAX_QOS);
+                        granted.lock().unwrap().insert(topic_key.clone(), qos);
+                        topics.subscribe(topic_key, user_key.to_string(), Box::new(QueueSubscriber::new(sender.clone())));
+                        codes.push(qos);
+                    }
+                    output.send(SUBACK << 4, &codes)
+                },
+                UNSUBSCRIBE if header & 0x0F == 0x02 => {
+                    let packet_id = match input.short() {
+                        Ok(packet_id) => packet_id,
+                        Err(_) => return "malformed UNSUBSCRIBE".to_string()
+                    };
+                    while !input.is_empty() {
+                        match input.string() {
+                            Ok(topic_key) => {
+                                granted.lock().unwrap().remove(&topic_key);
+                                topics.unsubscribe(topic_key, user_key.to_string());
+                            },
+                            Err(_) => return "malformed UNSUBSCRIBE".to_string()
+                        }
+                    }
+                    output.ack(UNSUBACK << 4, packet_id)
+                },
+                PINGREQ => output.send(PINGRESP << 4, &[]),
+                DISCONNECT => {
+                    *will = None;
+                    return "disconnected by client".to_string();
+                },
+                packet_type => return format!("unexpected packet type {}", packet_type)
+            };
+            if let Err(error) = result {
+                return format!("write failed: {}", error);
+            }
+        }
+    }
+    
+    /// writes the messages received on this queue as PUBLISH packets
+    /// at the QoS granted for their topic.
+    fn deliver(output: &Output, receiver: &Receiver<Message>, granted: &Mutex<HashMap<String, u8>>) {
+        let mut packet_id: u16 = 0;
+        for message in receiver.iter() {
+            let qos = match granted.lock().unwrap().get(&message.topic) {
+                Some(&qos) if message.topic.len() <= 0xFFFF => qos,
+                _ => continue
+            };
+            let mut body = Vec::with_capacity(message.topic.len() + message.message.len() + 4);
+            body.extend_from_slice(&(message.topic.len() as u16).to_be_bytes());
+            body.extend_from_slice(message.topic.as_bytes());
+            if qos > 0 {
+                packet_id = packet_id.checked_add(1).unwrap_or(1);
+                body.extend_from_slice(&packet_id.to_be_bytes());
+            }
+            body.extend_from_slice(message.message.as_bytes());
+            if output.send(PUBLISH << 4 | qos << 1, &body).is_err() {
+                break;
+            }
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use super::super::config::Config;
+    
+    /// returns a packet with this header and body, its remaining
+    /// length encoded in as few bytes as possible.
+    fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+        let mut bytes  = vec![header];
+        let mut length = body.len();
+        loop {
+            let digit = (length % 128) as u8;
+            length /= 128;
+            if length == 0 {
+                bytes.push(digit);
+                break;
+            }
+            bytes.push(digit | 0x80);
+        }
+        bytes.extend_from_slice(body);
+        bytes
+    }
+    
+    #[test]
+    fn remaining_lengths() {
+        for &(length, encoded) in [(0, 1), (127, 1), (128, 2), (16383, 2), (16384, 3), (2097152, 4)].iter() {
+            let body  = vec![7; length];
+            let bytes = packet(PUBLISH << 4, &body);
+            assert_eq!(bytes.len(), 1 + encoded + length);
+            assert_eq!(Mqtt::read(&mut &bytes[..]).unwrap(), Some((PUBLISH << 4, body)));
+        }
+    }
+    
+    #[test]
+    fn consecutive_packets() {
+        let mut bytes = packet(PINGREQ << 4, &[]);
+        bytes.extend(packet(DISCONNECT << 4, &[]));
+        let mut reader = &bytes[..];
+        assert_eq!(Mqtt::read(&mut reader).unwrap(), Some((PINGREQ << 4, Vec::new())));
+        assert_eq!(Mqtt::read(&mut reader).unwrap(), Some((DISCONNECT << 4, Vec::new())));
+        assert_eq!(Mqtt::read(&mut reader).unwrap(), None);
+    }
+    
+    #[test]
+    fn malformed_lengths() {
+        let five  = [PUBLISH << 4, 0xFF, 0xFF, 0xFF, 0xFF, 0x01];
+        assert_eq!(Mqtt::read(&mut &five[..]).unwrap_err().kind(), ErrorKind::InvalidData);
+        
+        let large = [PUBLISH << 4, 0x81, 0x80, 0x80, 0x08];
+        assert_eq!(Mqtt::read(&mut &large[..]).unwrap_err().kind(), ErrorKind::InvalidData);
+        
+        let unterminated = [PUBLISH << 4, 0x80];
+        assert_eq!(Mqtt::read(&mut &unterminated[..]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        
+        let truncated = [PUBLISH << 4, 3, 1, 2];
+        assert_eq!(Mqtt::read(&mut &truncated[..]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+    
+    /// returns this string with its length prefix.
+    fn string(value: &str) -> Vec<u8> {
+        let mut bytes = (value.len() as u16).to_be_bytes().to_vec();
+        bytes.extend_from_slice(value.as_bytes());
+        bytes
+    }
+    
+    /// returns a CONNECT packet for this client id, with these
+    /// credentials and will if given.
+    fn connect(client_id: &str, credentials: Option<(&str, &str)>, will: Option<(&str, &str)>) -> Vec<u8> {
+        let mut flags = 0x02;
+        let mut rest  = string(client_id);
+        if let Some((topic, message)) = will {
+            flags |= 0x04;
+            rest.extend(string(topic));
+            rest.extend(string(message));
+        }
+        if let Some((username, password)) = credentials {
+            flags |= 0xC0;
+            rest.extend(string(username));
+            rest.extend(string(password));
+        }
+        let mut body = string("MQTT");
+        body.extend_from_slice(&[4, flags, 0, 60]);
+        body.extend(rest);
+        packet(CONNECT << 4, &body)
+    }
+    
+    /// starts a listener with these options.
+    fn start(options: Options) -> (Topics, Settings, MqttHandle) {
+        let topics   = Topics::new();
+        let settings = Settings::new(Config { options, ..Config::default() });
+        let handle   = Mqtt::start("127.0.0.1:0", topics.clone(), settings.clone()).unwrap();
+        (topics, settings, handle)
+    }
+    
+    /// connects a client with this CONNECT packet, returning it and
+    /// the CONNACK return code.
+    fn open(handle: &MqttHandle, connect: &[u8]) -> (TcpStream, u8) {
+        let mut client = TcpStream::connect(handle.local_addr()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(connect).unwrap();
+        let (header, body) = Mqtt::read(&mut client).unwrap().unwrap();
+        assert_eq!((header, body.len()), (CONNACK << 4, 2));
+        (client, body[1])
+    }
+    
+    /// waits until this condition holds.
+    fn wait_for<F: Fn() -> bool>(condition: F) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    
+    /// returns true once the server has closed this client.
+    fn closed(client: &mut TcpStream) -> bool {
+        loop {
+            match Mqtt::read(client) {
+                Ok(Some(_)) => continue,
+                Ok(None)    => return true,
+                Err(error)  => return error.kind() != ErrorKind::WouldBlock && error.kind() != ErrorKind::TimedOut
+            }
+        }
+    }
+    
+    #[test]
+    fn connect_and_authenticate() {
+        let users = [("anne", "secret")].iter().map(|&(name, password)| (name.to_string(), User::new(password.to_string(), false))).collect::<HashMap<_, _>>();
+        let (topics, settings, handle) = start(Options { users: Some(users), ..Options::default() });
+        assert_eq!(open(&handle, &connect("one", None, None)).1, NOT_AUTHORIZED);
+        assert_eq!(open(&handle, &connect("one", Some(("anne", "wrong")), None)).1, BAD_CREDENTIALS);
+        assert_eq!(open(&handle, &connect("one:two", Some(("anne", "secret")), None)).1, BAD_IDENTIFIER);
+        
+        let mut old = connect("one", None, None);
+        old[8] = 3;
+        assert_eq!(open(&handle, &old).1, BAD_PROTOCOL);
+        
+        // authenticated clients are named by their username, and are
+        // revoked once it is removed.
+        let (mut client, code) = open(&handle, &connect("one", Some(("anne", "secret")), None));
+        assert_eq!(code, ACCEPTED);
+        wait_for(|| topics.connections().clients().iter().any(|client| client.user == "anne"));
+        let mut config = Config::default();
+        config.options.users = Some(HashMap::new());
+        settings.set(config, &topics).unwrap();
+        assert!(closed(&mut client));
+        wait_for(|| topics.connections().is_empty());
+        handle.shutdown();
+    }
+    
+    #[test]
+    fn connection_limits() {
+        let (topics, _, handle) = start(Options { max_connections: Some(1), ..Options::default() });
+        let (mut first, code) = open(&handle, &connect("first", None, None));
+        assert_eq!(code, ACCEPTED);
+        assert_eq!(open(&handle, &connect("second", None, None)).1, SERVER_UNAVAILABLE);
+        
+        // limits apply before a session is taken over, so reusing
+        // the id of a connected client does not get around them.
+        let (mut second, code) = open(&handle, &connect("first", None, None));
+        assert_eq!(code, SERVER_UNAVAILABLE);
+        assert!(closed(&mut second));
+        first.write_all(&packet(DISCONNECT << 4, &[])).unwrap();
+        assert!(closed(&mut first));
+        wait_for(|| topics.connections().is_empty());
+        
+        // shutdown disconnects clients still to send CONNECT.
+        let mut pending = TcpStream::connect(handle.local_addr()).unwrap();
+        pending.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let started = Instant::now();
+        handle.shutdown();
+        assert!(closed(&mut pending));
+        assert!(started.elapsed() < CONNECT_TIMEOUT);
+    }
+    
+    #[test]
+    fn publish_and_subscribe() {
+        let (topics, _, handle) = start(Options::default());
+        let (mut client, _) = open(&handle, &connect("", None, None));
+        let received = topics.subscribe_channel("news");
+        
+        let mut publish = string("news");
+        publish.extend_from_slice(&[0, 7]);
+        publish.extend_from_slice(b"hello");
+        client.write_all(&packet(PUBLISH << 4 | 1 << 1, &publish)).unwrap();
+        assert_eq!(Mqtt::read(&mut client).unwrap(), Some((PUBACK << 4, vec![0, 7])));
+        assert_eq!(received.recv_timeout(Duration::from_secs(5)).unwrap().message, "hello");
+        
+        // QoS 2 is granted as 1, and filters are refused.
+        let mut subscribe = vec![0, 1];
+        subscribe.extend(string("sport"));
+        subscribe.push(2);
+        subscribe.extend(string("sport/#"));
+        subscribe.push(0);
+        client.write_all(&packet(SUBSCRIBE << 4 | 0x02, &subscribe)).unwrap();
+        assert_eq!(Mqtt::read(&mut client).unwrap(), Some((SUBACK << 4, vec![0, 1, 1, 0x80])));
+        
+        topics.publish("sport".to_string(), "dave".to_string(), "goal".to_string());
+        let (header, body) = Mqtt::read(&mut client).unwrap().unwrap();
+        assert_eq!(header, PUBLISH << 4 | 1 << 1);
+        let mut expected = string("sport");
+        expected.extend_from_slice(&[0, 1]);
+        expected.extend_from_slice(b"goal");
+        assert_eq!(body, expected);
+        handle.shutdown();
+        assert!(closed(&mut client));
+    }
+    
+    #[test]
+    fn exactly_once() {
+        let (topics, _, handle) = start(Options::default());
+        let (mut client, _) = open(&handle, &connect("once", None, None));
+        let received = topics.subscribe_channel("news");
+        let publish  = |packet_id: u16, message: &str| {
+            let mut body = string("news");
+            body.extend_from_slice(&packet_id.to_be_bytes());
+            body.extend_from_slice(message.as_bytes());
+            packet(PUBLISH << 4 | 2 << 1, &body)
+        };
+        
+        // a retransmission before PUBREL is acknowledged but not
+        // published again.
+        for _ in 0..2 {
+            client.write_all(&publish(7, "hello")).unwrap();
+            assert_eq!(Mqtt::read(&mut client).unwrap(), Some((PUBREC << 4, vec![0, 7])));
+        }
+        client.write_all(&packet(PUBREL << 4 | 0x02, &[0, 7])).unwrap();
+        assert_eq!(Mqtt::read(&mut client).unwrap(), Some((PUBCOMP << 4, vec![0, 7])));
+        client.write_all(&publish(7, "again")).unwrap();
+        assert_eq!(Mqtt::read(&mut client).unwrap(), Some((PUBREC << 4, vec![0, 7])));
+        let messages = received.iter().take(2).map(|message| message.message).collect::<Vec<_>>();
+        assert_eq!(messages, vec!["hello".to_string(), "again".to_string()]);
+        
+        // clients leaving too many publishes unreleased are dropped.
+        let unreleased = (0..MAX_UNRELEASED as u16 + 1).flat_map(|packet_id| publish(packet_id + 100, "flood")).collect::<Vec<_>>();
+        client.write_all(&unreleased).unwrap();
+        assert!(closed(&mut client));
+        handle.shutdown();
+    }
+    
+    #[test]
+    fn wills() {
+        let (topics, _, handle) = start(Options { max_line_length: Some(8), ..Options::default() });
+        let received = topics.subscribe_channel("status");
+        
+        // a will is published when a client goes away without
+        // DISCONNECT, unless it is too long.
+        for &message in ["gone", "gone for good"].iter() {
+            let (client, code) = open(&handle, &connect("", None, Some(("status", message))));
+            assert_eq!(code, ACCEPTED);
+            drop(client);
+        }
+        let (mut client, _) = open(&handle, &connect("", None, Some(("status", "quit"))));
+        client.write_all(&packet(DISCONNECT << 4, &[])).unwrap();
+        assert!(closed(&mut client));
+        wait_for(|| topics.connections().is_empty());
+        
+        let wills = received.try_iter().map(|message| message.message).collect::<Vec<_>>();
+        assert_eq!(wills, vec!["gone".to_string()]);
+        handle.shutdown();
+    }
+}
@@ -0,0 +1,133 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use super::limiter::{Rate, RateAction};
+use super::users::User;
+
+/// Options
+///
+/// Configuration for a pubsub server. Defaults match the behavior
+/// of a server started with Server::bind.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// interval of client inactivity after which the server sends
+    /// a heartbeat ping. None disables heartbeats.
+    pub ping_interval : Option<Duration>,
+    
+    /// number of consecutive unanswered pings after which the
+    /// client is considered dead and disconnected.
+    pub ping_misses   : u32,
+    
+    /// maximum number of concurrent connections, across every
+    /// listener sharing a topic store.
+    pub max_connections        : Option<usize>,
+    
+    /// maximum number of concurrent connections from one ip address,
+    /// across every listener sharing a topic store.
+    pub max_connections_per_ip : Option<usize>,
+    
+    /// maximum number of topics a single connection may subscribe to.
+    pub max_subscriptions      : Option<usize>,
+    
+    /// maximum length in bytes of topic and user names.
+    pub max_name_length        : Option<usize>,
+    
+    /// maximum length in bytes of a protocol line, excluding the line
+    /// terminator. Clients sending longer lines are disconnected.
+    pub max_line_length        : Option<usize>,
+    
+    /// publish rate limit applied to each connection.
+    pub connection_rate : Option<Rate>,
+    
+    /// publish rate limit applied to each user, shared across all
+    /// connections identifying as that user.
+    pub user_rate       : Option<Rate>,
+    
+    /// publish rate limit applied to each topic, shared across all
+    /// publishers to that topic.
+    pub topic_rate      : Option<Rate>,
+    
+    /// what to do with a publish exceeding a rate limit.
+    pub rate_action     : RateAction,
+    
+    /// maximum number of lines queued for a connection. Messages
+    /// published to a connection with a full queue are dropped, and
+    /// a client whose replies cannot be queued is disconnected.
+    pub queue_capacity  : usize,
+    
+    /// how long a connection writer waits for further lines to
+    /// coalesce into one write. None writes as soon as possible,
+    /// coalescing only lines queued during the previous write.
+    pub flush_latency   : Option<Duration>,
+    
+    /// interval at which broker statistics are published to the
+    /// $SYS topics by a running Sys. None disables them.
+    pub sys_interval    : Option<Duration>,
+    
+    /// users allowed to connect. When set, clients must authenticate
+    /// before issuing other commands and cannot change identity.
+    pub users           : Option<HashMap<String, User>>
+}
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            ping_interval : None,
+            ping_misses   : 2,
+            max_connections        : None,
+            max_connections_per_ip : None,
+            max_subscriptions      : None,
+            max_name_length        : None,
+            max_line_length        : None,
+            connection_rate : None,
+            user_rate       : None,
+            topic_rate      : None,
+            rate_action     : RateAction::Reject,
+            queue_capacity  : 1024,
+            flush_latency   : None,
+            sys_interval    : None,
+            users           : None
+        }
+    }
+}
+
+impl Options {
+    
+    /// checks a topic or user name given through another protocol
+    /// against the delimiters of the native protocol and the name
+    /// length limit, returning the reason if it is refused.
+    pub fn check_name(&self, kind: &str, name: &str) -> Option<String> {
+        if name.is_empty() || name.contains(':') || name.contains('\n') || name.contains('\r') {
+            return Some(format!("{} must be non empty without ':' or line breaks", kind));
+        }
+        match self.max_name_length {
+            Some(max) if name.len() > max => Some(format!("{} exceeds {} bytes", kind, max)),
+            _ => None
+        }
+    }
+}
@@ -0,0 +1,830 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use uuid::Uuid;
+
+use std::collections::BTreeSet;
+use std::io::prelude::*;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::super::protocol::Message;
+use super::connections::{revoked_reason, Connection, Connections, Identity};
+use super::limiter::{RateAction, RateLimit};
+use super::options::Options;
+use super::server::wake;
+use super::settings::Settings;
+use super::subscriber::QueueSubscriber;
+use super::sys::{is_reserved, SYS_PREFIX};
+use super::topics::Topics;
+use super::users::User;
+use super::writer::{Framing, Writer};
+
+/// maximum length in bytes of a command when no line length limit
+/// is set.
+const MAX_COMMAND: usize = 16 << 20;
+
+/// maximum number of arguments of a command.
+const MAX_ARGS: usize = 1 << 16;
+
+/// commands allowed while subscribed.
+const SUBSCRIBED_COMMANDS: [&str; 6] = ["SUBSCRIBE", "UNSUBSCRIBE", "PSUBSCRIBE", "PUNSUBSCRIBE", "PING", "QUIT"];
+
+/// commands allowed before authenticating.
+const UNAUTHENTICATED_COMMANDS: [&str; 4] = ["AUTH", "HELLO", "PING", "QUIT"];
+
+///------------------------------------
+/// Reply
+///
+/// A RESP2 value sent to a client.
+///------------------------------------
+enum Reply {
+    Simple  (&'static str),
+    Error   (String),
+    Integer (i64),
+    Bulk    (Option<String>),
+    Array   (Vec<Reply>)
+}
+impl Reply {
+    
+    /// creates a bulk string reply.
+    fn bulk(value: &str) -> Reply {
+        Reply::Bulk(Some(value.to_string()))
+    }
+    
+    /// creates an error reply with the generic ERR prefix.
+    fn error(reason: &str) -> Reply {
+        Reply::Error(format!("ERR {}", reason))
+    }
+    
+    /// appends the encoding of this reply to this buffer.
+    fn encode(&self, output: &mut Vec<u8>) {
+        match *self {
+            Reply::Simple(value)       => output.extend_from_slice(format!("+{}\r\n", value).as_bytes()),
+            Reply::Error(ref reason)   => output.extend_from_slice(format!("-{}\r\n", reason).as_bytes()),
+            Reply::Integer(value)      => output.extend_from_slice(format!(":{}\r\n", value).as_bytes()),
+            Reply::Bulk(None)          => output.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(ref value)) => {
+                output.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+                output.extend_from_slice(value.as_bytes());
+                output.extend_from_slice(b"\r\n");
+            },
+            Reply::Array(ref values)   => {
+                output.extend_from_slice(format!("*{}\r\n", values.len()).as_bytes());
+                for value in values {
+                    value.encode(output);
+                }
+            }
+        }
+    }
+}
+
+///------------------------------------
+/// Output
+///
+/// Queues replies for a client on its
+/// writer. Shared between the reading
+/// and delivering threads of a
+/// connection.
+///------------------------------------
+#[derive(Clone)]
+struct Output {
+    writer : Writer
+}
+impl Output {
+    
+    /// queues these replies. Like the output of native clients,
+    /// they are dropped when the queue is full.
+    fn send(&self, replies: &[Reply]) -> Result<()> {
+        let mut buffer = Vec::new();
+        for reply in replies {
+            reply.encode(&mut buffer);
+        }
+        match self.writer.send_bytes(buffer) {
+            Err(ref error) if error.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result
+        }
+    }
+}
+
+///------------------------------------
+/// Client
+///
+/// State of a connected client. Its
+/// subscriptions are held by the key
+/// of its connection, the user key
+/// names it in messages and the
+/// account is the user it
+/// authenticated as.
+///------------------------------------
+struct Client {
+    id         : usize,
+    key        : String,
+    user_key   : String,
+    listed     : Arc<Mutex<String>>,
+    identity   : Identity,
+    name       : Option<String>,
+    account    : Option<(String, User)>,
+    channels   : BTreeSet<String>,
+    patterns   : BTreeSet<String>,
+    rate_limit : Option<RateLimit>,
+    sender     : SyncSender<(Option<String>, Message)>
+}
+impl Client {
+    fn subscriptions(&self) -> i64 {
+        (self.channels.len() + self.patterns.len()) as i64
+    }
+    
+    /// names this client in messages and the client list.
+    fn rename(&mut self, topics: &Topics, name: &str) {
+        topics.set_name(&self.key, name);
+        *self.listed.lock().unwrap() = name.to_string();
+        self.user_key = name.to_string();
+    }
+}
+
+/// RedisHandle
+///
+/// Handle to a running redis listener.
+pub struct RedisHandle {
+    addr        : SocketAddr,
+    running     : Arc<AtomicBool>,
+    connections : Connections,
+    acceptor    : JoinHandle<Vec<(JoinHandle<()>, TcpStream)>>
+}
+impl RedisHandle {
+    
+    /// returns the address this listener is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+    
+    /// stops accepting clients, disconnects those connected,
+    /// allowing their queued output 5 seconds to drain, and joins
+    /// the worker threads.
+    pub fn shutdown(self) {
+        self.running.store(false, Ordering::SeqCst);
+        wake(self.addr);
+        let workers = self.acceptor.join().unwrap_or_default();
+        self.connections.close(self.addr, "shutdown", Duration::from_secs(5));
+        for (worker, stream) in workers {
+            let _ = stream.shutdown(Shutdown::Both);
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Redis
+///
+/// Serves a topic store to redis clients over RESP2, so existing
+/// redis pub/sub clients share topics with native clients. The
+/// following outlines the supported commands.
+///
+///  SUBSCRIBE channel...      - subscribes like s:.
+///  UNSUBSCRIBE [channel...]  - unsubscribes like u:, from all
+///                              channels when none are given.
+///  PSUBSCRIBE pattern...     - subscribes to topics matching a glob.
+///  PUNSUBSCRIBE [pattern...] - unsubscribes from patterns.
+///  PUBLISH channel message   - publishes like p:, answered with the
+///                              number of receivers.
+///  PING [message]            - answered with PONG or the message.
+///  CLIENT SETNAME name       - identifies like i:.
+///  CLIENT GETNAME | ID | SETINFO
+///  AUTH username password    - authenticates like a: when users are
+///                              configured.
+///  QUIT
+///
+/// While subscribed only the subscription commands, PING and QUIT
+/// are accepted, as in redis. Messages must be single line utf-8,
+/// and a command may hold at most max_line_length bytes. Channels
+/// may contain ':', see check_channel. Limits and rates apply as
+/// for tcp clients, and clients are listed, kicked and revoked
+/// like theirs.
+pub struct Redis;
+impl Redis {
+    
+    /// starts a redis listener on this addr in the background.
+    pub fn start<T: ToSocketAddrs>(addr: T, topics: Topics, settings: Settings) -> Result<RedisHandle> {
+        let listener = TcpListener::bind(addr)?;
+        let addr     = listener.local_addr()?;
+        let running  = Arc::new(AtomicBool::new(true));
+        let connections = topics.connections().clone();
+        let acceptor = {
+            let running     = running.clone();
+            let connections = connections.clone();
+            thread::spawn(move || {
+                let mut workers: Vec<(JoinHandle<()>, TcpStream)> = Vec::new();
+                for stream in listener.incoming() {
+                    if !running.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let (stream, peer) = match stream.and_then(|stream| {
+                        let peer = stream.peer_addr()?;
+                        Ok((stream, peer))
+                    }) {
+                        Ok(accepted) => accepted,
+                        Err(error)   => {
+                            warn!(error:% = error; "redis accept failed");
+                            continue;
+                        }
+                    };
+                    let config  = settings.get();
+                    let options = &config.options;
+                    let (registered, output, closer) = match (stream.try_clone(), stream.try_clone(), stream.try_clone()) {
+                        (Ok(registered), Ok(output), Ok(closer)) => (registered, output, closer),
+                        _ => continue
+                    };
+                    let (writer, flusher) = Writer::spawn(output, Framing::Raw, options.queue_capacity, options.flush_latency, topics.stats().clone());
+                    let key    = Uuid::new_v4().to_hyphenated_string();
+                    let listed = Arc::new(Mutex::new(key.clone()));
+                    let identity = Identity::default();
+                    let registered = connections.insert(options, Connection {
+                        stream   : registered,
+                        addr     : peer,
+                        listener : addr,
+                        writer   : writer.clone(),
+                        identity : identity.clone(),
+                        key      : key.clone(),
+                        user     : listed.clone()
+                    });
+                    let id = match registered {
+                        Ok(id) => id,
+                        Err(reason) => {
+                            warn!(peer:% = peer, reason:% = reason; "redis connection refused");
+                            let _ = Output { writer: writer.clone() }.send(&[Reply::error(&reason)]);
+                            writer.close();
+                            let _ = flusher.join();
+                            let _ = stream.shutdown(Shutdown::Both);
+                            continue;
+                        }
+                    };
+                    let (topics, settings, connections) = (topics.clone(), settings.clone(), connections.clone());
+                    workers.retain(|(worker, _)| !worker.is_finished());
+                    workers.push((thread::spawn(move || {
+                        if let Err(error) = Redis::client(id, key, listed, identity, &stream, writer.clone(), &topics, &settings) {
+                            debug!(conn = id, error:% = error; "redis client failed");
+                        }
+                        writer.close();
+                        let _ = flusher.join();
+                        let _ = stream.shutdown(Shutdown::Both);
+                        connections.remove(id);
+                    }), closer));
+                } workers
+            })
+        };
+        Ok(RedisHandle { addr, running, connections, acceptor })
+    }
+    
+    /// reads one line of at most this many bytes without its line
+    /// ending.
+    fn line<R: BufRead>(reader: &mut R, limit: usize) -> Result<Option<String>> {
+        let mut line = String::new();
+        if reader.take(limit as u64 + 2).read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        if !line.ends_with('\n') {
+            return Err(Error::new(ErrorKind::InvalidData, "line too long or truncated"));
+        }
+        Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+    }
+    
+    ///-----------------------------------------
+    /// reads the next command as a multi bulk
+    /// array or an inline command, returning
+    /// None once the client closes. Its
+    /// arguments may hold at most limit bytes
+    /// in total, as a line of the native
+    /// protocol does, and are read as they
+    /// arrive rather than allocated up front
+    /// from the lengths the client declares.
+    ///-----------------------------------------
+    fn read<R: BufRead>(reader: &mut R, limit: usize) -> Result<Option<Vec<Vec<u8>>>> {
+        let invalid = |reason: &str| Error::new(ErrorKind::InvalidData, reason.to_string());
+        let line = match Redis::line(reader, limit)? {
+            Some(line) => line,
+            None => return Ok(None)
+        };
+        if !line.starts_with('*') {
+            return Ok(Some(line.split_whitespace().map(|arg| arg.as_bytes().to_vec()).collect()));
+        }
+        let count = line[1..].parse::<i64>().map_err(|_| invalid("invalid multibulk length"))?;
+        if count > MAX_ARGS as i64 {
+            return Err(invalid("too many arguments"));
+        }
+        let mut args  = Vec::new();
+        let mut total = 0;
+        for _ in 0..count {
+            let header = Redis::line(reader, limit)?.ok_or_else(|| invalid("truncated command"))?;
+            let length = match header.strip_prefix('$').map(|length| length.parse::<usize>()) {
+                Some(Ok(length)) => length,
+                _ => return Err(invalid("invalid bulk length"))
+            };
+            total += length;
+            if total > limit {
+                return Err(invalid(&format!("command exceeds {} bytes", limit)));
+            }
+            let mut arg = Vec::new();
+            reader.take(length as u64 + 2).read_to_end(&mut arg)?;
+            if arg.len() < length + 2 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "truncated bulk string"));
+            }
+            if !arg.ends_with(b"\r\n") {
+                return Err(invalid("bulk string not terminated"));
+            }
+            arg.truncate(length);
+            args.push(arg);
+        } Ok(Some(args))
+    }
+    
+    ///-----------------------------------------
+    /// checks a channel or pattern against the
+    /// line breaks and name length limit of the
+    /// native protocol. Unlike names given
+    /// through other protocols they may hold
+    /// ':', as redis names often do, so such
+    /// channels are shared with redis clients
+    /// only; native clients cannot name them.
+    ///-----------------------------------------
+    fn check_channel(options: &Options, kind: &str, name: &str) -> Option<String> {
+        if name.is_empty() || name.contains('\n') || name.contains('\r') {
+            return Some(format!("{} must be non empty without line breaks", kind));
+        }
+        match options.max_name_length {
+            Some(max) if name.len() > max => Some(format!("{} exceeds {} bytes", kind, max)),
+            _ => None
+        }
+    }
+    
+    /// serves a client until it disconnects.
+    #[allow(clippy::too_many_arguments)]
+    fn client(id: usize, key: String, listed: Arc<Mutex<String>>, identity: Identity, stream: &TcpStream, writer: Writer, topics: &Topics, settings: &Settings) -> Result<()> {
+        let addr   = stream.peer_addr()?;
+        let output = Output { writer };
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let (sender, receiver) = sync_channel(settings.get().options.queue_capacity);
+        let mut client = Client {
+            id,
+            key        : key.clone(),
+            user_key   : key,
+            listed,
+            identity,
+            name       : None,
+            account    : None,
+            channels   : BTreeSet::new(),
+            patterns   : BTreeSet::new(),
+            rate_limit : None,
+            sender
+        };
+        topics.stats().connect();
+        topics.publish(format!("{}clients/connected", SYS_PREFIX), client.user_key.clone(), addr.to_string());
+        info!(conn = id, peer:% = addr, user:% = client.user_key; "redis client connected");
+        let deliverer = {
+            let output = output.clone();
+            thread::spawn(move || Redis::deliver(&output, &receiver))
+        };
+        
+        let reason = loop {
+            let limit = settings.get().options.max_line_length.unwrap_or(MAX_COMMAND).min(MAX_COMMAND);
+            let args = match Redis::read(&mut reader, limit) {
+                Ok(Some(ref args)) if args.is_empty() => continue,
+                Ok(Some(args)) => args,
+                Ok(None)       => break "closed by client".to_string(),
+                Err(ref error) if error.kind() == ErrorKind::InvalidData => {
+                    topics.stats().parse_error();
+                    let _ = output.send(&[Reply::error(&format!("Protocol error: {}", error))]);
+                    break format!("protocol error: {}", error);
+                },
+                Err(error) => break format!("read failed: {}", error)
+            };
+            topics.stats().receive(args.iter().map(|arg| arg.len()).sum());
+            let args = match args.into_iter().map(String::from_utf8).collect::<::std::result::Result<Vec<_>, _>>() {
+                Ok(args) => args,
+                Err(_) => {
+                    if output.send(&[Reply::error("arguments must be utf-8")]).is_err() {
+                        break "write failed".to_string();
+                    } continue;
+                }
+            };
+            let config  = settings.get();
+            let options = &config.options;
+            if let (Some(users), Some((name, account))) = (options.users.as_ref(), client.account.as_ref()) {
+                if let Some(reason) = revoked_reason(users, name, account) {
+                    break reason.to_string();
+                }
+            }
+            let command = args[0].to_uppercase();
+            if command == "QUIT" {
+                let _ = output.send(&[Reply::Simple("OK")]);
+                break "quit".to_string();
+            }
+            let replies = match Redis::command(&mut client, topics, options, &command, &args[1..]) {
+                Ok(replies) => replies,
+                Err(reason) => {
+                    let _ = output.send(&[Reply::error(&reason)]);
+                    break reason;
+                }
+            };
+            if let Some(Reply::Error(ref reason)) = replies.first() {
+                debug!(conn = id, user:% = client.user_key, command:% = command, reason:% = reason; "redis command rejected");
+            }
+            if output.send(&replies).is_err() {
+                break "write failed".to_string();
+            }
+        };
+        
+        // unsubscribe, which ends the deliverer once the queue drains.
+        let _ = stream.shutdown(Shutdown::Read);
+        let (key, user_key) = (client.key.clone(), client.user_key.clone());
+        drop(client);
+        topics.delete_user_key(key);
+        let _ = deliverer.join();
+        info!(conn = id, peer:% = addr, user:% = user_key, reason:% = reason; "redis client disconnected");
+        topics.stats().disconnect();
+        topics.publish(format!("{}clients/disconnected", SYS_PREFIX), user_key, addr.to_string());
+        Ok(())
+    }
+    
+    /// runs this command, returning the replies for the client, or
+    /// the reason it is disconnected, which it is also sent.
+    fn command(client: &mut Client, topics: &Topics, options: &Options, command: &str, args: &[String]) -> ::std::result::Result<Vec<Reply>, String> {
+        let arity = |min: usize, max: usize| if args.len() < min || args.len() > max {
+            Some(vec![Reply::error(&format!("wrong number of arguments for '{}' command", command.to_lowercase()))])
+        } else { None };
+        if options.users.is_some() && client.account.is_none() && !UNAUTHENTICATED_COMMANDS.contains(&command) {
+            return Ok(vec![Reply::Error("NOAUTH Authentication required.".to_string())]);
+        }
+        if client.subscriptions() > 0 && !SUBSCRIBED_COMMANDS.contains(&command) {
+            return Ok(vec![Reply::error(&format!("Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are allowed in this context", command.to_lowercase()))]);
+        }
+        Ok(match command {
+            "PING" => {
+                if let Some(replies) = arity(0, 1) {
+                    return Ok(replies);
+                }
+                match (client.subscriptions() > 0, args.first()) {
+                    (true, message) => vec![Reply::Array(vec![Reply::bulk("pong"), Reply::bulk(message.map(|message| message.as_str()).unwrap_or(""))])],
+                    (false, Some(message)) => vec![Reply::bulk(message)],
+                    (false, None) => vec![Reply::Simple("PONG")]
+                }
+            },
+            "AUTH" => {
+                if let Some(replies) = arity(1, 2) {
+                    return Ok(replies);
+                }
+                let users = match options.users {
+                    Some(ref users) => users,
+                    None => return Ok(vec![Reply::error("authentication is not enabled")])
+                };
+                match (users.get(&args[0]), args.get(1)) {
+                    (Some(user), Some(password)) if user.verify(password) => {
+                        info!(conn = client.id, user:% = args[0], admin = user.admin; "redis client authenticated");
+                        client.rename(topics, &args[0]);
+                        client.account = Some((args[0].clone(), user.clone()));
+                        *client.identity.lock().unwrap() = client.account.clone();
+                        vec![Reply::Simple("OK")]
+                    },
+                    _ => {
+                        warn!(conn = client.id, user:% = args[0]; "redis authentication failed");
+                        vec![Reply::Error("WRONGPASS invalid username-password pair or user is disabled.".to_string())]
+                    }
+                }
+            },
+            "HELLO" => vec![Reply::Error("NOPROTO unsupported protocol version".to_string())],
+            "COMMAND" => vec![Reply::Array(Vec::new())],
+            "CLIENT" => match args.first().map(|subcommand| subcommand.to_uppercase()).as_deref() {
+                Some("SETNAME") if args.len() == 2 => {
+                    let name = &args[1];
+                    if options.users.is_some() {
+                        return Ok(vec![Reply::error("identity is set by authentication")]);
+                    }
+                    if let Some(reason) = options.check_name("name", name) {
+                        return Ok(vec![Reply::error(&reason)]);
+                    }
+                    if name.contains(' ') || is_reserved(name) {
+                        return Ok(vec![Reply::error(&format!("name {} is not allowed", name))]);
+                    }
+                    info!(conn = client.id, from:% = client.user_key, to:% = name; "identity changed");
+                    client.rename(topics, name);
+                    client.name     = Some(name.clone());
+                    vec![Reply::Simple("OK")]
+                },
+                Some("GETNAME") if args.len() == 1 => vec![Reply::Bulk(client.name.clone())],
+                Some("ID") if args.len() == 1 => vec![Reply::Integer(client.id as i64)],
+                Some("SETINFO") if args.len() == 3 => vec![Reply::Simple("OK")],
+                _ => vec![Reply::error("unknown or unsupported CLIENT subcommand")]
+            },
+            "SUBSCRIBE" | "PSUBSCRIBE" => {
+                if let Some(replies) = arity(1, usize::MAX) {
+                    return Ok(replies);
+                }
+                let pattern = command == "PSUBSCRIBE";
+                let mut replies = Vec::new();
+                for name in args {
+                    let rejected = Redis::check_channel(options, if pattern { "pattern" } else { "channel" }, name);
+                    let full = options.max_subscriptions.map(|max| client.subscriptions() >= max as i64).unwrap_or(false);
+                    let known = if pattern { client.patterns.contains(name) } else { client.channels.contains(name) };
+                    if let Some(reason) = rejected {
+                        replies.push(Reply::error(&reason));
+                        break;
+                    }
+                    if full && !known {
+                        replies.push(Reply::error(&format!("subscription limit of {} reached", options.max_subscriptions.unwrap_or(0))));
+                        break;
+                    }
+                    if !known {
+                        let tag = if pattern { Some(name.clone()) } else { None };
+                        let subscriber = Box::new(QueueSubscriber::with(client.sender.clone(), move |message| (tag.clone(), message)));
+                        if pattern {
+                            topics.psubscribe(name.clone(), client.key.clone(), subscriber);
+                            client.patterns.insert(name.clone());
+                        } else {
+                            topics.subscribe(name.clone(), client.key.clone(), subscriber);
+                            client.channels.insert(name.clone());
+                        }
+                    }
+                    let kind = if pattern { "psubscribe" } else { "subscribe" };
+                    replies.push(Reply::Array(vec![Reply::bulk(kind), Reply::bulk(name), Reply::Integer(client.subscriptions())]));
+                } replies
+            },
+            "UNSUBSCRIBE" | "PUNSUBSCRIBE" => {
+                let pattern = command == "PUNSUBSCRIBE";
+                let kind    = if pattern { "punsubscribe" } else { "unsubscribe" };
+                let names   = match (args.is_empty(), pattern) {
+                    (true, true)  => client.patterns.iter().cloned().collect(),
+                    (true, false) => client.channels.iter().cloned().collect(),
+                    (false, _)    => args.to_vec()
+                };
+                if names.is_empty() {
+                    return Ok(vec![Reply::Array(vec![Reply::bulk(kind), Reply::Bulk(None), Reply::Integer(client.subscriptions())])]);
+                }
+                names.into_iter().map(|name| {
+                    if pattern && client.patterns.remove(&name) {
+                        topics.punsubscribe(name.clone(), client.key.clone());
+                    } else if !pattern && client.channels.remove(&name) {
+                        topics.unsubscribe(name.clone(), client.key.clone());
+                    }
+                    Reply::Array(vec![Reply::bulk(kind), Reply::Bulk(Some(name)), Reply::Integer(client.subscriptions())])
+                }).collect()
+            },
+            "PUBLISH" => {
+                if let Some(replies) = arity(2, 2) {
+                    return Ok(replies);
+                }
+                let (topic_key, message) = (&args[0], &args[1]);
+                if is_reserved(topic_key) {
+                    return Ok(vec![Reply::error(&format!("topic {} is reserved", topic_key))]);
+                }
+                if let Some(reason) = Redis::check_channel(options, "channel", topic_key) {
+                    return Ok(vec![Reply::error(&reason)]);
+                }
+                if message.contains('\n') || message.contains('\r') {
+                    return Ok(vec![Reply::error("message must not contain line breaks")]);
+                }
+                let wait = topics.limiter().acquire(options, &mut client.rate_limit, &client.user_key, topic_key, message.len());
+                match (wait, options.rate_action) {
+                    (None, _) => {},
+                    (Some(wait), RateAction::Delay) => thread::sleep(wait),
+                    (Some(_), RateAction::Reject) => return Ok(vec![Reply::error(&format!("publish rate exceeded for {}", topic_key))]),
+                    (Some(_), RateAction::Disconnect) => return Err(format!("publish rate exceeded for {}", topic_key))
+                }
+                vec![Reply::Integer(topics.publish(topic_key.clone(), client.user_key.clone(), message.clone()) as i64)]
+            },
+            _ => vec![Reply::error(&format!("unknown command '{}'", command.to_lowercase()))]
+        })
+    }
+    
+    /// writes the messages received on this queue as message or
+    /// pmessage replies.
+    fn deliver(output: &Output, receiver: &Receiver<(Option<String>, Message)>) {
+        for (pattern, message) in receiver.iter() {
+            let reply = match pattern {
+                Some(pattern) => Reply::Array(vec![Reply::bulk("pmessage"), Reply::Bulk(Some(pattern)), Reply::Bulk(Some(message.topic)), Reply::Bulk(Some(message.message))]),
+                None => Reply::Array(vec![Reply::bulk("message"), Reply::Bulk(Some(message.topic)), Reply::Bulk(Some(message.message))])
+            };
+            if output.send(&[reply]).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Instant;
+    use super::super::config::Config;
+    use super::super::limiter::Rate;
+    
+    fn read(input: &[u8]) -> Result<Option<Vec<Vec<u8>>>> {
+        Redis::read(&mut &input[..], MAX_COMMAND)
+    }
+    
+    fn args(args: &[&str]) -> Option<Vec<Vec<u8>>> {
+        Some(args.iter().map(|arg| arg.as_bytes().to_vec()).collect())
+    }
+    
+    #[test]
+    fn inline_commands() {
+        assert_eq!(read(b"PING\r\n").unwrap(), args(&["PING"]));
+        assert_eq!(read(b"subscribe  news\tweather\n").unwrap(), args(&["subscribe", "news", "weather"]));
+        assert_eq!(read(b"\r\n").unwrap(), args(&[]));
+        assert_eq!(read(b"").unwrap(), None);
+        assert_eq!(read(b"PING").unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+    
+    #[test]
+    fn multibulk_commands() {
+        assert_eq!(read(b"*3\r\n$7\r\nPUBLISH\r\n$4\r\nnews\r\n$0\r\n\r\n").unwrap(), args(&["PUBLISH", "news", ""]));
+        assert_eq!(read(b"*2\r\n$4\r\nECHO\r\n$4\r\na\r\nb\r\n").unwrap(), args(&["ECHO", "a\r\nb"]));
+        assert_eq!(read(b"*0\r\n").unwrap(), args(&[]));
+        assert_eq!(read(b"*-1\r\n").unwrap(), args(&[]));
+        
+        let mut reader = &b"*1\r\n$4\r\nPING\r\nPING\r\n"[..];
+        assert_eq!(Redis::read(&mut reader, MAX_COMMAND).unwrap(), args(&["PING"]));
+        assert_eq!(Redis::read(&mut reader, MAX_COMMAND).unwrap(), args(&["PING"]));
+        assert_eq!(Redis::read(&mut reader, MAX_COMMAND).unwrap(), None);
+    }
+    
+    #[test]
+    fn malformed_multibulk() {
+        let invalid = |input: &[u8]| read(input).unwrap_err().kind();
+        assert_eq!(invalid(b"*x\r\n"), ErrorKind::InvalidData);
+        assert_eq!(invalid(format!("*{}\r\n", MAX_ARGS + 1).as_bytes()), ErrorKind::InvalidData);
+        assert_eq!(invalid(b"*1\r\n4\r\nPING\r\n"), ErrorKind::InvalidData);
+        assert_eq!(invalid(b"*1\r\n$-1\r\n"), ErrorKind::InvalidData);
+        assert_eq!(invalid(format!("*1\r\n${}\r\n", MAX_COMMAND + 1).as_bytes()), ErrorKind::InvalidData);
+        assert_eq!(invalid(b"*1\r\n$4\r\nPINGxx"), ErrorKind::InvalidData);
+        assert_eq!(invalid(b"*2\r\n$4\r\nPING\r\n"), ErrorKind::InvalidData);
+        assert_eq!(invalid(b"*1\r\n$4\r\nPI"), ErrorKind::UnexpectedEof);
+    }
+    
+    #[test]
+    fn command_size_limit() {
+        let mut reader = &b"*3\r\n$7\r\nPUBLISH\r\n$4\r\nnews\r\n$5\r\nhello\r\n"[..];
+        assert_eq!(Redis::read(&mut reader, 16).unwrap(), args(&["PUBLISH", "news", "hello"]));
+        let mut reader = &b"*3\r\n$7\r\nPUBLISH\r\n$4\r\nnews\r\n$6\r\nhello!\r\n"[..];
+        assert_eq!(Redis::read(&mut reader, 16).unwrap_err().kind(), ErrorKind::InvalidData);
+        let mut reader = &b"PUBLISH news hello!\r\n"[..];
+        assert_eq!(Redis::read(&mut reader, 16).unwrap_err().kind(), ErrorKind::InvalidData);
+        
+        // declared lengths are not allocated before the bytes arrive.
+        let mut reader = &b"*1\r\n$16000000\r\nPI"[..];
+        assert_eq!(Redis::read(&mut reader, MAX_COMMAND).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+    
+    /// starts a listener with these options.
+    fn start(options: Options) -> (Topics, Settings, RedisHandle) {
+        let topics   = Topics::new();
+        let settings = Settings::new(Config { options, ..Config::default() });
+        let handle   = Redis::start("127.0.0.1:0", topics.clone(), settings.clone()).unwrap();
+        (topics, settings, handle)
+    }
+    
+    /// connects a client to this listener.
+    fn open(handle: &RedisHandle) -> TcpStream {
+        let client = TcpStream::connect(handle.local_addr()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client
+    }
+    
+    /// sends this command as a multi bulk array.
+    fn send(client: &mut TcpStream, command: &[&str]) {
+        let mut bytes = format!("*{}\r\n", command.len()).into_bytes();
+        for arg in command {
+            bytes.extend(format!("${}\r\n{}\r\n", arg.len(), arg).into_bytes());
+        }
+        client.write_all(&bytes).unwrap();
+    }
+    
+    /// reads as many bytes as expected and compares them.
+    fn expect(client: &mut TcpStream, expected: &str) {
+        let mut bytes = vec![0; expected.len()];
+        client.read_exact(&mut bytes).unwrap();
+        assert_eq!(String::from_utf8_lossy(&bytes), expected);
+    }
+    
+    /// returns true once the server has closed this client.
+    fn closed(client: &mut TcpStream) -> bool {
+        let mut bytes = [0; 64];
+        loop {
+            match client.read(&mut bytes) {
+                Ok(0)      => return true,
+                Ok(_)      => continue,
+                Err(error) => return error.kind() != ErrorKind::WouldBlock && error.kind() != ErrorKind::TimedOut
+            }
+        }
+    }
+    
+    /// waits until this condition holds.
+    fn wait_for<F: Fn() -> bool>(condition: F) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    
+    #[test]
+    fn publish_and_subscribe() {
+        let (topics, _, handle) = start(Options::default());
+        let mut subscriber = open(&handle);
+        send(&mut subscriber, &["SUBSCRIBE", "user:1"]);
+        expect(&mut subscriber, "*3\r\n$9\r\nsubscribe\r\n$6\r\nuser:1\r\n:1\r\n");
+        send(&mut subscriber, &["PSUBSCRIBE", "user:*"]);
+        expect(&mut subscriber, "*3\r\n$10\r\npsubscribe\r\n$6\r\nuser:*\r\n:2\r\n");
+        send(&mut subscriber, &["PUBLISH", "user:1", "hello"]);
+        expect(&mut subscriber, "-ERR Can't execute 'publish': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are allowed in this context\r\n");
+        
+        // channels may hold ':', and are delivered to both the
+        // channel and the pattern subscription.
+        let mut publisher = open(&handle);
+        send(&mut publisher, &["PUBLISH", "user:1", "hello"]);
+        expect(&mut publisher, ":2\r\n");
+        expect(&mut subscriber, "*3\r\n$7\r\nmessage\r\n$6\r\nuser:1\r\n$5\r\nhello\r\n");
+        expect(&mut subscriber, "*4\r\n$8\r\npmessage\r\n$6\r\nuser:*\r\n$6\r\nuser:1\r\n$5\r\nhello\r\n");
+        let received = topics.subscribe_channel("news");
+        send(&mut publisher, &["PUBLISH", "news", "hi"]);
+        expect(&mut publisher, ":1\r\n");
+        assert_eq!(received.recv_timeout(Duration::from_secs(5)).unwrap().message, "hi");
+        handle.shutdown();
+        assert!(closed(&mut subscriber));
+    }
+    
+    #[test]
+    fn rates_and_limits() {
+        let (topics, _, handle) = start(Options {
+            connection_rate : Some(Rate { messages: Some(1), bytes: None }),
+            rate_action     : RateAction::Disconnect,
+            max_line_length : Some(16),
+            ..Options::default()
+        });
+        let mut client = open(&handle);
+        send(&mut client, &["PUBLISH", "news", "hello!"]);
+        expect(&mut client, "-ERR Protocol error: command exceeds 16 bytes\r\n");
+        assert!(closed(&mut client));
+        
+        // a publish exceeding the rate is answered before the client
+        // is disconnected.
+        let mut client = open(&handle);
+        send(&mut client, &["PUBLISH", "news", "one"]);
+        expect(&mut client, ":0\r\n");
+        send(&mut client, &["PUBLISH", "news", "two"]);
+        expect(&mut client, "-ERR publish rate exceeded for news\r\n");
+        assert!(closed(&mut client));
+        wait_for(|| topics.connections().is_empty());
+        handle.shutdown();
+    }
+    
+    #[test]
+    fn authenticate_and_revoke() {
+        let users = [("anne", "secret")].iter().map(|&(name, password)| (name.to_string(), User::new(password.to_string(), false))).collect::<HashMap<_, _>>();
+        let (topics, settings, handle) = start(Options { users: Some(users), ..Options::default() });
+        let mut client = open(&handle);
+        send(&mut client, &["SUBSCRIBE", "news"]);
+        expect(&mut client, "-NOAUTH Authentication required.\r\n");
+        send(&mut client, &["AUTH", "anne", "wrong"]);
+        expect(&mut client, "-WRONGPASS");
+        expect(&mut client, " invalid username-password pair or user is disabled.\r\n");
+        send(&mut client, &["AUTH", "anne", "secret"]);
+        expect(&mut client, "+OK\r\n");
+        wait_for(|| topics.connections().clients().iter().any(|client| client.user == "anne"));
+        
+        // clients are revoked like tcp clients once their user is
+        // removed.
+        let mut config = Config::default();
+        config.options.users = Some(HashMap::new());
+        settings.set(config, &topics).unwrap();
+        assert!(closed(&mut client));
+        wait_for(|| topics.connections().is_empty());
+        handle.shutdown();
+    }
+}
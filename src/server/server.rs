@@ -26,14 +26,85 @@
 
 use uuid::Uuid;
 
-use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
 use std::io::prelude::*;
-use std::io::{BufReader, Result};
+use std::io::{BufReader, ErrorKind, Result};
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
 use super::super::protocol::Command;
+use super::connections::{revoked_reason, ClientInfo, Connection, Connections, Identity};
+use super::http::Response;
+use super::config::Config;
+use super::limiter::RateAction;
+use super::options::Options;
+use super::settings::Settings;
+use super::subscriber::StreamSubscriber;
+use super::sys::{is_reserved, SYS_PREFIX};
 use super::topics::Topics;
+use super::users::User;
+use super::websocket::{self, FrameReader};
+use super::writer::{Framing, Writer};
+
+/// ServerHandle
+///
+/// Handle to a running server. Used to stop the server
+/// or to wait for it to finish.
+pub struct ServerHandle {
+    addr        : SocketAddr,
+    running     : Arc<AtomicBool>,
+    connections : Connections,
+    acceptor    : JoinHandle<Vec<JoinHandle<()>>>
+}
+impl ServerHandle {
+    
+    /// returns the address this server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+    
+    /// stops the server, allowing clients 5 seconds to drain.
+    pub fn shutdown(self) -> Result<()> {
+        self.shutdown_timeout(Duration::from_secs(5))
+    }
+    
+    /// stops accepting connections, sends each client a goodbye
+    /// line, closes all connections and joins the worker threads.
+    /// Writes still pending after this timeout are abandoned.
+    pub fn shutdown_timeout(self, timeout: Duration) -> Result<()> {
+        info!(addr:% = self.addr, clients = self.connections.len(); "server shutting down");
+        self.running.store(false, Ordering::SeqCst);
+        
+        wake(self.addr);
+        let workers = self.acceptor.join().unwrap();
+        self.connections.close(self.addr, "shutdown", timeout);
+        for worker in workers {
+            let _ = worker.join();
+        } Ok(())
+    }
+    
+    /// blocks until the server stops accepting connections
+    /// and every client has disconnected.
+    pub fn join(self) -> Result<()> {
+        for worker in self.acceptor.join().unwrap() {
+            let _ = worker.join();
+        } Ok(())
+    }
+}
+
+/// wakes the acceptor of this listener blocked on incoming() by
+/// connecting to it, through loopback when it listens on every
+/// address.
+pub(crate) fn wake(addr: SocketAddr) {
+    let addr = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port()),
+        IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), addr.port()),
+        _ => addr
+    };
+    let _ = TcpStream::connect(addr);
+}
 
 /// Server
 ///
@@ -41,62 +112,643 @@ use super::topics::Topics;
 pub struct Server;
 impl Server {
     
-    /// binds a pubsub server to this addr.
+    /// binds a pubsub server to this addr, blocking until it stops.
     pub fn bind<T: ToSocketAddrs>(addr: T, topics: Topics) -> Result<()> {
-        let listener = try!(TcpListener::bind(addr));
+        Server::start(addr, topics)?.join()
+    }
+    
+    /// starts a pubsub server on this addr in the background.
+    pub fn start<T: ToSocketAddrs>(addr: T, topics: Topics) -> Result<ServerHandle> {
+        Server::start_with(addr, topics, Options::default())
+    }
+    
+    /// starts a pubsub server on this addr with these options.
+    pub fn start_with<T: ToSocketAddrs>(addr: T, topics: Topics, options: Options) -> Result<ServerHandle> {
+        let config = Config { options, ..Config::default() };
+        Server::start_with_settings(addr, topics, Settings::new(config))
+    }
+    
+    /// starts a pubsub server on this addr with these live settings.
+    /// Changes to the settings apply to connected clients.
+    pub fn start_with_settings<T: ToSocketAddrs>(addr: T, topics: Topics, settings: Settings) -> Result<ServerHandle> {
+        Server::listen(addr, topics, settings, Framing::Lines)
+    }
+    
+    /// starts a pubsub server accepting websocket clients on this
+    /// addr with these live settings. Each text or binary message
+    /// carries one or more command lines, and each line sent to the
+    /// client is one text message.
+    pub fn start_websocket<T: ToSocketAddrs>(addr: T, topics: Topics, settings: Settings) -> Result<ServerHandle> {
+        Server::listen(addr, topics, settings, Framing::WebSocket)
+    }
+    
+    /// starts a server with this framing in the background.
+    fn listen<T: ToSocketAddrs>(addr: T, topics: Topics, settings: Settings, framing: Framing) -> Result<ServerHandle> {
+        let listener    = TcpListener::bind(addr)?;
+        let addr        = listener.local_addr()?;
+        let running     = Arc::new(AtomicBool::new(true));
+        let connections = topics.connections().clone();
+        let acceptor    = {
+            let running = running.clone();
+            thread::spawn(move || Server::accept(listener, addr, framing, topics, settings, running))
+        };
+        Ok(ServerHandle { addr, running, connections, acceptor })
+    }
+    
+    /// accepts connections until the server is stopped, returning
+    /// the worker threads of clients still connected. Failed accepts
+    /// are skipped so transient errors do not stop the server.
+    /// Connection limits are shared with every listener of the
+    /// topic store.
+    fn accept(listener: TcpListener, local: SocketAddr, framing: Framing, topics: Topics, settings: Settings, running: Arc<AtomicBool>) -> Vec<JoinHandle<()>> {
+        let mut workers: Vec<JoinHandle<()>> = Vec::new();
+        let connections = topics.connections().clone();
         for stream in listener.incoming() {
-            let stream  = try!(stream);
-            let topics  = topics.clone();
-            let _       = thread::spawn(move || Server::create_client(topics, stream));
-        } Ok(())
+            if !running.load(Ordering::SeqCst) {
+                break;
+            }
+            let (stream, addr) = match stream.and_then(|stream| {
+                let addr = stream.peer_addr()?;
+                Ok((stream, addr))
+            }) {
+                Ok(accepted) => accepted,
+                Err(error)   => {
+                    warn!(error:% = error; "accept failed");
+                    continue;
+                }
+            };
+            let config  = settings.get();
+            let options = &config.options;
+            let (registered, output) = match (stream.try_clone(), stream.try_clone()) {
+                (Ok(registered), Ok(output)) => (registered, output),
+                _ => continue
+            };
+            let (writer, flusher) = Writer::spawn(output, framing, options.queue_capacity, options.flush_latency, topics.stats().clone());
+            let identity = Identity::default();
+            let key      = Uuid::new_v4().to_hyphenated_string();
+            let user_key = Arc::new(Mutex::new(key.clone()));
+            let connection = Connection {
+                stream   : registered,
+                addr,
+                listener : local,
+                writer   : writer.clone(),
+                identity : identity.clone(),
+                key      : key.clone(),
+                user     : user_key.clone()
+            };
+            let id = match connections.insert(options, connection) {
+                Ok(id)      => id,
+                Err(reason) => {
+                    warn!(peer:% = addr, reason:% = reason; "connection refused");
+                    let _ = match framing {
+                        Framing::WebSocket => Response::text(503, &reason).write(&stream),
+                        _                  => Server::reply(&mut &stream, Command::Error(reason))
+                    };
+                    writer.close();
+                    continue;
+                }
+            };
+            let topics      = topics.clone();
+            let settings    = settings.clone();
+            let connections = connections.clone();
+            workers.retain(|worker| !worker.is_finished());
+            workers.push(thread::spawn(move || {
+                let _ = Server::create_client(id, topics, settings, stream, writer.clone(), identity, user_key);
+                writer.close();
+                let _ = flusher.join();
+                connections.remove(id);
+            }));
+        } workers
+    }
+    
+    /// checks a topic or user name against the name length limit.
+    fn check_name(options: &Options, name: &str) -> Option<String> {
+        match options.max_name_length {
+            Some(max) if name.len() > max => Some(format!("name exceeds {} bytes", max)),
+            _ => None
+        }
+    }
+    
+    /// checks a client command against the authenticated user,
+    /// returning the reason if the command is rejected.
+    fn check_auth(options: &Options, user: Option<&User>, command: &Command) -> Option<String> {
+        if let Command::Admin(_) = *command {
+            return match user {
+                Some(user) if user.admin => None,
+                _ => Some("admin privileges required".to_string())
+            };
+        }
+        if let (&Command::Forward(..), Some(_)) = (command, options.users.as_ref()) {
+            return match user {
+                Some(user) if user.admin => None,
+                _ => Some("admin privileges required to forward".to_string())
+            };
+        }
+        if options.users.is_none() {
+            return match *command {
+                Command::Authenticate(_, _) => Some("authentication is not enabled".to_string()),
+                _ => None
+            };
+        }
+        match *command {
+            Command::Authenticate(_, _) | Command::Ping(_) | Command::Pong(_) => None,
+            Command::Identity(_)        => Some("identity is set by authentication".to_string()),
+            _ if user.is_none()         => Some("authentication required".to_string()),
+            _ => None
+        }
+    }
+    
+    /// checks a client command against the configured limits,
+    /// returning the reason if the command is rejected.
+    fn check_command(options: &Options, topics: &Topics, key: &str, command: &Command) -> Option<String> {
+        match *command {
+            Command::Identity(ref user_key) if is_reserved(user_key) => Some(format!("user {} is reserved", user_key)),
+            Command::Identity(ref user_key) => Server::check_name(options, user_key),
+            Command::Authenticate(ref user_key, _) => Server::check_name(options, user_key),
+            Command::Subscribe(ref topic_key) => {
+                if let Some(reason) = Server::check_name(options, topic_key) {
+                    return Some(reason);
+                }
+                let max = options.max_subscriptions?;
+                let subscriptions = topics.topics_of(key);
+                if subscriptions.len() >= max && !subscriptions.contains(topic_key) {
+                    return Some(format!("subscription limit of {} reached", max));
+                } None
+            },
+            Command::Publish(ref topic_key, _) if is_reserved(topic_key) => Some(format!("topic {} is reserved", topic_key)),
+            Command::Publish(ref topic_key, _) => Server::check_name(options, topic_key),
+            Command::Forward(ref topic_key, ..) if is_reserved(topic_key) => Some(format!("topic {} is reserved", topic_key)),
+            Command::Forward(ref topic_key, _, ref user_key, _) => Server::check_name(options, topic_key).or_else(|| Server::check_name(options, user_key)),
+            _ => None
+        }
     }
     
-    /// creates a new client with this topic store and stream.
-    fn create_client(topics: Topics, stream : TcpStream) -> Result<()> {
+    ///-----------------------------------------
+    /// runs this admin action, returning the
+    /// result lines for the client, or the
+    /// reason it failed. Result lines are sent
+    /// as r: lines followed by r:end. The
+    /// following outlines the actions and
+    /// their lines.
+    ///
+    ///  reload         - reloaded[: warnings]
+    ///  topics         - <subscribers> <topic>, per topic
+    ///  clients        - <id> <addr> <user> <topics>, per client
+    ///  queue:<id>     - <id> <queue depth> <dropped packets>
+    ///  kick:<id>      - kicked <id>
+    ///
+    /// Clients are those of every listener
+    /// sharing the topic store. Topics of a
+    /// client are comma separated, or - when
+    /// it has none.
+    ///-----------------------------------------
+    fn admin(topics: &Topics, settings: &Settings, action: &str) -> ::std::result::Result<Vec<String>, String> {
+        let split = action.splitn(2, ':').collect::<Vec<_>>();
+        match (split[0], split.get(1)) {
+            ("reload", None) => match settings.reload(topics) {
+                Ok(ref warnings) if warnings.is_empty() => {
+                    info!("configuration reloaded");
+                    Ok(vec!["reloaded".to_string()])
+                },
+                Ok(warnings) => {
+                    warn!(warnings:% = warnings.join("; "); "configuration reloaded with warnings");
+                    Ok(vec![format!("reloaded: {}", warnings.join("; "))])
+                },
+                Err(error) => {
+                    error!(error:% = error; "configuration reload rejected");
+                    Err(error.to_string())
+                }
+            },
+            ("topics", None) => {
+                let mut subscriptions = topics.subscriptions();
+                subscriptions.sort();
+                Ok(subscriptions.into_iter().map(|(topic_key, count)| format!("{} {}", count, topic_key)).collect())
+            },
+            ("clients", None) => {
+                Ok(topics.connections().clients().into_iter().map(|client| {
+                    let topic_keys = topics.topics_of(&client.key);
+                    let topic_keys = if topic_keys.is_empty() { "-".to_string() } else { topic_keys.join(",") };
+                    format!("{} {} {} {}", client.id, client.addr, client.user, topic_keys)
+                }).collect())
+            },
+            ("queue", Some(id)) => {
+                let client = Server::admin_client(topics, id)?;
+                Ok(vec![format!("{} {} {}", client.id, client.depth, client.dropped)])
+            },
+            ("kick", Some(id)) => {
+                let client = Server::admin_client(topics, id)?;
+                info!(conn = client.id, peer:% = client.addr, user:% = client.user; "kicking client");
+                topics.connections().kick(client.id, "disconnected by admin", Duration::from_secs(1));
+                Ok(vec![format!("kicked {}", client.id)])
+            },
+            _ => Err(format!("unknown admin action '{}'", action))
+        }
+    }
+    
+    /// returns the client named by this admin argument.
+    fn admin_client(topics: &Topics, id: &str) -> ::std::result::Result<ClientInfo, String> {
+        id.parse().ok()
+            .and_then(|id| topics.connections().get(id))
+            .ok_or_else(|| format!("no client {}", id))
+    }
+    
+    /// writes this command to the client as a single line.
+    fn reply<W: Write>(writer: &mut W, command: Command) -> Result<()> {
+        let line = format!("{}\n", command.serialize());
+        writer.write_all(line.as_bytes())
+    }
+    
+    /// creates a new client with this topic store and stream. The
+    /// settings are read again for every line so reloaded settings
+    /// apply to this client. A client authenticated as a user that
+    /// has since been removed, or whose password has changed, is
+    /// disconnected on reload, or on its next line should it race
+    /// the reload. Subscriptions are held under a key unique to
+    /// this client, while the user_key names the user publishing.
+    /// Connects and disconnects are published to the $SYS topics.
+    /// Websocket clients are connected once their handshake is
+    /// accepted.
+    fn create_client(id: usize, topics: Topics, settings: Settings, stream : TcpStream, writer: Writer, identity: Identity, user_key: Arc<Mutex<String>>) -> Result<()> {
         
-        // initialize client state.
-        let mut reader   = BufReader::new(stream.try_clone().unwrap());
+        // initialize client state. The user_key starts out as the
+        // key unique to this client.
+        let     key      = user_key.lock().unwrap().clone();
+        let     addr     = stream.peer_addr()?;
+        let     input: Box<dyn Read> = match writer.framing() {
+            Framing::WebSocket => {
+                stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+                if let Err(error) = websocket::handshake(&stream) {
+                    debug!(conn = id, peer:% = addr, error:% = error; "websocket handshake failed");
+                    let _ = stream.shutdown(Shutdown::Both);
+                    return Err(error);
+                }
+                Box::new(FrameReader::new(stream.try_clone()?, writer.clone()))
+            },
+            _ => Box::new(stream.try_clone()?)
+        };
+        let mut reader   = BufReader::new(input);
         let mut buffer   = String::new();
-        let     user_key = Arc::new(Mutex::new(Uuid::new_v4().to_hyphenated_string()));
+        let mut missed   = 0;
+        let mut rate_limit    = None;
+        let mut ping_interval = settings.get().options.ping_interval;
+        stream.set_read_timeout(ping_interval)?;
+        topics.stats().connect();
+        topics.publish(format!("{}clients/connected", SYS_PREFIX), key.clone(), addr.to_string());
+        info!(conn = id, peer:% = addr, user:% = key; "client connected");
         
-        // read from stream.
-        while try!(reader.read_line(&mut buffer)) > 0 {
+        // read from stream, pinging the client when idle.
+        let reason = loop {
+            let config  = settings.get();
+            let options = &config.options;
+            if options.ping_interval != ping_interval {
+                ping_interval = options.ping_interval;
+                if let Err(error) = stream.set_read_timeout(ping_interval) {
+                    break format!("socket error: {}", error);
+                }
+            }
+            let line_limit = options.max_line_length.map(|max| max as u64 + 1).unwrap_or(u64::MAX);
+            let remaining  = line_limit.saturating_sub(buffer.len() as u64);
+            match (&mut reader).take(remaining).read_line(&mut buffer) {
+                Ok(0) => break "closed by client".to_string(),
+                Ok(_) if buffer.len() as u64 >= line_limit && !buffer.ends_with('\n') => {
+                    let reason = format!("line exceeds {} bytes", line_limit - 1);
+                    let _ = writer.send(Command::Error(reason.clone()));
+                    break reason;
+                },
+                Ok(_) => missed = 0,
+                Err(ref error) if error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut => {
+                    if missed >= options.ping_misses {
+                        break "heartbeat timed out".to_string();
+                    }
+                    // a client not draining its queue cannot be pinged,
+                    // so a full queue counts as a missed heartbeat.
+                    missed += 1;
+                    match writer.send(Command::Ping(missed.to_string())) {
+                        Err(ref error) if error.kind() == ErrorKind::WouldBlock => {},
+                        Err(_) => break "writer closed".to_string(),
+                        Ok(()) => {}
+                    } continue;
+                },
+                Err(error) => break format!("read failed: {}", error)
+            }
             let user_key = user_key.clone();
-            match Command::parse(&buffer) {
-                Ok(command) => match command {
-                    
-                    // update user_key with new value.
-                    Command::Identity(new_user_key) => {
-                        let mut user_key = user_key.lock().unwrap();
-                        topics.rename_user_key(user_key.clone(), new_user_key.clone());
-                        *user_key = new_user_key;
-                    },
-                    
-                    // subscribe this user to this topic.
-                    Command::Subscribe(topic_key) => {
-                        let user_key = user_key.lock().unwrap();
-                        let stream   = stream.try_clone().unwrap();
-                        topics.subscribe(topic_key, user_key.clone(), stream);
-                    },
-                    
-                    // unsubscribe this user from this topic.
-                    Command::Unsubscribe(topic_key) => {
-                        let user_key = user_key.lock().unwrap();
-                        topics.unsubscribe(topic_key, user_key.clone());                       
-                    },
-                    
-                    // publish this message to this topic.
-                    Command::Publish(topic_key, message) => {
-                        let user_key = user_key.lock().unwrap();
-                        topics.publish(topic_key, user_key.clone(), message);
+            topics.stats().receive(buffer.len());
+            let command  = match Command::parse(&buffer) {
+                Ok(command) => command,
+                Err(error)  => {
+                    topics.stats().parse_error();
+                    warn!(conn = id, peer:% = addr, error:% = error; "invalid command");
+                    buffer.clear();
+                    continue;
+                }
+            }; buffer.clear();
+            
+            // the settings may have been reloaded while reading.
+            let config  = settings.get();
+            let options = &config.options;
+            let user    = match (options.users.as_ref(), identity.lock().unwrap().as_ref()) {
+                (Some(users), Some((name, user))) => match revoked_reason(users, name, user) {
+                    Some(reason) => {
+                        let _ = writer.send(Command::Goodbye(reason.to_string()));
+                        break reason.to_string();
                     },
-                    
-                    _ => { /* do nothing */ }
-                }, Err(error) => println!("{:?}", error)
-            }; buffer.clear();  
-        }
-        let user_key = user_key.lock().unwrap();
-        topics.delete_user_key(user_key.clone());
+                    None => users.get(name)
+                },
+                _ => None
+            };
+            
+            // reject unauthenticated commands and commands
+            // exceeding the configured limits.
+            let rejected = Server::check_auth(options, user, &command)
+                .or_else(|| Server::check_command(options, &topics, &key, &command));
+            if let Some(reason) = rejected {
+                debug!(conn = id, user:% = user_key.lock().unwrap(), reason:% = reason; "command rejected");
+                if writer.send(Command::Error(reason)).is_err() {
+                    break "writer closed".to_string();
+                } continue;
+            }
+            
+            // a publish is a forward on behalf of this client
+            // along an empty route.
+            let command = match command {
+                Command::Publish(topic_key, message) => Command::Forward(topic_key, Vec::new(), user_key.lock().unwrap().clone(), message),
+                command => command
+            };
+            match command {
+                
+                // update user_key with new value.
+                Command::Identity(new_user_key) => {
+                    let mut user_key = user_key.lock().unwrap();
+                    info!(conn = id, from:% = user_key, to:% = new_user_key; "identity changed");
+                    topics.set_name(&key, &new_user_key);
+                    *user_key = new_user_key;
+                },
+                
+                // authenticate and take on this user_key.
+                Command::Authenticate(new_user_key, password) => {
+                    let user = options.users.as_ref().and_then(|users| users.get(&new_user_key));
+                    match user {
+                        Some(user) if user.verify(&password) => {
+                            info!(conn = id, user:% = new_user_key, admin = user.admin; "client authenticated");
+                            *identity.lock().unwrap() = Some((new_user_key.clone(), user.clone()));
+                            topics.set_name(&key, &new_user_key);
+                            *user_key.lock().unwrap() = new_user_key;
+                        },
+                        _ => {
+                            warn!(conn = id, peer:% = addr, user:% = new_user_key; "authentication failed");
+                            let _ = writer.send(Command::Error("authentication failed".to_string()));
+                        }
+                    }
+                },
+                
+                // subscribe this user to this topic.
+                Command::Subscribe(topic_key) => {
+                    debug!(conn = id, user:% = user_key.lock().unwrap(), topic:% = topic_key; "subscribed");
+                    topics.subscribe(topic_key, key.clone(), Box::new(StreamSubscriber::new(writer.clone())));
+                },
+                
+                // unsubscribe this user from this topic.
+                Command::Unsubscribe(topic_key) => {
+                    debug!(conn = id, user:% = user_key.lock().unwrap(), topic:% = topic_key; "unsubscribed");
+                    topics.unsubscribe(topic_key, key.clone());
+                },
+                
+                // publish this message to this topic, dropping
+                // bridged messages that already passed through
+                // this broker.
+                Command::Forward(topic_key, route, publisher, message) => {
+                    let user_key = user_key.lock().unwrap().clone();
+                    if config.name.as_ref().map(|name| route.contains(name)).unwrap_or(false) {
+                        debug!(conn = id, user:% = user_key, topic:% = topic_key, route:% = route.join(","); "bridged message looped");
+                        continue;
+                    }
+                    if let Some(wait) = topics.limiter().acquire(options, &mut rate_limit, &user_key, &topic_key, message.len()) {
+                        let reason = format!("publish rate exceeded for {}", topic_key);
+                        debug!(conn = id, user:% = user_key, topic:% = topic_key; "publish rate exceeded");
+                        match options.rate_action {
+                            RateAction::Delay      => thread::sleep(wait),
+                            RateAction::Reject     => {
+                                let _ = writer.send(Command::Error(reason));
+                                continue;
+                            },
+                            RateAction::Disconnect => {
+                                let _ = writer.send(Command::Error(reason.clone()));
+                                break reason;
+                            }
+                        }
+                    }
+                    topics.forward(topic_key, publisher, message, route);
+                },
+                
+                // run this admin action.
+                Command::Admin(action) => {
+                    info!(conn = id, user:% = user_key.lock().unwrap(), action:% = action; "admin action");
+                    let replies = match Server::admin(&topics, &settings, &action) {
+                        Ok(lines)  => lines.into_iter().map(Command::Reply).chain(Some(Command::Reply("end".to_string()))).collect(),
+                        Err(error) => vec![Command::Error(error)]
+                    };
+                    if replies.into_iter().any(|reply| writer.send(reply).is_err()) {
+                        break "writer closed".to_string();
+                    }
+                },
+                
+                // answer heartbeats from this client.
+                Command::Ping(token) => {
+                    let pong = writer.send(Command::Pong(token));
+                    if pong.is_err() {
+                        break "writer closed".to_string();
+                    }
+                },
+                
+                _ => { /* do nothing */ }
+            }
+        };
+        let user_key = user_key.lock().unwrap().clone();
+        info!(conn = id, peer:% = addr, user:% = user_key, reason:% = reason; "client disconnected");
+        topics.delete_user_key(key);
+        topics.stats().disconnect();
+        topics.publish(format!("{}clients/disconnected", SYS_PREFIX), user_key, addr.to_string());
         Ok(())
     }    
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    
+    use super::super::super::client::Client;
+    
+    /// waits until this many clients are registered with the server.
+    fn wait_for(handle: &ServerHandle, clients: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while handle.connections.len() != clients {
+            assert!(Instant::now() < deadline, "expected {} clients", clients);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    
+    #[test]
+    fn shutdown_says_goodbye() {
+        let handle = Server::start("127.0.0.1:0", Topics::new()).unwrap();
+        let addr   = handle.local_addr();
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        wait_for(&handle, 1);
+        
+        handle.shutdown_timeout(Duration::from_secs(1)).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut line   = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "g:shutdown\n");
+        line.clear();
+        assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+    
+    #[test]
+    fn heartbeat_times_out_silent_clients() {
+        let options = Options { ping_interval: Some(Duration::from_millis(50)), ping_misses: 2, ..Options::default() };
+        let handle  = Server::start_with("127.0.0.1:0", Topics::new(), options).unwrap();
+        let stream  = TcpStream::connect(handle.local_addr()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut lines  = Vec::new();
+        let mut line   = String::new();
+        while reader.read_line(&mut line).unwrap() > 0 {
+            lines.push(line.clone());
+            line.clear();
+        }
+        assert_eq!(lines, vec!["ping:1\n", "ping:2\n"]);
+        handle.shutdown().unwrap();
+    }
+    
+    #[test]
+    fn heartbeat_keeps_answering_clients() {
+        let options = Options { ping_interval: Some(Duration::from_millis(50)), ping_misses: 1, ..Options::default() };
+        let handle  = Server::start_with("127.0.0.1:0", Topics::new(), options).unwrap();
+        let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line   = String::new();
+        for _ in 0..4 {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, "ping:1\n");
+            stream.write_all(b"pong:1\n").unwrap();
+        }
+        stream.write_all(b"ping:token\n").unwrap();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            if line != "ping:1\n" {
+                break;
+            }
+            stream.write_all(b"pong:1\n").unwrap();
+        }
+        assert_eq!(line, "pong:token\n");
+        handle.shutdown().unwrap();
+    }
+    
+    #[test]
+    fn shutdown_without_clients() {
+        let handle = Server::start("127.0.0.1:0", Topics::new()).unwrap();
+        let addr   = handle.local_addr();
+        handle.shutdown().unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+    }
+    
+    #[test]
+    fn reload_disconnects_revoked_users() {
+        let users = |users: &[(&str, &str)]| {
+            let users = users.iter().map(|&(name, password)| (name.to_string(), User::new(password.to_string(), false))).collect();
+            Config { options: Options { users: Some(users), ..Options::default() }, ..Config::default() }
+        };
+        let topics   = Topics::new();
+        let settings = Settings::new(users(&[("anne", "a"), ("bill", "b"), ("carl", "c")]));
+        let handle   = Server::start_with_settings("127.0.0.1:0", topics.clone(), settings.clone()).unwrap();
+        let clients  = [("anne", "a"), ("bill", "b"), ("carl", "c")].iter().map(|&(name, password)| {
+            let mut stream = TcpStream::connect(handle.local_addr()).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            write!(stream, "a:{}:{}\nping:{}\n", name, password, name).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line   = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert_eq!(line, format!("pong:{}\n", name));
+            (stream, reader)
+        }).collect::<Vec<_>>();
+        
+        settings.set(users(&[("bill", "changed"), ("carl", "c")]), &topics).unwrap();
+        for (client, reason) in clients.into_iter().zip(&["user removed", "credentials changed", ""]) {
+            let (mut stream, mut reader) = client;
+            let mut line = String::new();
+            if reason.is_empty() {
+                writeln!(stream, "ping:kept").unwrap();
+                reader.read_line(&mut line).unwrap();
+                assert_eq!(line, "pong:kept\n");
+            } else {
+                reader.read_line(&mut line).unwrap();
+                assert_eq!(line, format!("g:{}\n", reason));
+                line.clear();
+                assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+            }
+        }
+        handle.shutdown().unwrap();
+    }
+    
+    #[test]
+    fn admin_actions_require_an_admin() {
+        let mut users = ::std::collections::HashMap::new();
+        users.insert("root".to_string(), User::new("secret".to_string(), true));
+        users.insert("dave".to_string(), User::new("pass".to_string(), false));
+        let options = Options { users: Some(users), ..Options::default() };
+        let handle  = Server::start_with("127.0.0.1:0", Topics::new(), options).unwrap();
+        let mut dave = Client::connect(handle.local_addr()).unwrap();
+        dave.authenticate("dave", "pass").unwrap();
+        assert_eq!(dave.admin("topics").unwrap_err().to_string(), "admin privileges required");
+        let mut root = Client::connect(handle.local_addr()).unwrap();
+        root.authenticate("root", "secret").unwrap();
+        assert_eq!(root.admin("topics").unwrap(), Vec::<String>::new());
+        assert_eq!(root.admin("purge:news").unwrap_err().to_string(), "unknown admin action 'purge:news'");
+        handle.shutdown().unwrap();
+    }
+    
+    #[test]
+    fn admin_lists_inspects_and_kicks_clients() {
+        let topics = Topics::new();
+        let handle = Server::start("127.0.0.1:0", topics.clone()).unwrap();
+        let admin  = |action: &str| {
+            let mut users = ::std::collections::HashMap::new();
+            users.insert("root".to_string(), User::new("secret".to_string(), true));
+            let config = Config { options: Options { users: Some(users), ..Options::default() }, ..Config::default() };
+            Server::admin(&topics, &Settings::new(config), action)
+        };
+        let mut dave = Client::connect(handle.local_addr()).unwrap();
+        dave.identity("dave").unwrap();
+        dave.subscribe("news").unwrap();
+        dave.subscribe("sport").unwrap();
+        dave.sync().unwrap();
+        let mut anon = Client::connect(handle.local_addr()).unwrap();
+        anon.sync().unwrap();
+        
+        assert_eq!(admin("topics").unwrap(), vec!["1 news".to_string(), "1 sport".to_string()]);
+        let clients = admin("clients").unwrap();
+        assert_eq!(clients.len(), 2);
+        let fields = clients.iter().map(|line| line.split(' ').collect::<Vec<_>>()).collect::<Vec<_>>();
+        assert_eq!((fields[0][2], fields[0][3]), ("dave", "news,sport"));
+        assert_eq!(fields[1][3], "-");
+        
+        let id = fields[0][0];
+        assert_eq!(admin(&format!("queue:{}", id)).unwrap(), vec![format!("{} 0 0", id)]);
+        assert_eq!(admin("queue:99").unwrap_err(), "no client 99");
+        assert_eq!(admin("queue:x").unwrap_err(), "no client x");
+        assert_eq!(admin(&format!("kick:{}", id)).unwrap(), vec![format!("kicked {}", id)]);
+        match dave.receive().unwrap() {
+            Some(Command::Goodbye(reason)) => assert_eq!(reason, "disconnected by admin"),
+            other => panic!("expected a goodbye, got {:?}", other)
+        }
+        assert!(dave.receive().unwrap().is_none());
+        wait_for(&handle, 1);
+        assert_eq!(admin("topics").unwrap(), Vec::<String>::new());
+        handle.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use std::sync::{Arc, RwLock};
+
+use super::config::{Config, ConfigError};
+use super::topics::Topics;
+
+/// Loader
+///
+/// Produces a fresh validated config, typically by reading the
+/// config file again.
+pub type Loader = dyn Fn() -> Result<Config, ConfigError> + Send + Sync;
+
+/// Settings
+///
+/// The live configuration of one or more servers. Connections read
+/// the current config for each command, so replacing it applies to
+/// every live connection at once. Queue capacity and flush latency
+/// only apply to connections accepted after the change.
+#[derive(Clone)]
+pub struct Settings {
+    config : Arc<RwLock<Arc<Config>>>,
+    loader : Option<Arc<Loader>>
+}
+impl Settings {
+    pub fn new(config: Config) -> Settings {
+        Settings {
+            config : Arc::new(RwLock::new(Arc::new(config))),
+            loader : None
+        }
+    }
+    
+    /// creates settings that can be reloaded from this loader.
+    pub fn with_loader<F>(config: Config, loader: F) -> Settings where F: Fn() -> Result<Config, ConfigError> + Send + Sync + 'static {
+        Settings {
+            config : Arc::new(RwLock::new(Arc::new(config))),
+            loader : Some(Arc::new(loader))
+        }
+    }
+    
+    /// returns the current config.
+    pub fn get(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+    
+    ///-----------------------------------------
+    /// validates and applies this config,
+    /// updating the persistent topics of this
+    /// store and the maximum log level. Listen,
+    /// metrics and gateway addresses and the
+    /// log format and output cannot change while
+    /// running; the running values are kept
+    /// and a warning is returned. An
+    /// invalid config is rejected and the
+    /// current config stays in place. Clients
+    /// of every protocol authenticated as a
+    /// user that was removed, or whose password
+    /// changed, are disconnected.
+    ///-----------------------------------------
+    pub fn set(&self, mut config: Config, topics: &Topics) -> Result<Vec<String>, ConfigError> {
+        config.validate()?;
+        let mut warnings = Vec::new();
+        let mut current  = self.config.write().unwrap();
+        if config.listen != current.listen || config.websocket != current.websocket || config.mqtt != current.mqtt || config.redis != current.redis || config.stomp != current.stomp {
+            warnings.push("listen address changes require a restart".to_string());
+            config.listen    = current.listen.clone();
+            config.websocket = current.websocket.clone();
+            config.mqtt      = current.mqtt.clone();
+            config.redis     = current.redis.clone();
+            config.stomp     = current.stomp.clone();
+        }
+        if config.metrics != current.metrics {
+            warnings.push("metrics address changes require a restart".to_string());
+            config.metrics = current.metrics.clone();
+        }
+        if config.name != current.name || config.bridges != current.bridges {
+            warnings.push("name and bridge changes require a restart".to_string());
+            config.name    = current.name.clone();
+            config.bridges = current.bridges.clone();
+        }
+        if config.gateway != current.gateway {
+            warnings.push("gateway address changes require a restart".to_string());
+            config.gateway = current.gateway.clone();
+        }
+        if config.log.format != current.log.format || config.log.output != current.log.output {
+            warnings.push("log format and output changes require a restart".to_string());
+            config.log.format = current.log.format;
+            config.log.output = current.log.output.clone();
+        }
+        if config.log.level != current.log.level {
+            log::set_max_level(config.log.level);
+        }
+        for topic_key in &current.persist {
+            if !config.persist.contains(topic_key) {
+                topics.unpersist(topic_key.clone());
+            }
+        }
+        for topic_key in &config.persist {
+            topics.persist(topic_key.clone());
+        }
+        let users = config.options.users.clone();
+        *current  = Arc::new(config);
+        drop(current);
+        topics.connections().revoke(users.as_ref());
+        Ok(warnings)
+    }
+    
+    /// reloads the config from the loader and applies it.
+    pub fn reload(&self, topics: &Topics) -> Result<Vec<String>, ConfigError> {
+        let config = match self.loader {
+            Some(ref loader) => loader()?,
+            None => return Err(ConfigError::new("no config source to reload from".to_string()))
+        };
+        self.set(config, topics)
+    }
+}
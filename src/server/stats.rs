@@ -0,0 +1,180 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// upper bounds in microseconds of the publish latency buckets.
+pub const LATENCY_BUCKETS: [u64; 12] = [10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 100000, 1000000];
+
+//------------------------------------
+// Counters
+//------------------------------------
+#[derive(Default)]
+struct Counters {
+    clients      : AtomicU64,
+    connections  : AtomicU64,
+    messages     : AtomicU64,
+    bytes        : AtomicU64,
+    delivered    : AtomicU64,
+    dropped      : AtomicU64,
+    bytes_in     : AtomicU64,
+    bytes_out    : AtomicU64,
+    queues       : Mutex<Vec<Weak<AtomicUsize>>>,
+    parse_errors : AtomicU64,
+    latency      : [AtomicU64; 13],
+    latency_sum  : AtomicU64
+}
+
+///------------------------------------
+/// Stats
+///
+/// Broker counters shared by every
+/// server using the same topic store.
+///------------------------------------
+#[derive(Clone, Default)]
+pub struct Stats {
+    counters : Arc<Counters>
+}
+impl Stats {
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+    
+    /// records a client connecting.
+    pub fn connect(&self) {
+        self.counters.clients.fetch_add(1, Ordering::Relaxed);
+        self.counters.connections.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// records a client disconnecting.
+    pub fn disconnect(&self) {
+        self.counters.clients.fetch_sub(1, Ordering::Relaxed);
+    }
+    
+    /// records a message of this many bytes being published.
+    pub fn publish(&self, bytes: usize) {
+        self.counters.messages.fetch_add(1, Ordering::Relaxed);
+        self.counters.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    
+    /// records the fan-out of a published message: how many
+    /// subscribers it was queued for and how many it was not.
+    pub fn deliver(&self, delivered: usize, dropped: usize, elapsed: Duration) {
+        self.counters.delivered.fetch_add(delivered as u64, Ordering::Relaxed);
+        self.counters.dropped.fetch_add(dropped as u64, Ordering::Relaxed);
+        let micros = elapsed.as_micros() as u64;
+        let bucket = LATENCY_BUCKETS.iter().position(|bound| micros <= *bound).unwrap_or(LATENCY_BUCKETS.len());
+        self.counters.latency[bucket].fetch_add(1, Ordering::Relaxed);
+        self.counters.latency_sum.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+    
+    /// records bytes read from a client.
+    pub fn receive(&self, bytes: usize) {
+        self.counters.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    
+    /// records bytes written to a client.
+    pub fn send(&self, bytes: usize) {
+        self.counters.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+    
+    /// tracks the depth of a client queue until it is dropped.
+    pub fn track_queue(&self, depth: &Arc<AtomicUsize>) {
+        let mut queues = self.counters.queues.lock().unwrap();
+        if queues.len() >= 1024 && queues.len().is_power_of_two() {
+            queues.retain(|depth| depth.strong_count() > 0);
+        }
+        queues.push(Arc::downgrade(depth));
+    }
+    
+    /// records a line that could not be parsed.
+    pub fn parse_error(&self) {
+        self.counters.parse_errors.fetch_add(1, Ordering::Relaxed);
+    }
+    
+    /// returns the number of connected clients.
+    pub fn clients(&self) -> u64 {
+        self.counters.clients.load(Ordering::Relaxed)
+    }
+    
+    /// returns the number of connections accepted so far.
+    pub fn connections(&self) -> u64 {
+        self.counters.connections.load(Ordering::Relaxed)
+    }
+    
+    /// returns the number of messages published so far.
+    pub fn messages(&self) -> u64 {
+        self.counters.messages.load(Ordering::Relaxed)
+    }
+    
+    /// returns the number of message bytes published so far.
+    pub fn bytes(&self) -> u64 {
+        self.counters.bytes.load(Ordering::Relaxed)
+    }
+    
+    /// returns the number of messages queued for subscribers so far.
+    pub fn delivered(&self) -> u64 {
+        self.counters.delivered.load(Ordering::Relaxed)
+    }
+    
+    /// returns the number of messages subscribers failed to take.
+    pub fn dropped(&self) -> u64 {
+        self.counters.dropped.load(Ordering::Relaxed)
+    }
+    
+    /// returns the number of bytes read from clients so far.
+    pub fn bytes_in(&self) -> u64 {
+        self.counters.bytes_in.load(Ordering::Relaxed)
+    }
+    
+    /// returns the number of bytes written to clients so far.
+    pub fn bytes_out(&self) -> u64 {
+        self.counters.bytes_out.load(Ordering::Relaxed)
+    }
+    
+    /// returns the number of frames waiting in client queues.
+    pub fn queued(&self) -> u64 {
+        let mut queues = self.counters.queues.lock().unwrap();
+        queues.retain(|depth| depth.strong_count() > 0);
+        queues.iter().filter_map(Weak::upgrade).map(|depth| depth.load(Ordering::Relaxed) as u64).sum()
+    }
+    
+    /// returns the number of lines that could not be parsed.
+    pub fn parse_errors(&self) -> u64 {
+        self.counters.parse_errors.load(Ordering::Relaxed)
+    }
+    
+    /// returns the publish latency bucket counts, one per bound in
+    /// LATENCY_BUCKETS followed by the count above the last bound,
+    /// and the sum of all latencies.
+    pub fn latency(&self) -> (Vec<u64>, Duration) {
+        let buckets = self.counters.latency.iter().map(|count| count.load(Ordering::Relaxed)).collect();
+        let sum     = Duration::from_nanos(self.counters.latency_sum.load(Ordering::Relaxed));
+        (buckets, sum)
+    }
+}
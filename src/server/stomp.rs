@@ -0,0 +1,845 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use uuid::Uuid;
+
+use std::io::prelude::*;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::super::protocol::Message;
+use super::connections::{revoked_reason, Connection, Connections, Identity};
+use super::limiter::RateAction;
+use super::options::Options;
+use super::server::wake;
+use super::settings::Settings;
+use super::subscriber::QueueSubscriber;
+use super::sys::{is_reserved, SYS_PREFIX};
+use super::topics::Topics;
+use super::users::User;
+use super::writer::{Framing, Writer};
+
+/// time allowed for the CONNECT frame after accepting.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// maximum length in bytes of a frame line or body.
+const MAX_FRAME: usize = 16 << 20;
+
+/// maximum number of headers of a frame.
+const MAX_HEADERS: usize = 128;
+
+/// protocol versions accepted, preferred last.
+const VERSIONS: [&str; 3] = ["1.0", "1.1", "1.2"];
+
+///------------------------------------
+/// Frame
+///
+/// A STOMP frame. Header names and
+/// values are held unescaped.
+///------------------------------------
+struct Frame {
+    command : String,
+    headers : Vec<(String, String)>,
+    body    : Vec<u8>
+}
+impl Frame {
+    fn new(command: &str) -> Frame {
+        Frame { command: command.to_string(), headers: Vec::new(), body: Vec::new() }
+    }
+    
+    /// adds this header.
+    fn header(mut self, name: &str, value: &str) -> Frame {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+    
+    /// returns the first value of this header, which takes
+    /// precedence over repeated ones.
+    fn get(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|header| header.0 == name).map(|header| header.1.as_str())
+    }
+    
+    /// encodes this frame, escaping headers for this version.
+    fn encode(&self, version: &str) -> Vec<u8> {
+        let escape = version != "1.0" && self.command != "CONNECTED";
+        let mut output = Vec::with_capacity(self.body.len() + 64);
+        output.extend_from_slice(self.command.as_bytes());
+        output.push(b'\n');
+        for (name, value) in &self.headers {
+            output.extend_from_slice(Frame::escape(name, escape).as_bytes());
+            output.push(b':');
+            output.extend_from_slice(Frame::escape(value, escape).as_bytes());
+            output.push(b'\n');
+        }
+        output.push(b'\n');
+        output.extend_from_slice(&self.body);
+        output.push(0);
+        output
+    }
+    
+    /// escapes the characters with meaning in a header.
+    fn escape(value: &str, escape: bool) -> String {
+        if !escape {
+            return value.to_string();
+        }
+        let mut output = String::with_capacity(value.len());
+        for c in value.chars() {
+            match c {
+                '\\' => output.push_str("\\\\"),
+                '\n' => output.push_str("\\n"),
+                '\r' => output.push_str("\\r"),
+                ':'  => output.push_str("\\c"),
+                c    => output.push(c)
+            }
+        } output
+    }
+    
+    /// reverses escape, returning None for undefined escapes.
+    fn unescape(value: &str) -> Option<String> {
+        let mut output = String::with_capacity(value.len());
+        let mut chars  = value.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                output.push(c);
+                continue;
+            }
+            match chars.next()? {
+                '\\' => output.push('\\'),
+                'n'  => output.push('\n'),
+                'r'  => output.push('\r'),
+                'c'  => output.push(':'),
+                _    => return None
+            }
+        } Some(output)
+    }
+}
+
+///------------------------------------
+/// Connect
+///
+/// A client accepted by its CONNECT
+/// frame. The user_key names it in
+/// messages and the account is the
+/// user it authenticated as.
+///------------------------------------
+struct Connect {
+    user_key   : String,
+    account    : Option<(String, User)>,
+    heart_beat : Option<Duration>,
+    timeout    : Option<Duration>
+}
+
+///------------------------------------
+/// Subscription
+///
+/// A client subscription as delivered
+/// in MESSAGE frames.
+///------------------------------------
+#[derive(Clone)]
+struct Subscription {
+    id          : String,
+    destination : String,
+    ack         : String
+}
+
+///------------------------------------
+/// Output
+///
+/// Queues frames for a client on its
+/// writer. Shared between the reading
+/// and delivering threads of a
+/// connection.
+///------------------------------------
+#[derive(Clone)]
+struct Output {
+    writer  : Writer,
+    version : Arc<Mutex<String>>
+}
+impl Output {
+    fn send(&self, frame: &Frame) -> Result<()> {
+        let frame = frame.encode(&self.version.lock().unwrap());
+        self.write(frame)
+    }
+    
+    /// queues these bytes. Like the output of native clients, they
+    /// are dropped when the queue is full.
+    fn write(&self, bytes: Vec<u8>) -> Result<()> {
+        match self.writer.send_bytes(bytes) {
+            Err(ref error) if error.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result
+        }
+    }
+    
+    /// sends an ERROR frame, after which the connection closes.
+    fn error(&self, request: Option<&Frame>, message: &str) {
+        let mut frame = Frame::new("ERROR").header("message", message).header("content-type", "text/plain");
+        if let Some(receipt) = request.and_then(|request| request.get("receipt")) {
+            frame = frame.header("receipt-id", receipt);
+        }
+        frame.body = message.as_bytes().to_vec();
+        let _ = self.send(&frame);
+    }
+}
+
+/// StompHandle
+///
+/// Handle to a running stomp listener.
+pub struct StompHandle {
+    addr        : SocketAddr,
+    running     : Arc<AtomicBool>,
+    connections : Connections,
+    acceptor    : JoinHandle<Vec<(JoinHandle<()>, TcpStream)>>
+}
+impl StompHandle {
+    
+    /// returns the address this listener is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+    
+    /// stops accepting clients, disconnects those connected,
+    /// allowing their queued output 5 seconds to drain, and joins
+    /// the worker threads. Clients still to send CONNECT, which
+    /// are not registered when refused, are disconnected too.
+    pub fn shutdown(self) {
+        self.running.store(false, Ordering::SeqCst);
+        wake(self.addr);
+        let workers = self.acceptor.join().unwrap_or_default();
+        self.connections.close(self.addr, "shutdown", Duration::from_secs(5));
+        for (worker, stream) in workers {
+            let _ = stream.shutdown(Shutdown::Both);
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Stomp
+///
+/// Serves a topic store to STOMP 1.0, 1.1 and 1.2 clients, so
+/// existing messaging clients share topics with native clients.
+/// Destinations are topic names. The following outlines how frames
+/// are mapped.
+///
+///  CONNECT / STOMP - login becomes the user named in messages, and
+///                    with passcode authenticates when users are
+///                    configured.
+///  SEND            - publishes the body like p:.
+///  SUBSCRIBE       - subscribes like s:, delivering MESSAGE frames
+///                    carrying the subscription id.
+///  UNSUBSCRIBE     - unsubscribes by subscription id.
+///  ACK / NACK      - accepted, as messages are not redelivered.
+///  DISCONNECT      - answered with its receipt before closing.
+///
+/// Any frame with a receipt header is answered with a RECEIPT.
+/// Invalid frames are answered with an ERROR frame and the
+/// connection is closed, as the protocol requires. Bodies must be
+/// single line utf-8. Transactions are not supported. Limits and
+/// rates apply as for tcp clients, and clients are listed, kicked
+/// and revoked like theirs.
+pub struct Stomp;
+impl Stomp {
+    
+    /// starts a stomp listener on this addr in the background.
+    pub fn start<T: ToSocketAddrs>(addr: T, topics: Topics, settings: Settings) -> Result<StompHandle> {
+        let listener = TcpListener::bind(addr)?;
+        let addr     = listener.local_addr()?;
+        let running  = Arc::new(AtomicBool::new(true));
+        let connections = topics.connections().clone();
+        let acceptor = {
+            let running     = running.clone();
+            let connections = connections.clone();
+            thread::spawn(move || {
+                let mut workers: Vec<(JoinHandle<()>, TcpStream)> = Vec::new();
+                for stream in listener.incoming() {
+                    if !running.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let (stream, peer) = match stream.and_then(|stream| {
+                        let peer = stream.peer_addr()?;
+                        Ok((stream, peer))
+                    }) {
+                        Ok(accepted) => accepted,
+                        Err(error)   => {
+                            warn!(error:% = error; "stomp accept failed");
+                            continue;
+                        }
+                    };
+                    let config  = settings.get();
+                    let options = &config.options;
+                    let (registered, output, closer) = match (stream.try_clone(), stream.try_clone(), stream.try_clone()) {
+                        (Ok(registered), Ok(output), Ok(closer)) => (registered, output, closer),
+                        _ => continue
+                    };
+                    
+                    // refused clients are answered once their CONNECT
+                    // frame is read, so they are given a writer too.
+                    let (writer, flusher) = Writer::spawn(output, Framing::Raw, options.queue_capacity, options.flush_latency, topics.stats().clone());
+                    let key  = Uuid::new_v4().to_hyphenated_string();
+                    let user = Arc::new(Mutex::new(key.clone()));
+                    let identity = Identity::default();
+                    let registered = connections.insert(options, Connection {
+                        stream   : registered,
+                        addr     : peer,
+                        listener : addr,
+                        writer   : writer.clone(),
+                        identity : identity.clone(),
+                        key      : key.clone(),
+                        user     : user.clone()
+                    });
+                    let (topics, settings, connections) = (topics.clone(), settings.clone(), connections.clone());
+                    workers.retain(|(worker, _)| !worker.is_finished());
+                    workers.push((thread::spawn(move || {
+                        let id = registered.as_ref().ok().cloned();
+                        if let Err(error) = Stomp::client(registered, key, user, identity, &stream, writer.clone(), &topics, &settings) {
+                            debug!(peer:% = peer, error:% = error; "stomp client failed");
+                        }
+                        writer.close();
+                        let _ = flusher.join();
+                        let _ = stream.shutdown(Shutdown::Both);
+                        if let Some(id) = id {
+                            connections.remove(id);
+                        }
+                    }), closer));
+                } workers
+            })
+        };
+        Ok(StompHandle { addr, running, connections, acceptor })
+    }
+    
+    /// reads one line without its line ending.
+    fn line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+        let mut line = String::new();
+        if reader.take(MAX_FRAME as u64).read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        if !line.ends_with('\n') {
+            return Err(Error::new(ErrorKind::InvalidData, "frame line too long or truncated"));
+        }
+        Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()))
+    }
+    
+    /// reads the next frame, skipping heart beats, returning None
+    /// once the client closes. Headers are unescaped unless escape
+    /// is false, as for CONNECT frames.
+    fn read<R: BufRead>(reader: &mut R, escape: bool) -> Result<Option<Frame>> {
+        let invalid = |reason: &str| Error::new(ErrorKind::InvalidData, reason.to_string());
+        let command = loop {
+            match Stomp::line(reader)? {
+                Some(ref line) if line.is_empty() => continue,
+                Some(line) => break line,
+                None => return Ok(None)
+            }
+        };
+        let escape = escape && command != "CONNECT" && command != "STOMP";
+        let mut frame = Frame::new(&command);
+        loop {
+            let line = Stomp::line(reader)?.ok_or_else(|| invalid("truncated frame"))?;
+            if line.is_empty() {
+                break;
+            }
+            if frame.headers.len() >= MAX_HEADERS {
+                return Err(invalid("too many headers"));
+            }
+            let (name, value) = match line.find(':') {
+                Some(index) => (&line[..index], &line[index + 1..]),
+                None => return Err(invalid("header without ':'"))
+            };
+            let header = if escape {
+                match (Frame::unescape(name), Frame::unescape(value)) {
+                    (Some(name), Some(value)) => (name, value),
+                    _ => return Err(invalid("undefined escape in header"))
+                }
+            } else {
+                (name.to_string(), value.to_string())
+            };
+            frame.headers.push(header);
+        }
+        match frame.get("content-length").map(|length| length.parse::<usize>()) {
+            Some(Ok(length)) if length <= MAX_FRAME => {
+                // read as the bytes arrive, rather than allocating the
+                // length the client declares up front.
+                let mut body = Vec::new();
+                reader.take(length as u64 + 1).read_to_end(&mut body)?;
+                if body.len() <= length {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "truncated frame body"));
+                }
+                if body.pop() != Some(0) {
+                    return Err(invalid("frame not terminated by NUL"));
+                }
+                frame.body = body;
+            },
+            Some(_) => return Err(invalid("invalid content-length")),
+            None => {
+                reader.take(MAX_FRAME as u64 + 1).read_until(0, &mut frame.body)?;
+                if frame.body.pop() != Some(0) {
+                    return Err(invalid("frame not terminated by NUL"));
+                }
+            }
+        } Ok(Some(frame))
+    }
+    
+    /// parses a heart-beat header into its two intervals.
+    fn heart_beat(frame: &Frame) -> Option<(u64, u64)> {
+        let mut parts = frame.get("heart-beat").unwrap_or("0,0").split(',').map(|part| part.trim().parse::<u64>());
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(send)), Some(Ok(receive)), None) => Some((send, receive)),
+            _ => None
+        }
+    }
+    
+    /// reads and answers the CONNECT frame, returning None if the
+    /// client was refused, as it is for this reason if one is given.
+    /// Anonymous clients are named by the key of their connection.
+    fn connect<R: BufRead>(reader: &mut R, output: &Output, options: &Options, key: &str, refused: Option<&String>, addr: SocketAddr) -> Result<Option<Connect>> {
+        let frame = match Stomp::read(reader, false)? {
+            Some(frame) => frame,
+            None => return Ok(None)
+        };
+        if frame.command != "CONNECT" && frame.command != "STOMP" {
+            output.error(Some(&frame), "expected CONNECT");
+            return Ok(None);
+        }
+        let accepted = frame.get("accept-version").unwrap_or("1.0").split(',').map(|version| version.trim()).collect::<Vec<_>>();
+        let version  = match VERSIONS.iter().rev().find(|version| accepted.contains(version)) {
+            Some(version) => *version,
+            None => {
+                output.error(Some(&frame), &format!("supported protocol versions are {}", VERSIONS.join(" ")));
+                return Ok(None);
+            }
+        };
+        *output.version.lock().unwrap() = version.to_string();
+        let (send, receive) = match Stomp::heart_beat(&frame) {
+            Some(heart_beat) => heart_beat,
+            None => {
+                output.error(Some(&frame), "invalid heart-beat header");
+                return Ok(None);
+            }
+        };
+        let login = frame.get("login").map(|login| login.to_string());
+        let (user_key, account) = match (options.users.as_ref(), login) {
+            (Some(users), Some(login)) => match (users.get(&login), frame.get("passcode")) {
+                (Some(user), Some(passcode)) if user.verify(passcode) => (login.clone(), Some((login, user.clone()))),
+                _ => {
+                    warn!(peer:% = addr, user:% = login; "stomp authentication failed");
+                    output.error(Some(&frame), "authentication failed");
+                    return Ok(None);
+                }
+            },
+            (Some(_), None) => {
+                output.error(Some(&frame), "authentication required");
+                return Ok(None);
+            },
+            (None, Some(login)) => match options.check_name("login", &login) {
+                Some(reason) => {
+                    output.error(Some(&frame), &reason);
+                    return Ok(None);
+                },
+                None if is_reserved(&login) => {
+                    output.error(Some(&frame), &format!("user {} is reserved", login));
+                    return Ok(None);
+                },
+                None => (login, None)
+            },
+            (None, None) => (key.to_string(), None)
+        };
+        if let Some(reason) = refused {
+            warn!(peer:% = addr, user:% = user_key, reason:% = reason; "stomp connection refused");
+            output.error(Some(&frame), reason);
+            return Ok(None);
+        }
+        
+        // heart beats are offered at the ping interval, each side
+        // sending at the slower of what one offers and the other
+        // wants.
+        let offered = options.ping_interval.map(|interval| interval.as_millis() as u64).unwrap_or(0);
+        let negotiate = |ours: u64, theirs: u64| if ours > 0 && theirs > 0 { Some(Duration::from_millis(ours.max(theirs))) } else { None };
+        let connected = Frame::new("CONNECTED")
+            .header("version", version)
+            .header("server", concat!("pubsub/", env!("CARGO_PKG_VERSION")))
+            .header("session", key)
+            .header("heart-beat", &format!("{},{}", offered, offered));
+        output.send(&connected)?;
+        Ok(Some(Connect { user_key, account, heart_beat: negotiate(offered, receive), timeout: negotiate(offered, send) }))
+    }
+    
+    ///-----------------------------------------
+    /// serves a client until it disconnects.
+    /// Its subscriptions are held by the key
+    /// of its connection, which is registered
+    /// unless the connection limits refused it.
+    /// The name and identity of the connection
+    /// are set once CONNECT is accepted, so it
+    /// is listed and revoked like tcp clients.
+    ///-----------------------------------------
+    #[allow(clippy::too_many_arguments)]
+    fn client(registered: ::std::result::Result<usize, String>, key: String, name: Arc<Mutex<String>>, identity: Identity, stream: &TcpStream, writer: Writer, topics: &Topics, settings: &Settings) -> Result<()> {
+        let addr   = stream.peer_addr()?;
+        let output = Output { writer, version: Arc::new(Mutex::new("1.2".to_string())) };
+        let mut reader = BufReader::new(stream.try_clone()?);
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        let Connect { user_key, account, heart_beat, timeout } = match Stomp::connect(&mut reader, &output, &settings.get().options, &key, registered.as_ref().err(), addr)? {
+            Some(connected) => connected,
+            None => return Ok(())
+        };
+        let id = match registered {
+            Ok(id)  => id,
+            Err(_)  => return Ok(())
+        };
+        
+        // clients are dropped after missing as many heart beats as
+        // native clients may miss pings.
+        let misses = settings.get().options.ping_misses.max(1);
+        stream.set_read_timeout(timeout.map(|timeout| timeout * misses))?;
+        topics.set_name(&key, &user_key);
+        *name.lock().unwrap() = user_key.clone();
+        *identity.lock().unwrap() = account.clone();
+        topics.stats().connect();
+        topics.publish(format!("{}clients/connected", SYS_PREFIX), user_key.clone(), addr.to_string());
+        info!(conn = id, peer:% = addr, user:% = user_key; "stomp client connected");
+        
+        // deliver messages from a queue shared by every subscription.
+        let subscriptions = Arc::new(Mutex::new(Vec::new()));
+        let (sender, receiver) = sync_channel(settings.get().options.queue_capacity);
+        let deliverer = {
+            let (output, subscriptions) = (output.clone(), subscriptions.clone());
+            thread::spawn(move || Stomp::deliver(&output, &receiver, &subscriptions, heart_beat))
+        };
+        let reason = Stomp::serve(&mut reader, &output, topics, settings, &key, &user_key, account.as_ref(), &sender, &subscriptions);
+        
+        // unsubscribe, which ends the deliverer once the queue drains.
+        let _ = stream.shutdown(Shutdown::Read);
+        drop(sender);
+        topics.delete_user_key(key);
+        let _ = deliverer.join();
+        info!(conn = id, peer:% = addr, user:% = user_key, reason:% = reason; "stomp client disconnected");
+        topics.stats().disconnect();
+        topics.publish(format!("{}clients/disconnected", SYS_PREFIX), user_key, addr.to_string());
+        Ok(())
+    }
+    
+    /// handles frames after CONNECT, returning why the client was
+    /// disconnected. Subscriptions are held by the connection key,
+    /// sends are named by the user key.
+    #[allow(clippy::too_many_arguments)]
+    fn serve<R: BufRead>(reader: &mut R, output: &Output, topics: &Topics, settings: &Settings, key: &str, user_key: &str, account: Option<&(String, User)>, sender: &SyncSender<Message>, subscriptions: &Mutex<Vec<Subscription>>) -> String {
+        let mut rate_limit = None;
+        let escape = output.version.lock().unwrap().as_str() != "1.0";
+        loop {
+            let frame = match Stomp::read(reader, escape) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return "closed by client".to_string(),
+                Err(ref error) if error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut => return "heart beat timed out".to_string(),
+                Err(ref error) if error.kind() == ErrorKind::InvalidData => {
+                    topics.stats().parse_error();
+                    output.error(None, &format!("malformed frame: {}", error));
+                    return format!("malformed frame: {}", error);
+                },
+                Err(error) => return format!("read failed: {}", error)
+            };
+            topics.stats().receive(frame.body.len() + frame.headers.iter().map(|header| header.0.len() + header.1.len() + 2).sum::<usize>() + frame.command.len() + 3);
+            let config  = settings.get();
+            let options = &config.options;
+            if let (Some(users), Some((name, account))) = (options.users.as_ref(), account) {
+                if let Some(reason) = revoked_reason(users, name, account) {
+                    output.error(Some(&frame), reason);
+                    return reason.to_string();
+                }
+            }
+            let rejected = match frame.command.as_str() {
+                "SEND" => {
+                    let destination = frame.get("destination").unwrap_or("");
+                    let message = String::from_utf8(frame.body.clone()).ok();
+                    let rejected = match message {
+                        _ if frame.get("transaction").is_some() => Some("transactions are not supported".to_string()),
+                        None => Some("body is not utf-8".to_string()),
+                        Some(ref message) if message.contains('\n') || message.contains('\r') => Some("body contains line breaks".to_string()),
+                        Some(ref message) if options.max_line_length.map(|max| message.len() > max).unwrap_or(false) => Some("body too long".to_string()),
+                        Some(_) if is_reserved(destination) => Some(format!("destination {} is reserved", destination)),
+                        Some(_) => options.check_name("destination", destination)
+                    };
+                    match (rejected, message) {
+                        (None, Some(message)) => {
+                            let wait = topics.limiter().acquire(options, &mut rate_limit, user_key, destination, message.len());
+                            match (wait, options.rate_action) {
+                                (None, _) => {
+                                    topics.publish(destination.to_string(), user_key.to_string(), message);
+                                },
+                                (Some(wait), RateAction::Delay) => {
+                                    thread::sleep(wait);
+                                    topics.publish(destination.to_string(), user_key.to_string(), message);
+                                },
+                                (Some(_), RateAction::Reject) => debug!(user:% = user_key, topic:% = destination; "stomp send rate exceeded"),
+                                (Some(_), RateAction::Disconnect) => {
+                                    let reason = format!("publish rate exceeded for {}", destination);
+                                    output.error(Some(&frame), &reason);
+                                    return reason;
+                                }
+                            } None
+                        },
+                        (rejected, _) => rejected
+                    }
+                },
+                "SUBSCRIBE" => {
+                    let destination = frame.get("destination").unwrap_or("");
+                    let ack = frame.get("ack").unwrap_or("auto");
+                    let mut subscriptions = subscriptions.lock().unwrap();
+                    let subscribed = topics.topics_of(key);
+                    let full = options.max_subscriptions.map(|max| subscribed.len() >= max && !subscribed.iter().any(|topic_key| topic_key == destination)).unwrap_or(false);
+                    match frame.get("id") {
+                        None => Some("SUBSCRIBE requires an id header".to_string()),
+                        Some(id) if subscriptions.iter().any(|subscription| subscription.id == id) => Some(format!("subscription {} already exists", id)),
+                        Some(_) if !["auto", "client", "client-individual"].contains(&ack) => Some(format!("invalid ack mode {}", ack)),
+                        Some(_) if full => Some(format!("subscription limit of {} reached", options.max_subscriptions.unwrap_or(0))),
+                        Some(id) => options.check_name("destination", destination).or_else(|| {
+                            subscriptions.push(Subscription { id: id.to_string(), destination: destination.to_string(), ack: ack.to_string() });
+                            topics.subscribe(destination.to_string(), key.to_string(), Box::new(QueueSubscriber::new(sender.clone())));
+                            None
+                        })
+                    }
+                },
+                "UNSUBSCRIBE" => {
+                    let mut subscriptions = subscriptions.lock().unwrap();
+                    match frame.get("id").and_then(|id| subscriptions.iter().position(|subscription| subscription.id == id)) {
+                        Some(index) => {
+                            let removed = subscriptions.remove(index);
+                            if !subscriptions.iter().any(|subscription| subscription.destination == removed.destination) {
+                                topics.unsubscribe(removed.destination, key.to_string());
+                            } None
+                        },
+                        None => Some("UNSUBSCRIBE requires the id of a subscription".to_string())
+                    }
+                },
+                "ACK" | "NACK" => None,
+                "BEGIN" | "COMMIT" | "ABORT" => Some("transactions are not supported".to_string()),
+                "DISCONNECT" => None,
+                command => Some(format!("unexpected frame {}", command))
+            };
+            if let Some(reason) = rejected {
+                output.error(Some(&frame), &reason);
+                return format!("{} refused: {}", frame.command, reason);
+            }
+            if let Some(receipt) = frame.get("receipt") {
+                if let Err(error) = output.send(&Frame::new("RECEIPT").header("receipt-id", receipt)) {
+                    return format!("write failed: {}", error);
+                }
+            }
+            if frame.command == "DISCONNECT" {
+                return "disconnected by client".to_string();
+            }
+        }
+    }
+    
+    /// writes the messages received on this queue as MESSAGE frames,
+    /// one for each subscription to their topic, sending a heart
+    /// beat when idle for this interval.
+    fn deliver(output: &Output, receiver: &Receiver<Message>, subscriptions: &Mutex<Vec<Subscription>>, heart_beat: Option<Duration>) {
+        let mut message_id: u64 = 0;
+        let version = output.version.lock().unwrap().clone();
+        loop {
+            let message = match heart_beat.map(|interval| receiver.recv_timeout(interval)) {
+                Some(Ok(message)) => message,
+                Some(Err(RecvTimeoutError::Timeout)) => {
+                    if output.write(b"\n".to_vec()).is_err() {
+                        break;
+                    } continue;
+                },
+                Some(Err(RecvTimeoutError::Disconnected)) => break,
+                None => match receiver.recv() {
+                    Ok(message) => message,
+                    Err(_) => break
+                }
+            };
+            let targets = subscriptions.lock().unwrap().iter().filter(|subscription| subscription.destination == message.topic).cloned().collect::<Vec<_>>();
+            for subscription in targets {
+                message_id += 1;
+                let mut frame = Frame::new("MESSAGE")
+                    .header("subscription", &subscription.id)
+                    .header("message-id", &message_id.to_string())
+                    .header("destination", &message.topic)
+                    .header("content-type", "text/plain;charset=utf-8")
+                    .header("content-length", &message.message.len().to_string())
+                    .header("user", &message.user);
+                if version == "1.2" && subscription.ack != "auto" {
+                    frame = frame.header("ack", &message_id.to_string());
+                }
+                frame.body = message.message.as_bytes().to_vec();
+                if output.send(&frame).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::time::Instant;
+    use super::super::config::Config;
+    
+    #[test]
+    fn escaping() {
+        let value = "a:b\\c\nd\re";
+        assert_eq!(Frame::escape(value, true), "a\\cb\\\\c\\nd\\re");
+        assert_eq!(Frame::escape(value, false), value);
+        assert_eq!(Frame::unescape(&Frame::escape(value, true)).as_deref(), Some(value));
+        assert_eq!(Frame::unescape("tab\\t"), None);
+        assert_eq!(Frame::unescape("trailing\\"), None);
+    }
+    
+    #[test]
+    fn headers_are_unescaped() {
+        let input = b"SEND\ndestination:a\\cb\nfirst:1\nfirst:2\n\nbody\0";
+        let frame = Stomp::read(&mut &input[..], true).unwrap().unwrap();
+        assert_eq!(frame.command, "SEND");
+        assert_eq!(frame.get("destination"), Some("a:b"));
+        assert_eq!(frame.get("first"), Some("1"));
+        assert_eq!(frame.body, b"body");
+        
+        let frame = Stomp::read(&mut &input[..], false).unwrap().unwrap();
+        assert_eq!(frame.get("destination"), Some("a\\cb"));
+        
+        let input = b"CONNECT\nlogin:a\\b\n\n\0";
+        let frame = Stomp::read(&mut &input[..], true).unwrap().unwrap();
+        assert_eq!(frame.get("login"), Some("a\\b"));
+        
+        let input = b"SEND\ndestination:a\\tb\n\n\0";
+        assert_eq!(Stomp::read(&mut &input[..], true).err().map(|error| error.kind()), Some(ErrorKind::InvalidData));
+    }
+    
+    #[test]
+    fn content_length() {
+        let input = b"\n\r\nSEND\r\ncontent-length:5\r\n\r\na\0b\0c\0\nSEND\n\nnext\0";
+        let mut reader = &input[..];
+        let frame = Stomp::read(&mut reader, true).unwrap().unwrap();
+        assert_eq!(frame.body, b"a\0b\0c");
+        let frame = Stomp::read(&mut reader, true).unwrap().unwrap();
+        assert_eq!(frame.body, b"next");
+        assert!(Stomp::read(&mut reader, true).unwrap().is_none());
+        
+        let invalid = |input: &[u8]| Stomp::read(&mut &input[..], true).err().map(|error| error.kind());
+        assert_eq!(invalid(b"SEND\ncontent-length:3\n\nabcd\0"), Some(ErrorKind::InvalidData));
+        assert_eq!(invalid(b"SEND\ncontent-length:x\n\nabc\0"), Some(ErrorKind::InvalidData));
+        assert_eq!(invalid(format!("SEND\ncontent-length:{}\n\n\0", MAX_FRAME + 1).as_bytes()), Some(ErrorKind::InvalidData));
+        assert_eq!(invalid(b"SEND\ncontent-length:9\n\nabc\0"), Some(ErrorKind::UnexpectedEof));
+        assert_eq!(invalid(b"SEND\n\nunterminated"), Some(ErrorKind::InvalidData));
+        assert_eq!(invalid(b"SEND\nno header\n\n\0"), Some(ErrorKind::InvalidData));
+    }
+    
+    #[test]
+    fn encoding() {
+        let frame = Frame::new("MESSAGE").header("destination", "a:b").header("user", "x\ny");
+        assert_eq!(frame.encode("1.2"), b"MESSAGE\ndestination:a\\cb\nuser:x\\ny\n\n\0".to_vec());
+        assert_eq!(frame.encode("1.0"), b"MESSAGE\ndestination:a:b\nuser:x\ny\n\n\0".to_vec());
+    }
+    
+    /// starts a listener with these options.
+    fn start(options: Options) -> (Topics, Settings, StompHandle) {
+        let topics   = Topics::new();
+        let settings = Settings::new(Config { options, ..Config::default() });
+        let handle   = Stomp::start("127.0.0.1:0", topics.clone(), settings.clone()).unwrap();
+        (topics, settings, handle)
+    }
+    
+    /// connects a client sending this CONNECT frame, returning it
+    /// and the frame it is answered with.
+    fn open(handle: &StompHandle, connect: &str) -> (BufReader<TcpStream>, Frame) {
+        let mut client = TcpStream::connect(handle.local_addr()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.write_all(connect.as_bytes()).unwrap();
+        let mut client = BufReader::new(client);
+        let frame = Stomp::read(&mut client, true).unwrap().unwrap();
+        (client, frame)
+    }
+    
+    /// returns true once the server has closed this client.
+    fn closed(client: &mut BufReader<TcpStream>) -> bool {
+        loop {
+            match Stomp::read(client, true) {
+                Ok(Some(_)) => continue,
+                Ok(None)    => return true,
+                Err(error)  => return error.kind() != ErrorKind::WouldBlock && error.kind() != ErrorKind::TimedOut
+            }
+        }
+    }
+    
+    /// waits until this condition holds.
+    fn wait_for<F: Fn() -> bool>(condition: F) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    
+    #[test]
+    fn connect_and_revoke() {
+        let users = [("anne", "secret")].iter().map(|&(name, password)| (name.to_string(), User::new(password.to_string(), false))).collect::<HashMap<_, _>>();
+        let (topics, settings, handle) = start(Options { users: Some(users), ..Options::default() });
+        let (_, frame) = open(&handle, "CONNECT\naccept-version:1.2\n\n\0");
+        assert_eq!((frame.command.as_str(), frame.get("message")), ("ERROR", Some("authentication required")));
+        let (_, frame) = open(&handle, "CONNECT\naccept-version:1.2\nlogin:anne\npasscode:wrong\n\n\0");
+        assert_eq!((frame.command.as_str(), frame.get("message")), ("ERROR", Some("authentication failed")));
+        
+        // authenticated clients are named by their login, and are
+        // revoked once it is removed.
+        let (mut client, frame) = open(&handle, "CONNECT\naccept-version:1.2\nlogin:anne\npasscode:secret\n\n\0");
+        assert_eq!(frame.command, "CONNECTED");
+        wait_for(|| topics.connections().clients().iter().any(|client| client.user == "anne"));
+        let mut config = Config::default();
+        config.options.users = Some(HashMap::new());
+        settings.set(config, &topics).unwrap();
+        assert!(closed(&mut client));
+        wait_for(|| topics.connections().is_empty());
+        handle.shutdown();
+    }
+    
+    #[test]
+    fn send_and_subscribe() {
+        let (topics, _, handle) = start(Options { max_connections: Some(1), ..Options::default() });
+        let (mut client, frame) = open(&handle, "STOMP\naccept-version:1.1,1.2\nlogin:ledger\n\n\0");
+        assert_eq!((frame.command.as_str(), frame.get("version")), ("CONNECTED", Some("1.2")));
+        let (_, frame) = open(&handle, "CONNECT\naccept-version:1.2\n\n\0");
+        assert_eq!((frame.command.as_str(), frame.get("message")), ("ERROR", Some("connection limit of 1 reached")));
+        
+        let received = topics.subscribe_channel("orders");
+        client.get_mut().write_all(b"SUBSCRIBE\nid:0\ndestination:orders\nreceipt:1\n\n\0SEND\ndestination:orders\n\nbuy\0").unwrap();
+        let frame = Stomp::read(&mut client, true).unwrap().unwrap();
+        assert_eq!((frame.command.as_str(), frame.get("receipt-id")), ("RECEIPT", Some("1")));
+        let frame = Stomp::read(&mut client, true).unwrap().unwrap();
+        assert_eq!((frame.command.as_str(), frame.get("subscription"), frame.get("user")), ("MESSAGE", Some("0"), Some("ledger")));
+        assert_eq!(frame.body, b"buy");
+        assert_eq!(received.recv_timeout(Duration::from_secs(5)).unwrap().message, "buy");
+        handle.shutdown();
+        assert!(closed(&mut client));
+    }
+}
@@ -0,0 +1,146 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use std::io::{Error, ErrorKind, Result};
+use std::sync::mpsc::{Sender, SyncSender, TrySendError};
+
+use super::super::protocol::{Message, Packet};
+use super::writer::Writer;
+
+/// Subscriber
+///
+/// A sink that receives the messages published to a topic. The
+/// topic store fans out through this trait only, so tcp streams,
+/// channels or test doubles can all be subscribed alongside each
+/// other without changes to the routing code.
+pub trait Subscriber: Send {
+    
+    /// sends this message packet to the subscriber. An error means
+    /// this packet was not delivered; the subscriber is only dropped
+    /// once it is no longer alive.
+    fn send(&mut self, packet: &Packet) -> Result<()>;
+    
+    /// returns true if this subscriber can still receive messages.
+    fn alive(&self) -> bool;
+}
+
+/// StreamSubscriber
+///
+/// Subscriber that queues packets on a connection writer. The
+/// subscriber is alive for as long as the writer is.
+pub struct StreamSubscriber {
+    writer : Writer
+}
+impl StreamSubscriber {
+    pub fn new(writer: Writer) -> StreamSubscriber {
+        StreamSubscriber {
+            writer
+        }
+    }
+}
+impl Subscriber for StreamSubscriber {
+    fn send(&mut self, packet: &Packet) -> Result<()> {
+        self.writer.send_packet(packet)
+    }
+    
+    fn alive(&self) -> bool {
+        self.writer.alive()
+    }
+}
+
+/// ChannelSubscriber
+///
+/// Subscriber that forwards messages to an in-process channel. The
+/// subscriber is considered dead once the receiving end is dropped.
+pub struct ChannelSubscriber {
+    sender : Sender<Message>,
+    alive  : bool
+}
+impl ChannelSubscriber {
+    pub fn new(sender: Sender<Message>) -> ChannelSubscriber {
+        ChannelSubscriber {
+            sender,
+            alive: true
+        }
+    }
+}
+impl Subscriber for ChannelSubscriber {
+    fn send(&mut self, packet: &Packet) -> Result<()> {
+        if self.sender.send(packet.message().clone()).is_err() {
+            self.alive = false;
+            return Err(Error::new(ErrorKind::BrokenPipe, "channel receiver dropped"));
+        } Ok(())
+    }
+    
+    fn alive(&self) -> bool {
+        self.alive
+    }
+}
+
+/// QueueSubscriber
+///
+/// Subscriber that forwards messages to a bounded in-process queue,
+/// dropping them when the queue is full so a slow consumer cannot
+/// hold up publishers. The subscriber is considered dead once the
+/// receiving end is dropped. Messages may be wrapped on the way, so
+/// a queue shared by several subscriptions can tell them apart.
+pub struct QueueSubscriber<T = Message> {
+    sender : SyncSender<T>,
+    wrap   : Box<dyn Fn(Message) -> T + Send>,
+    alive  : bool
+}
+impl QueueSubscriber {
+    pub fn new(sender: SyncSender<Message>) -> QueueSubscriber {
+        QueueSubscriber::with(sender, |message| message)
+    }
+}
+impl<T: Send> QueueSubscriber<T> {
+    
+    /// creates a subscriber queueing messages wrapped by this function.
+    pub fn with<F: Fn(Message) -> T + Send + 'static>(sender: SyncSender<T>, wrap: F) -> QueueSubscriber<T> {
+        QueueSubscriber {
+            sender,
+            wrap  : Box::new(wrap),
+            alive : true
+        }
+    }
+}
+impl<T: Send> Subscriber for QueueSubscriber<T> {
+    fn send(&mut self, packet: &Packet) -> Result<()> {
+        match self.sender.try_send((self.wrap)(packet.message().clone())) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(Error::new(ErrorKind::WouldBlock, "subscriber queue full")),
+            Err(TrySendError::Disconnected(_)) => {
+                self.alive = false;
+                Err(Error::new(ErrorKind::BrokenPipe, "queue receiver dropped"))
+            }
+        }
+    }
+    
+    fn alive(&self) -> bool {
+        self.alive
+    }
+}
@@ -0,0 +1,169 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::settings::Settings;
+use super::topics::Topics;
+
+/// prefix of the reserved topics the broker publishes to.
+pub const SYS_PREFIX: &str = "$SYS/";
+
+/// user the broker publishes its statistics as.
+pub const SYS_USER: &str = "$SYS";
+
+/// returns true if this topic or user name is reserved for the broker,
+/// being $SYS itself or under the $SYS/ prefix.
+pub fn is_reserved(name: &str) -> bool {
+    name == SYS_USER || name.starts_with(SYS_PREFIX)
+}
+
+/// Sys
+///
+/// Publishes broker statistics to the $SYS topics every
+/// Options::sys_interval. The interval is read from the live
+/// settings so a reload can enable, change or disable it. The
+/// following outlines the topics, each message being a number.
+///
+///  $SYS/clients/count      - clients currently connected.
+///  $SYS/clients/total      - connections accepted since start.
+///  $SYS/topics/count       - topics currently held.
+///  $SYS/messages/published - messages published since start.
+///  $SYS/messages/rate      - messages published per second.
+///  $SYS/bytes/published    - message bytes published since start.
+///  $SYS/bytes/rate         - message bytes published per second.
+///
+/// Events are published as they happen by the server and topic
+/// store, with the user field naming the client concerned.
+///
+///  $SYS/clients/connected         - message is the client address.
+///  $SYS/clients/disconnected      - message is the client address.
+///  $SYS/subscriptions/subscribed   - message is the topic.
+///  $SYS/subscriptions/unsubscribed - message is the topic.
+///
+/// Publishing stops when the Sys is stopped or dropped.
+pub struct Sys {
+    stop   : Sender<()>,
+    thread : JoinHandle<()>
+}
+impl Sys {
+    
+    /// starts publishing statistics of this topic store.
+    pub fn spawn(topics: Topics, settings: Settings) -> Sys {
+        let (stop, stopped) = channel();
+        let thread = thread::spawn(move || {
+            let mut last = (Instant::now(), topics.stats().messages(), topics.stats().bytes());
+            loop {
+                let interval = settings.get().options.sys_interval;
+                match stopped.recv_timeout(interval.unwrap_or(Duration::from_secs(1))) {
+                    Err(RecvTimeoutError::Timeout) if interval.is_some() => {
+                        last = Sys::publish(&topics, last);
+                    },
+                    Err(RecvTimeoutError::Timeout) => {},
+                    _ => break
+                }
+            }
+        });
+        Sys { stop, thread }
+    }
+    
+    /// stops publishing and waits for the publisher to finish.
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+    
+    /// publishes the statistics, computing rates since the last
+    /// publish. Returns the time and counters of this publish.
+    fn publish(topics: &Topics, last: (Instant, u64, u64)) -> (Instant, u64, u64) {
+        let stats    = topics.stats();
+        let now      = Instant::now();
+        let messages = stats.messages();
+        let bytes    = stats.bytes();
+        let seconds  = now.duration_since(last.0).as_secs_f64().max(0.001);
+        let values = [
+            ("clients/count",      stats.clients().to_string()),
+            ("clients/total",      stats.connections().to_string()),
+            ("topics/count",       topics.len().to_string()),
+            ("messages/published", messages.to_string()),
+            ("messages/rate",      format!("{:.1}", (messages - last.1) as f64 / seconds)),
+            ("bytes/published",    bytes.to_string()),
+            ("bytes/rate",         format!("{:.1}", (bytes - last.2) as f64 / seconds))
+        ];
+        for (name, value) in values.iter() {
+            topics.publish(format!("{}{}", SYS_PREFIX, name), SYS_USER.to_string(), value.clone());
+        }
+        (now, messages, bytes)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::config::Config;
+    use super::super::options::Options;
+    use super::super::subscriber::ChannelSubscriber;
+    
+    #[test]
+    fn reserved_names() {
+        assert!(is_reserved("$SYS"));
+        assert!(is_reserved("$SYS/clients/count"));
+        assert!(!is_reserved("$SYSTEM"));
+        assert!(!is_reserved("$SYSLOG/errors"));
+        assert!(!is_reserved("news"));
+    }
+    
+    #[test]
+    fn statistics_are_published_at_the_interval() {
+        let topics   = Topics::new();
+        let options  = Options { sys_interval: Some(Duration::from_millis(10)), ..Options::default() };
+        let settings = Settings::new(Config { options, ..Config::default() });
+        let count    = topics.subscribe_channel("$SYS/topics/count");
+        topics.publish("news".to_string(), "dave".to_string(), "hello".to_string());
+        let messages = topics.subscribe_channel("$SYS/messages/published");
+        let sys      = Sys::spawn(topics.clone(), settings);
+        let message  = count.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!((message.user.as_str(), message.message.as_str()), (SYS_USER, "2"));
+        assert_eq!(messages.recv_timeout(Duration::from_secs(5)).unwrap().message, "1");
+        sys.stop();
+    }
+    
+    #[test]
+    fn subscriptions_are_published_with_the_user_name() {
+        let topics = Topics::new();
+        let events = topics.subscribe_channel("$SYS/subscriptions/subscribed");
+        assert_eq!(events.recv().unwrap().message, "$SYS/subscriptions/subscribed");
+        let subscribe = |user_key: &str| {
+            topics.subscribe("news".to_string(), user_key.to_string(), Box::new(ChannelSubscriber::new(channel().0)));
+            events.recv_timeout(Duration::from_secs(5)).unwrap().user
+        };
+        topics.set_name("key", "dave");
+        assert_eq!(subscribe("key"), "dave");
+        topics.delete_user_key("key".to_string());
+        assert_eq!(subscribe("key"), "key");
+    }
+}
@@ -26,36 +26,74 @@
 
 
 
-use std::io::prelude::*;
-use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
+use uuid::Uuid;
 
-use super::super::protocol::Command;
+use std::slice;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Instant;
+use std::sync::mpsc::{channel, Receiver};
+use std::collections::{HashMap, HashSet};
+
+use super::super::protocol::{Message, Packet};
+use super::connections::Connections;
+use super::limiter::Limiter;
+use super::stats::Stats;
+use super::subscriber::{Subscriber, ChannelSubscriber};
+use super::sys::{is_reserved, SYS_PREFIX, SYS_USER};
+
+///------------------------------------
+/// Delivery
+///
+/// Outcome of publishing a message to
+/// the subscribers of a topic.
+///------------------------------------
+#[derive(Debug, Default)]
+pub struct Delivery {
+    pub delivered : usize,
+    pub dropped   : usize,
+    pub dead      : Vec<String>
+}
 
 ///------------------------------------
 /// Topic
 ///
-/// Manages a collection of streams
+/// Manages a collection of subscribers
 /// bound to a given topic.
 ///------------------------------------
+#[derive(Clone, Default)]
 pub struct Topic {
-    dict : Arc<Mutex<HashMap<String, TcpStream>>>    
+    dict       : Arc<Mutex<HashMap<String, Box<dyn Subscriber>>>>,
+    persistent : bool
 }
 impl Topic {
     pub fn new() -> Topic {
         Topic {
-            dict: Arc::new(Mutex::new(HashMap::new()))
+            dict       : Arc::new(Mutex::new(HashMap::new())),
+            persistent : false
         }
     }
+    
+    ///-----------------------------------------
+    /// returns true if this topic has no
+    /// subscribers.
+    ///-----------------------------------------
+    pub fn is_empty(&self) -> bool {
+        self.dict.lock().unwrap().is_empty()
+    }
+    
+    ///-----------------------------------------
+    /// returns the number of subscribers.
+    ///-----------------------------------------
+    pub fn len(&self) -> usize {
+        self.dict.lock().unwrap().len()
+    }
+    
     ///-----------------------------------------
     /// subscribes this user to this topic.
     ///----------------------------------------- 
-    pub fn subscribe(&self, user_key: String, stream: TcpStream) {
+    pub fn subscribe(&self, user_key: String, subscriber: Box<dyn Subscriber>) {
         let mut dict = self.dict.lock().unwrap();
-        if !dict.contains_key(&user_key) {
-            dict.insert(user_key, stream);
-        }
+        dict.entry(user_key).or_insert(subscriber);
     }
     
     ///-----------------------------------------
@@ -68,109 +106,666 @@ impl Topic {
         }
     }
     // -----------------------------------------
-    // publishes this message to this topic.
+    // publishes this message to this topic,
+    // dropping subscribers no longer alive.
+    // returns the delivery counts and the
+    // user_keys that were dropped.
     // ----------------------------------------- 
-    pub fn publish(&self, message: String) {
-        let dict = self.dict.lock().unwrap();
-        let mut bytes = message.into_bytes();
-        for (_, mut stream) in dict.iter() {
-            stream.write(&mut bytes).unwrap();
+    pub fn publish(&self, packet: &Packet) -> Delivery {
+        let mut dict     = self.dict.lock().unwrap();
+        let mut delivery = Delivery::default();
+        for (user_key, subscriber) in dict.iter_mut() {
+            match subscriber.send(packet) {
+                Ok(())  => delivery.delivered += 1,
+                Err(_)  => delivery.dropped   += 1
+            }
+            if !subscriber.alive() {
+                delivery.dead.push(user_key.clone());
+            }
         }
+        for user_key in &delivery.dead {
+            dict.remove(user_key);
+        } delivery
     }
-
     ///-----------------------------------------
-    /// renames this user_key to a new key.
-    ///-----------------------------------------     
-    pub fn rename_user_key(&self, old_user_key: String, new_user_key: String) {
-        let mut dict = self.dict.lock().unwrap();
-        if dict.contains_key(&old_user_key) {
-            let stream = dict.remove(&old_user_key).unwrap();
-            dict.insert(new_user_key, stream);
-        }
-    }
-    
-    ///-----------------------------------------
-    /// deletes this user_key.
+    /// deletes this user_key, returning true
+    /// if it was subscribed.
     ///----------------------------------------- 
-    pub fn delete_user_key(&self, user_key:String) {
+    pub fn delete_user_key(&self, user_key:String) -> bool {
         let mut dict = self.dict.lock().unwrap();
-        if dict.contains_key(&user_key) {
-            dict.remove(&user_key).unwrap();
-        }
+        dict.remove(&user_key).is_some()
     }
     
     
 }
 //------------------------------------
 // Store
+//
+// Topics are held in a read/write locked
+// map, each topic guarding its own
+// subscribers. The users index maps each
+// user_key to the topics it subscribes
+// to, so deleting a user only visits
+// that user's topics. User keys must be
+// unique to a client, so clients of the
+// same user do not replace each other's
+// subscriptions; the names map gives the
+// user named for a key in $SYS events.
+// Topics left without subscribers are
+// removed unless marked persistent.
+// Subscription changes are published to
+// the $SYS topics. Pattern subscriptions
+// are held apart, keyed by their glob
+// pattern, and are few enough to visit on
+// each publish.
 //------------------------------------
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Topics {
-    dict: Arc<Mutex<HashMap<String, Topic>>>    
+    dict        : Arc<RwLock<HashMap<String, Topic>>>,
+    patterns    : Arc<RwLock<HashMap<String, Topic>>>,
+    users       : Arc<Mutex<HashMap<String, HashSet<String>>>>,
+    names       : Arc<Mutex<HashMap<String, String>>>,
+    limiter     : Limiter,
+    connections : Connections,
+    stats       : Stats
 }
 impl Topics {
     pub fn new() -> Topics {
         Topics {
-            dict: Arc::new(Mutex::new(HashMap::new()))  
+            dict        : Arc::new(RwLock::new(HashMap::new())),
+            patterns    : Arc::new(RwLock::new(HashMap::new())),
+            users       : Arc::new(Mutex::new(HashMap::new())),
+            names       : Arc::new(Mutex::new(HashMap::new())),
+            limiter     : Limiter::new(),
+            connections : Connections::new(),
+            stats       : Stats::new()
+        }
+    }
+    
+    /// returns the user and topic rate limits of this store.
+    pub fn limiter(&self) -> &Limiter {
+        &self.limiter
+    }
+    
+    ///-----------------------------------------
+    /// returns the clients of every listener
+    /// serving this store, so connection
+    /// limits apply to the broker as a whole.
+    ///-----------------------------------------
+    pub fn connections(&self) -> &Connections {
+        &self.connections
+    }
+    
+    /// returns the broker counters of this store.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+    
+    ///-----------------------------------------
+    /// names the user of the client holding
+    /// this user_key, as given in $SYS events.
+    /// The name is forgotten when the user_key
+    /// is deleted.
+    ///-----------------------------------------
+    pub fn set_name(&self, user_key: &str, name: &str) {
+        let mut names = self.names.lock().unwrap();
+        if user_key == name {
+            names.remove(user_key);
+        } else {
+            names.insert(user_key.to_string(), name.to_string());
         }
     }
+    
+    /// returns the name of the user holding this user_key.
+    fn name_of(&self, user_key: &str) -> String {
+        let names = self.names.lock().unwrap();
+        names.get(user_key).cloned().unwrap_or_else(|| user_key.to_string())
+    }
+    
+    /// returns this topic if it exists.
+    fn topic(&self, topic_key: &str) -> Option<Topic> {
+        let dict = self.dict.read().unwrap();
+        dict.get(topic_key).cloned()
+    }
+    
     ///-----------------------------------------
     /// subscribes this user to this topic.
     ///----------------------------------------- 
-    pub fn subscribe(&self, topic_key: String, user_key: String, stream: TcpStream) {
-        let mut dict = self.dict.lock().unwrap();
-        if dict.contains_key(&topic_key) {
-            let topic = dict.get(&topic_key).unwrap();
-            topic.subscribe(user_key, stream);
-        } else {
-            let topic = Topic::new();
-            topic.subscribe(user_key, stream);
-            dict.insert(topic_key, topic);
+    pub fn subscribe(&self, topic_key: String, user_key: String, subscriber: Box<dyn Subscriber>) {
+        // subscribe under the map lock so the topic
+        // cannot be collected in the meantime.
+        let subscriber = {
+            let dict = self.dict.read().unwrap();
+            match dict.get(&topic_key) {
+                Some(topic) => {
+                    topic.subscribe(user_key.clone(), subscriber);
+                    None
+                },
+                None => Some(subscriber)
+            }
+        };
+        if let Some(subscriber) = subscriber {
+            let mut dict = self.dict.write().unwrap();
+            let topic    = dict.entry(topic_key.clone()).or_default();
+            topic.subscribe(user_key.clone(), subscriber);
+        }
+        {
+            let mut users = self.users.lock().unwrap();
+            users.entry(user_key.clone()).or_default().insert(topic_key.clone());
+        }
+        self.publish(format!("{}subscriptions/subscribed", SYS_PREFIX), self.name_of(&user_key), topic_key);
+    }
+    
+    ///-----------------------------------------
+    /// subscribes this user to every topic
+    /// matching this glob pattern. Patterns
+    /// support *, ?, [abc], [^abc], [a-z] and
+    /// \ escapes, and only match the reserved
+    /// $SYS topics when they start with $SYS
+    /// themselves.
+    ///-----------------------------------------
+    pub fn psubscribe(&self, pattern: String, user_key: String, subscriber: Box<dyn Subscriber>) {
+        let mut patterns = self.patterns.write().unwrap();
+        patterns.entry(pattern).or_default().subscribe(user_key, subscriber);
+    }
+    
+    ///-----------------------------------------
+    /// unsubscribes this user from this glob
+    /// pattern.
+    ///-----------------------------------------
+    pub fn punsubscribe(&self, pattern: String, user_key: String) {
+        if let Some(topic) = self.patterns.read().unwrap().get(&pattern) {
+            topic.unsubscribe(user_key);
         }
+        self.collect_patterns();
+    }
+    
+    ///-----------------------------------------
+    /// marks this topic as persistent, creating
+    /// it if needed. Persistent topics are kept
+    /// when their last subscriber leaves.
+    ///-----------------------------------------
+    pub fn persist(&self, topic_key: String) {
+        let mut dict = self.dict.write().unwrap();
+        dict.entry(topic_key).or_default().persistent = true;
+    }
+    
+    ///-----------------------------------------
+    /// clears the persistent mark from this
+    /// topic, removing it if it is empty.
+    ///-----------------------------------------
+    pub fn unpersist(&self, topic_key: String) {
+        {
+            let mut dict = self.dict.write().unwrap();
+            if let Some(topic) = dict.get_mut(&topic_key) {
+                topic.persistent = false;
+            }
+        }
+        self.collect(&topic_key);
+    }
+    
+    ///-----------------------------------------
+    /// returns the number of topics.
+    ///-----------------------------------------
+    pub fn len(&self) -> usize {
+        self.dict.read().unwrap().len()
+    }
+    
+    ///-----------------------------------------
+    /// returns the topics this user is
+    /// subscribed to.
+    ///-----------------------------------------
+    pub fn topics_of(&self, user_key: &str) -> Vec<String> {
+        let users = self.users.lock().unwrap();
+        let mut topic_keys = users.get(user_key).map(|topic_keys| topic_keys.iter().cloned().collect::<Vec<_>>()).unwrap_or_default();
+        topic_keys.sort();
+        topic_keys
+    }
+    
+    ///-----------------------------------------
+    /// returns each topic with its number of
+    /// subscribers.
+    ///-----------------------------------------
+    pub fn subscriptions(&self) -> Vec<(String, usize)> {
+        let dict = self.dict.read().unwrap();
+        dict.iter().map(|(topic_key, topic)| (topic_key.clone(), topic.len())).collect()
+    }
+    
+    ///-----------------------------------------
+    /// returns true if there are no topics.
+    ///-----------------------------------------
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    
+    ///-----------------------------------------
+    /// subscribes an in-process channel to this
+    /// topic. A dropped receiver cannot be
+    /// detected until a send fails, so the
+    /// subscription still counts until the
+    /// next publish to this topic reaps it.
+    ///-----------------------------------------
+    pub fn subscribe_channel(&self, topic_key: &str) -> Receiver<Message> {
+        let (sender, receiver) = channel();
+        let user_key = Uuid::new_v4().to_hyphenated_string();
+        self.subscribe(topic_key.to_string(), user_key, Box::new(ChannelSubscriber::new(sender)));
+        receiver
     }
     
     ///-----------------------------------------
     /// unsubscribes this user from this topic.
     ///-----------------------------------------
     pub fn unsubscribe(&self, topic_key: String, user_key: String) {
-        let dict = self.dict.lock().unwrap();
-        if dict.contains_key(&topic_key) {
-            let topic = dict.get(&topic_key).unwrap();
-            topic.unsubscribe(user_key);
+        if let Some(topic) = self.topic(&topic_key) {
+            topic.unsubscribe(user_key.clone());
         }
+        self.unindex(&topic_key, slice::from_ref(&user_key));
+        self.collect(&topic_key);
+        self.publish(format!("{}subscriptions/unsubscribed", SYS_PREFIX), self.name_of(&user_key), topic_key);
     }
     
     ///-----------------------------------------
-    /// publishes this message.
+    /// publishes this message, returning the
+    /// number of subscribers it was delivered
+    /// to, pattern subscribers included. The
+    /// message is encoded once and shared by
+    /// all subscribers. Messages to reserved
+    /// topics are not counted in the stats.
     ///----------------------------------------- 
-    pub fn publish(&self, topic_key: String, user_key: String, message: String) {
-        let dict = self.dict.lock().unwrap();
-        if dict.contains_key(&topic_key) {
-            let topic   = dict.get(&topic_key).unwrap();
-            let command = Command::Message(topic_key, user_key, message);
-            topic.publish(command.serialize());
-        }
+    pub fn publish(&self, topic_key: String, user_key: String, message: String) -> usize {
+        self.forward(topic_key, user_key, message, Vec::new())
     }
     
     ///-----------------------------------------
-    /// renames this user_key.
-    ///-----------------------------------------     
-    pub fn rename_user_key(&self, old_user_key: String, new_user_key:String) {
-        let dict = self.dict.lock().unwrap();
-        for (_, topic) in dict.iter() {
-            topic.rename_user_key(
-                old_user_key.clone(), 
-                new_user_key.clone());
+    /// publishes this message as bridged from
+    /// another broker along this route.
+    ///----------------------------------------- 
+    pub fn forward(&self, topic_key: String, user_key: String, message: String, route: Vec<String>) -> usize {
+        let start    = Instant::now();
+        let counted  = !is_reserved(&topic_key);
+        if counted {
+            self.stats.publish(message.len());
         }
-    } 
+        let topic    = self.topic(&topic_key);
+        let patterns = self.matching(&topic_key);
+        let mut delivery = Delivery::default();
+        if topic.is_some() || !patterns.is_empty() {
+            let mut message = Message::new(topic_key, user_key, message);
+            message.route   = route;
+            let packet = Packet::new(message);
+            if let Some(topic) = topic {
+                delivery = topic.publish(&packet);
+                if !delivery.dead.is_empty() {
+                    self.unindex(&packet.message().topic, &delivery.dead);
+                    self.collect(&packet.message().topic);
+                }
+            }
+            let mut dead = false;
+            for topic in patterns {
+                let matched = topic.publish(&packet);
+                delivery.delivered += matched.delivered;
+                delivery.dropped   += matched.dropped;
+                dead |= !matched.dead.is_empty();
+            }
+            if dead {
+                self.collect_patterns();
+            }
+        }
+        if counted {
+            self.stats.deliver(delivery.delivered, delivery.dropped, start.elapsed());
+        } delivery.delivered
+    }
     
     ///-----------------------------------------
     /// deletes this user_key.
     ///-----------------------------------------     
     pub fn delete_user_key(&self, user_key: String) {
-        let dict = self.dict.lock().unwrap();
-        for (_, topic) in dict.iter() {
-            topic.delete_user_key(user_key.clone());
+        let topic_keys = {
+            let mut users = self.users.lock().unwrap();
+            users.remove(&user_key).unwrap_or_default()
+        };
+        self.names.lock().unwrap().remove(&user_key);
+        for topic_key in topic_keys {
+            if let Some(topic) = self.topic(&topic_key) {
+                topic.delete_user_key(user_key.clone());
+            }
+            self.collect(&topic_key);
+        }
+        
+        // patterns are only written to when this key held one.
+        let mut held = false;
+        for topic in self.patterns.read().unwrap().values() {
+            held |= topic.delete_user_key(user_key.clone());
+        }
+        if held {
+            self.collect_patterns();
+        }
+    }
+    
+    /// returns the pattern subscriptions matching this topic.
+    fn matching(&self, topic_key: &str) -> Vec<Topic> {
+        let patterns = self.patterns.read().unwrap();
+        if patterns.is_empty() {
+            return Vec::new();
+        }
+        let reserved = is_reserved(topic_key);
+        patterns.iter()
+            .filter(|&(pattern, _)| (!reserved || pattern.starts_with(SYS_USER)) && glob(pattern.as_bytes(), topic_key.as_bytes()))
+            .map(|(_, topic)| topic.clone())
+            .collect()
+    }
+    
+    /// removes pattern subscriptions left without subscribers.
+    fn collect_patterns(&self) {
+        let mut patterns = self.patterns.write().unwrap();
+        patterns.retain(|_, topic| !topic.is_empty());
+    }
+    
+    /// removes this topic if it is empty and not persistent.
+    fn collect(&self, topic_key: &str) {
+        match self.topic(topic_key) {
+            Some(ref topic) if !topic.persistent && topic.is_empty() => {},
+            _ => return
+        }
+        let mut dict = self.dict.write().unwrap();
+        let empty    = match dict.get(topic_key) {
+            Some(topic) => !topic.persistent && topic.is_empty(),
+            None        => false
+        };
+        if empty {
+            dict.remove(topic_key);
+        }
+    }
+    
+    /// removes this topic from the index of these users.
+    fn unindex(&self, topic_key: &str, user_keys: &[String]) {
+        let mut users = self.users.lock().unwrap();
+        for user_key in user_keys {
+            let empty = match users.get_mut(user_key) {
+                Some(topic_keys) => {
+                    topic_keys.remove(topic_key);
+                    topic_keys.is_empty()
+                },
+                None => false
+            };
+            if empty {
+                users.remove(user_key);
+            }
+        }
+    }
+}
+
+/// returns true if this glob pattern matches this text.
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut pi, mut ti) = (0, 0);
+    let mut star = None;
+    while ti < text.len() {
+        let step = match pattern.get(pi) {
+            Some(&b'*') => {
+                star = Some((pi, ti));
+                pi += 1;
+                continue;
+            },
+            Some(&b'?') => Some(1),
+            Some(&b'[') => match glob_class(pattern, pi, text[ti]) {
+                Some((true, next)) => Some(next - pi),
+                Some((false, _))   => None,
+                None if text[ti] == b'[' => Some(1),
+                None => None
+            },
+            Some(&b'\\') if pi + 1 < pattern.len() => if pattern[pi + 1] == text[ti] { Some(2) } else { None },
+            Some(&byte) if byte == text[ti] => Some(1),
+            _ => None
+        };
+        match (step, star) {
+            (Some(step), _) => {
+                pi += step;
+                ti += 1;
+            },
+            (None, Some((star_pi, star_ti))) => {
+                pi   = star_pi + 1;
+                ti   = star_ti + 1;
+                star = Some((star_pi, star_ti + 1));
+            },
+            (None, None) => return false
+        }
+    }
+    pattern[pi..].iter().all(|&byte| byte == b'*')
+}
+
+/// matches this byte against the [class] starting at this index,
+/// returning whether it matched and the index after the class, or
+/// None if the class is not terminated.
+fn glob_class(pattern: &[u8], start: usize, byte: u8) -> Option<(bool, usize)> {
+    let mut index   = start + 1;
+    let negate      = pattern.get(index) == Some(&b'^');
+    let mut matched = false;
+    if negate {
+        index += 1;
+    }
+    while index < pattern.len() {
+        match pattern[index] {
+            b']' => return Some((matched != negate, index + 1)),
+            b'\\' if index + 1 < pattern.len() => {
+                matched |= pattern[index + 1] == byte;
+                index   += 2;
+            },
+            low if index + 2 < pattern.len() && pattern[index + 1] == b'-' && pattern[index + 2] != b']' => {
+                let high = pattern[index + 2];
+                matched |= low.min(high) <= byte && byte <= low.max(high);
+                index   += 3;
+            },
+            other => {
+                matched |= other == byte;
+                index   += 1;
+            }
+        }
+    } None
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Result;
+    
+    /// subscriber recording where the bytes of each packet live.
+    struct Recorder(Arc<Mutex<Vec<usize>>>);
+    impl Subscriber for Recorder {
+        fn send(&mut self, packet: &Packet) -> Result<()> {
+            self.0.lock().unwrap().push(packet.bytes().as_ptr() as usize);
+            Ok(())
+        }
+        fn alive(&self) -> bool {
+            true
         }
-    }          
-}
\ No newline at end of file
+    }
+    
+    /// returns the number of subscribers of this topic.
+    fn subscribers(topics: &Topics, topic_key: &str) -> usize {
+        topics.dict.read().unwrap().get(topic_key).map(|topic| topic.dict.lock().unwrap().len()).unwrap_or(0)
+    }
+    
+    /// subscribes a channel to this topic under this user_key.
+    fn subscribe(topics: &Topics, topic_key: &str, user_key: &str) -> Receiver<Message> {
+        let (sender, receiver) = channel();
+        topics.subscribe(topic_key.to_string(), user_key.to_string(), Box::new(ChannelSubscriber::new(sender)));
+        receiver
+    }
+    
+    /// returns the indexed topics of this user, sorted.
+    fn indexed(topics: &Topics, user_key: &str) -> Option<Vec<String>> {
+        let users = topics.users.lock().unwrap();
+        users.get(user_key).map(|topic_keys| {
+            let mut topic_keys: Vec<String> = topic_keys.iter().cloned().collect();
+            topic_keys.sort();
+            topic_keys
+        })
+    }
+    
+    #[test]
+    fn subscriptions_are_indexed_by_user() {
+        let topics = Topics::new();
+        let anne   = subscribe(&topics, "news", "anne");
+        let _anne  = subscribe(&topics, "sport", "anne");
+        let bill   = subscribe(&topics, "news", "bill");
+        assert_eq!(indexed(&topics, "anne"), Some(vec!["news".to_string(), "sport".to_string()]));
+        assert_eq!(indexed(&topics, "bill"), Some(vec!["news".to_string()]));
+        
+        topics.unsubscribe("news".to_string(), "anne".to_string());
+        assert_eq!(indexed(&topics, "anne"), Some(vec!["sport".to_string()]));
+        topics.publish("news".to_string(), "dave".to_string(), "hello".to_string());
+        assert_eq!(bill.recv().unwrap().message, "hello");
+        assert!(anne.try_recv().is_err());
+        
+        topics.unsubscribe("sport".to_string(), "anne".to_string());
+        assert_eq!(indexed(&topics, "anne"), None);
+    }
+    
+    #[test]
+    fn delete_user_key_removes_every_subscription() {
+        let topics = Topics::new();
+        let _news  = subscribe(&topics, "news", "anne");
+        let _sport = subscribe(&topics, "sport", "anne");
+        let bill   = subscribe(&topics, "news", "bill");
+        topics.delete_user_key("anne".to_string());
+        assert_eq!(indexed(&topics, "anne"), None);
+        assert_eq!(subscribers(&topics, "news"), 1);
+        assert_eq!(subscribers(&topics, "sport"), 0);
+        
+        topics.publish("news".to_string(), "dave".to_string(), "hello".to_string());
+        assert_eq!(bill.recv().unwrap().message, "hello");
+        assert_eq!(indexed(&topics, "bill"), Some(vec!["news".to_string()]));
+    }
+    
+    #[test]
+    fn channel_subscribers() {
+        let topics   = Topics::new();
+        let receiver = topics.subscribe_channel("news");
+        topics.publish("news".to_string(), "dave".to_string(), "hello".to_string());
+        topics.publish("sport".to_string(), "dave".to_string(), "ignored".to_string());
+        assert_eq!(receiver.recv().unwrap(), Message::new("news".to_string(), "dave".to_string(), "hello".to_string()));
+        assert!(receiver.try_recv().is_err());
+    }
+    
+    #[test]
+    fn dropped_channels_are_reaped_on_publish() {
+        let topics = Topics::new();
+        let kept   = topics.subscribe_channel("news");
+        drop(topics.subscribe_channel("news"));
+        assert_eq!(subscribers(&topics, "news"), 2);
+        topics.publish("news".to_string(), "dave".to_string(), "hello".to_string());
+        assert_eq!(subscribers(&topics, "news"), 1);
+        assert_eq!(kept.recv().unwrap().message, "hello");
+    }
+    
+    #[test]
+    fn empty_topics_are_collected() {
+        let topics = Topics::new();
+        let _anne  = subscribe(&topics, "news", "anne");
+        let _bill  = subscribe(&topics, "sport", "bill");
+        assert_eq!(topics.len(), 2);
+        topics.unsubscribe("news".to_string(), "anne".to_string());
+        assert_eq!(topics.len(), 1);
+        topics.delete_user_key("bill".to_string());
+        assert!(topics.is_empty());
+        
+        drop(subscribe(&topics, "news", "anne"));
+        topics.publish("news".to_string(), "dave".to_string(), "hello".to_string());
+        assert!(topics.is_empty());
+    }
+    
+    #[test]
+    fn persistent_topics_are_kept() {
+        let topics = Topics::new();
+        topics.persist("news".to_string());
+        assert_eq!(topics.len(), 1);
+        let _anne = subscribe(&topics, "news", "anne");
+        topics.unsubscribe("news".to_string(), "anne".to_string());
+        assert_eq!(topics.len(), 1);
+        
+        let _anne = subscribe(&topics, "news", "anne");
+        topics.persist("news".to_string());
+        topics.delete_user_key("anne".to_string());
+        assert_eq!(topics.len(), 1);
+        assert_eq!(subscribers(&topics, "news"), 0);
+    }
+    
+    #[test]
+    fn packets_are_shared_by_subscribers() {
+        let topics    = Topics::new();
+        let addresses = Arc::new(Mutex::new(Vec::new()));
+        for user_key in &["anne", "bill", "carl"] {
+            topics.subscribe("news".to_string(), user_key.to_string(), Box::new(Recorder(addresses.clone())));
+        }
+        topics.publish("news".to_string(), "dave".to_string(), "hello".to_string());
+        let addresses = addresses.lock().unwrap();
+        assert_eq!(addresses.len(), 3);
+        assert!(addresses.iter().all(|address| *address == addresses[0]));
+    }
+    
+    fn matches(pattern: &str, text: &str) -> bool {
+        glob(pattern.as_bytes(), text.as_bytes())
+    }
+    
+    #[test]
+    fn wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "news"));
+        assert!(matches("news/*", "news/"));
+        assert!(matches("news/*", "news/sport/today"));
+        assert!(matches("*/today", "news/sport/today"));
+        assert!(matches("a*b*c", "axxbyybc"));
+        assert!(!matches("a*b*c", "axxbyyb"));
+        assert!(!matches("news/*", "news"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(matches("??", "ab"));
+        assert!(!matches("??", "abc"));
+    }
+    
+    #[test]
+    fn classes() {
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("sensor[0-9]", "sensor7"));
+        assert!(matches("sensor[9-0]", "sensor7"));
+        assert!(!matches("sensor[0-9]", "sensorx"));
+        assert!(matches("[^a-z]", "7"));
+        assert!(matches("[^a-z]", "A"));
+        assert!(!matches("[^a-z]", "q"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("[\\]]", "]"));
+        assert!(matches("*[^/]", "news/a"));
+        assert!(!matches("*[^/]", "news/"));
+    }
+    
+    #[test]
+    fn escapes() {
+        assert!(matches("a\\*", "a*"));
+        assert!(!matches("a\\*", "ab"));
+        assert!(matches("\\?", "?"));
+        assert!(!matches("\\?", "x"));
+        assert!(matches("a\\", "a\\"));
+        assert!(!matches("a\\", "a"));
+    }
+    
+    #[test]
+    fn unterminated_class() {
+        assert!(matches("[ab", "[ab"));
+        assert!(!matches("[ab", "a"));
+        assert!(matches("x[*", "x[yz"));
+        assert!(!matches("[^", "a"));
+    }
+    
+    #[test]
+    fn reserved_topics_match_only_sys_patterns() {
+        let topics = Topics::new();
+        for pattern in ["*", "$SYS/*", "$SYS*", "$SYS/clients/?ount"].iter() {
+            topics.psubscribe(pattern.to_string(), "key".to_string(), Box::new(ChannelSubscriber::new(channel().0)));
+        }
+        assert_eq!(topics.matching("news").len(), 1);
+        assert_eq!(topics.matching("$SYS").len(), 1);
+        assert_eq!(topics.matching("$SYS/clients/count").len(), 3);
+        assert_eq!(topics.matching("$SYS/topics/count").len(), 2);
+        assert_eq!(topics.matching("$SYSTEM").len(), 2);
+    }
+}
@@ -0,0 +1,54 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+/// User
+///
+/// Credentials and role of a user allowed to authenticate with
+/// the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub password : String,
+    pub admin    : bool
+}
+impl User {
+    pub fn new(password: String, admin: bool) -> User {
+        User {
+            password,
+            admin
+        }
+    }
+    
+    /// returns true if this password matches, comparing in
+    /// constant time.
+    pub fn verify(&self, password: &str) -> bool {
+        let expected = self.password.as_bytes();
+        let given    = password.as_bytes();
+        let mut diff = expected.len() ^ given.len();
+        for (index, byte) in expected.iter().enumerate() {
+            diff |= (byte ^ given.get(index).cloned().unwrap_or(0)) as usize;
+        } diff == 0
+    }
+}
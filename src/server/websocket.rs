@@ -0,0 +1,432 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind, Result};
+use std::net::TcpStream;
+
+use super::http::{base64_encode, Request, Response};
+use super::writer::Writer;
+
+/// guid appended to the client key when accepting a handshake.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// maximum length in bytes of a message, across its fragments.
+const MAX_MESSAGE: usize = 16 << 20;
+
+/// reads the opening handshake from this stream and accepts it,
+/// answering requests that are not websocket upgrades with an
+/// error response.
+pub fn handshake(stream: &TcpStream) -> Result<()> {
+    let request = match Request::read(stream) {
+        Ok(request)   => request,
+        Err(response) => {
+            response.write(stream)?;
+            return Err(Error::new(ErrorKind::InvalidData, "malformed handshake"));
+        }
+    };
+    let upgrade = request.header("upgrade").map(|value| value.eq_ignore_ascii_case("websocket")).unwrap_or(false);
+    let connection = request.header("connection")
+        .map(|value| value.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade")))
+        .unwrap_or(false);
+    let key = match request.header("sec-websocket-key") {
+        Some(key) if request.method == "GET" && upgrade && connection => key,
+        _ => {
+            Response::text(400, "expected a websocket upgrade").write(stream)?;
+            return Err(Error::new(ErrorKind::InvalidData, "not a websocket upgrade"));
+        }
+    };
+    if request.header("sec-websocket-version") != Some("13") {
+        Response::text(426, "websocket version 13 required").header("Sec-WebSocket-Version", "13".to_string()).write(stream)?;
+        return Err(Error::new(ErrorKind::InvalidData, "unsupported websocket version"));
+    }
+    let accept = base64_encode(&sha1(format!("{}{}", key, GUID).as_bytes()));
+    let head   = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept);
+    (&mut &*stream).write_all(head.as_bytes())
+}
+
+/// FrameReader
+///
+/// Reads the messages of a websocket client as a stream of lines.
+/// Text and binary messages alike end with a newline unless they
+/// already do, so each message is one or more command lines. Pings
+/// are answered through the writer and a close frame ends the
+/// stream. Partial frames are kept across read errors so a read
+/// timeout does not lose data.
+pub struct FrameReader<R> {
+    inner   : R,
+    writer  : Writer,
+    input   : Vec<u8>,
+    message : Vec<u8>,
+    output  : Vec<u8>,
+    offset  : usize,
+    closed  : bool
+}
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R, writer: Writer) -> FrameReader<R> {
+        FrameReader {
+            inner,
+            writer,
+            input   : Vec::new(),
+            message : Vec::new(),
+            output  : Vec::new(),
+            offset  : 0,
+            closed  : false
+        }
+    }
+    
+    /// parses the next complete frame held in the input, returning
+    /// its fin flag, opcode and unmasked payload.
+    fn frame(&mut self) -> Result<Option<(bool, u8, Vec<u8>)>> {
+        if self.input.len() < 2 {
+            return Ok(None);
+        }
+        let fin    = self.input[0] & 0x80 != 0;
+        let opcode = self.input[0] & 0x0F;
+        if self.input[1] & 0x80 == 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "client frames must be masked"));
+        }
+        let (length, start) = match self.input[1] & 0x7F {
+            126 if self.input.len() >= 4  => (u16::from_be_bytes([self.input[2], self.input[3]]) as u64, 4),
+            127 if self.input.len() >= 10 => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&self.input[2..10]);
+                (u64::from_be_bytes(bytes), 10)
+            },
+            126 | 127 => return Ok(None),
+            length    => (length as u64, 2)
+        };
+        if length > MAX_MESSAGE as u64 {
+            return Err(Error::new(ErrorKind::InvalidData, format!("frame exceeds {} bytes", MAX_MESSAGE)));
+        }
+        let end = start + 4 + length as usize;
+        if self.input.len() < end {
+            return Ok(None);
+        }
+        let mask    = [self.input[start], self.input[start + 1], self.input[start + 2], self.input[start + 3]];
+        let payload = self.input[start + 4..end].iter().enumerate().map(|(index, byte)| byte ^ mask[index % 4]).collect();
+        self.input.drain(..end);
+        Ok(Some((fin, opcode, payload)))
+    }
+    
+    /// reads frames until a message is complete or the client closes,
+    /// moving the message to the output.
+    fn fill(&mut self) -> Result<()> {
+        while !self.closed {
+            let (fin, opcode, payload) = match self.frame()? {
+                Some(frame) => frame,
+                None => {
+                    let mut buffer = [0; 4096];
+                    match self.inner.read(&mut buffer)? {
+                        0 => self.closed = true,
+                        read => self.input.extend_from_slice(&buffer[..read])
+                    } continue;
+                }
+            };
+            match opcode {
+                0x0..=0x2 => {
+                    if self.message.len() + payload.len() > MAX_MESSAGE {
+                        return Err(Error::new(ErrorKind::InvalidData, format!("message exceeds {} bytes", MAX_MESSAGE)));
+                    }
+                    self.message.extend_from_slice(&payload);
+                    if fin {
+                        if !self.message.ends_with(b"\n") {
+                            self.message.push(b'\n');
+                        }
+                        self.output = ::std::mem::take(&mut self.message);
+                        self.offset = 0;
+                        return Ok(());
+                    }
+                },
+                0x8 => self.closed = true,
+                0x9 => self.writer.pong(payload)?,
+                0xA => {},
+                _   => return Err(Error::new(ErrorKind::InvalidData, format!("unknown opcode {}", opcode)))
+            }
+        } Ok(())
+    }
+}
+impl<R: Read> Read for FrameReader<R> {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        if self.offset >= self.output.len() {
+            self.fill()?;
+            if self.offset >= self.output.len() {
+                return Ok(0);
+            }
+        }
+        let count = buffer.len().min(self.output.len() - self.offset);
+        buffer[..count].copy_from_slice(&self.output[self.offset..self.offset + count]);
+        self.offset += count;
+        Ok(count)
+    }
+}
+
+/// returns the sha-1 digest of these bytes.
+fn sha1(bytes: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+    let mut padded = bytes.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((bytes.len() as u64) * 8).to_be_bytes());
+    for block in padded.chunks(64) {
+        let mut words = [0u32; 80];
+        for index in 0..16 {
+            words[index] = u32::from_be_bytes([block[index * 4], block[index * 4 + 1], block[index * 4 + 2], block[index * 4 + 3]]);
+        }
+        for index in 16..80 {
+            words[index] = (words[index - 3] ^ words[index - 8] ^ words[index - 14] ^ words[index - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (index, word) in words.iter().enumerate() {
+            let (f, k) = match index {
+                0..=19  => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _       => (b ^ c ^ d, 0xCA62C1D6)
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (value, add) in state.iter_mut().zip([a, b, c, d, e].iter()) {
+            *value = value.wrapping_add(*add);
+        }
+    }
+    let mut digest = [0; 20];
+    for (index, value) in state.iter().enumerate() {
+        digest[index * 4..index * 4 + 4].copy_from_slice(&value.to_be_bytes());
+    } digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use super::super::config::Config;
+    use super::super::server::Server;
+    use super::super::settings::Settings;
+    use super::super::stats::Stats;
+    use super::super::topics::Topics;
+    use super::super::writer::Framing;
+    
+    /// a reader handing out one byte per read, as a slow client would.
+    struct Trickle(Vec<u8>, usize);
+    impl Read for Trickle {
+        fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+            if self.1 >= self.0.len() || buffer.is_empty() {
+                return Ok(0);
+            }
+            buffer[0] = self.0[self.1];
+            self.1 += 1;
+            Ok(1)
+        }
+    }
+    
+    /// returns a masked client frame.
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask  = [0x12, 0x34, 0x56, 0x78];
+        let mut bytes = vec![if fin { 0x80 | opcode } else { opcode }];
+        match payload.len() {
+            length if length < 126    => bytes.push(0x80 | length as u8),
+            length if length < 65536  => {
+                bytes.push(0x80 | 126);
+                bytes.extend_from_slice(&(length as u16).to_be_bytes());
+            },
+            length => {
+                bytes.push(0x80 | 127);
+                bytes.extend_from_slice(&(length as u64).to_be_bytes());
+            }
+        }
+        bytes.extend_from_slice(&mask);
+        bytes.extend(payload.iter().enumerate().map(|(index, byte)| byte ^ mask[index % 4]));
+        bytes
+    }
+    
+    /// returns a writer over a loopback connection and its client end.
+    fn writer() -> (Writer, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client   = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let (writer, _) = Writer::spawn(stream, Framing::WebSocket, 16, None, Stats::new());
+        (writer, client)
+    }
+    
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+    
+    #[test]
+    fn sha1_vectors() {
+        assert_eq!(hex(&sha1(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(hex(&sha1(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(hex(&sha1(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")), "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+        assert_eq!(hex(&sha1(&[b'a'; 1000])), "291e9a6c66994949b57ba5e650361e98fc36b1ba");
+    }
+    
+    #[test]
+    fn accept_key() {
+        let accept = base64_encode(&sha1(format!("{}{}", "dGhlIHNhbXBsZSBub25jZQ==", GUID).as_bytes()));
+        assert_eq!(accept, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+    
+    #[test]
+    fn messages_become_lines() {
+        let mut input = frame(true, 0x1, b"s:news");
+        input.extend(frame(true, 0x2, b"p:news:hi\n"));
+        input.extend(frame(true, 0x1, &[b'x'; 300]));
+        let mut output = String::new();
+        FrameReader::new(Trickle(input, 0), writer().0).read_to_string(&mut output).unwrap();
+        assert_eq!(output, format!("s:news\np:news:hi\n{}\n", "x".repeat(300)));
+    }
+    
+    #[test]
+    fn fragments_are_joined() {
+        let (writer, mut client) = writer();
+        let mut input = frame(false, 0x1, b"p:news:");
+        input.extend(frame(true, 0x9, b"hi"));
+        input.extend(frame(false, 0x0, b"hello "));
+        input.extend(frame(true, 0x0, b"world"));
+        input.extend(frame(true, 0x8, b""));
+        input.extend(frame(true, 0x1, b"ignored"));
+        let mut output = String::new();
+        FrameReader::new(Trickle(input, 0), writer).read_to_string(&mut output).unwrap();
+        assert_eq!(output, "p:news:hello world\n");
+        
+        let mut pong = [0; 4];
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.read_exact(&mut pong).unwrap();
+        assert_eq!(pong, [0x8A, 2, b'h', b'i']);
+    }
+    
+    #[test]
+    fn oversized_frames_are_refused() {
+        let mut input = vec![0x81, 0x80 | 127];
+        input.extend_from_slice(&(MAX_MESSAGE as u64 + 1).to_be_bytes());
+        input.extend_from_slice(&[0; 4]);
+        let error = FrameReader::new(&input[..], writer().0).read(&mut [0; 16]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        
+        let half  = vec![b'x'; MAX_MESSAGE / 2 + 1];
+        let mut input = frame(false, 0x1, &half);
+        input.extend(frame(true, 0x0, &half));
+        let error = FrameReader::new(&input[..], writer().0).read(&mut [0; 16]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+    
+    #[test]
+    fn unmasked_frames_are_refused() {
+        let input = [0x81, 1, b'x'];
+        let error = FrameReader::new(&input[..], writer().0).read(&mut [0; 16]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+    
+    /// answers this handshake request over loopback, returning the
+    /// result and the response the client received.
+    fn handshake_with(request: &str) -> (Result<()>, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(request.as_bytes()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let result = handshake(&stream);
+        drop(stream);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        (result, response)
+    }
+    
+    #[test]
+    fn handshakes() {
+        let upgrade = "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n";
+        let (result, response) = handshake_with(&format!("{}Sec-WebSocket-Version: 13\r\n\r\n", upgrade));
+        assert!(result.is_ok());
+        assert_eq!(response, "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n");
+        
+        let (result, response) = handshake_with(&format!("{}Sec-WebSocket-Version: 8\r\n\r\n", upgrade));
+        assert!(result.is_err());
+        assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+        assert!(response.contains("\r\nSec-WebSocket-Version: 13\r\n"));
+        
+        let (result, response) = handshake_with("GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        assert!(result.is_err());
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        
+        let (result, response) = handshake_with("nonsense\r\n\r\n");
+        assert!(result.is_err());
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+    
+    /// reads one unmasked server frame, returning its first byte and
+    /// payload.
+    fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).unwrap();
+        assert!(head[1] < 126);
+        let mut payload = vec![0; head[1] as usize];
+        stream.read_exact(&mut payload).unwrap();
+        (head[0], payload)
+    }
+    
+    #[test]
+    fn websocket_clients_share_topics() {
+        let topics = Topics::new();
+        let handle = Server::start_websocket("127.0.0.1:0", topics.clone(), Settings::new(Config::default())).unwrap();
+        let mut client = TcpStream::connect(handle.local_addr()).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        write!(client, "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n").unwrap();
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0];
+            client.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        assert!(head.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+        
+        client.write_all(&frame(true, 0x1, b"s:news")).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while topics.subscriptions().is_empty() {
+            assert!(Instant::now() < deadline, "expected a subscription");
+            thread::sleep(Duration::from_millis(10));
+        }
+        topics.publish("news".to_string(), "dave".to_string(), "hello".to_string());
+        let (first, payload) = read_frame(&mut client);
+        assert_eq!(first, 0x81);
+        assert!(String::from_utf8(payload).unwrap().ends_with(":hello"));
+        
+        client.write_all(&frame(true, 0x9, b"hi")).unwrap();
+        assert_eq!(read_frame(&mut client), (0x8A, b"hi".to_vec()));
+        
+        handle.shutdown_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(read_frame(&mut client), (0x81, b"g:shutdown".to_vec()));
+        assert_eq!(read_frame(&mut client), (0x88, Vec::new()));
+    }
+}
@@ -0,0 +1,414 @@
+/*--------------------------------------------------------------------------
+ pubsub-rs
+
+ The MIT License (MIT)
+
+ Copyright (c) 2016 Haydn Paterson (sinclair) <haydn.developer@gmail.com>
+
+ Permission is hereby granted, free of charge, to any person obtaining a copy
+ of this software and associated documentation files (the "Software"), to deal
+ in the Software without restriction, including without limitation the rights
+ to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+ copies of the Software, and to permit persons to whom the Software is
+ furnished to do so, subject to the following conditions:
+
+ The above copyright notice and this permission notice shall be included in
+ all copies or substantial portions of the Software.
+
+ THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+ IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+ FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+ AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+ THE SOFTWARE.
+---------------------------------------------------------------------------*/
+
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind, IoSlice, Result};
+use std::net::TcpStream;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use super::super::protocol::{Command, Packet};
+use super::stats::Stats;
+
+/// maximum number of frames coalesced into a single write.
+const MAX_BATCH: usize = 64;
+
+/// Framing
+///
+/// How lines are framed on the wire.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    /// newline terminated lines.
+    Lines,
+    /// one websocket text frame per line.
+    WebSocket,
+    /// bytes written as is, for clients of another protocol. Lines
+    /// and packets are not written.
+    Raw
+}
+
+///------------------------------------
+/// Frame
+///
+/// A unit of output queued for a
+/// connection. Packets and lines are
+/// written newline terminated, text
+/// and bytes are written as is. On
+/// websockets each is a text frame,
+/// bytes a binary frame and pongs a
+/// control frame.
+///------------------------------------
+enum Frame {
+    Packet (Packet),
+    Line   (String),
+    Text   (String),
+    Bytes  (Vec<u8>),
+    Pong   (Vec<u8>),
+    Close
+}
+
+///------------------------------------
+/// State
+///
+/// Counters and completion flag shared
+/// between a writer and its thread.
+///------------------------------------
+#[derive(Default)]
+struct State {
+    depth   : Arc<AtomicUsize>,
+    dropped : AtomicUsize,
+    done    : Mutex<bool>,
+    signal  : Condvar
+}
+
+/// Writer
+///
+/// Queues output for a tcp stream and writes it from a dedicated
+/// thread. Frames queued while a write is in progress, or within
+/// the flush latency of the first frame, are coalesced into one
+/// vectored write. Packets are dropped when the queue is full so
+/// a slow client cannot hold up publishers.
+#[derive(Clone)]
+pub struct Writer {
+    sender  : SyncSender<Frame>,
+    state   : Arc<State>,
+    framing : Framing
+}
+impl Writer {
+    
+    /// spawns a writer thread for this stream, counting its queue
+    /// depth and output in these stats. Websocket writers send a
+    /// close frame when closed.
+    pub fn spawn(stream: TcpStream, framing: Framing, capacity: usize, latency: Option<Duration>, stats: Stats) -> (Writer, JoinHandle<()>) {
+        let (sender, receiver) = sync_channel(capacity);
+        let state  = Arc::new(State::default());
+        stats.track_queue(&state.depth);
+        let handle = {
+            let state = state.clone();
+            thread::spawn(move || {
+                Writer::run(stream, framing, receiver, &state, &stats, latency);
+                state.depth.store(0, Ordering::SeqCst);
+                *state.done.lock().unwrap() = true;
+                state.signal.notify_all();
+            })
+        };
+        (Writer { sender, state, framing }, handle)
+    }
+    
+    /// queues this packet, dropping it if the queue is full.
+    pub fn send_packet(&self, packet: &Packet) -> Result<()> {
+        self.drop_full(Frame::Packet(packet.clone()))
+    }
+    
+    /// queues this text to be written as is, dropping it if the
+    /// queue is full.
+    pub fn send_text(&self, text: String) -> Result<()> {
+        self.drop_full(Frame::Text(text))
+    }
+    
+    /// queues these bytes to be written as is, dropping them if the
+    /// queue is full.
+    pub fn send_bytes(&self, bytes: Vec<u8>) -> Result<()> {
+        self.drop_full(Frame::Bytes(bytes))
+    }
+    
+    /// queues this frame, counting it as dropped if the queue is full.
+    fn drop_full(&self, frame: Frame) -> Result<()> {
+        let result = self.queue(frame);
+        if let Err(ref error) = result {
+            if error.kind() == ErrorKind::WouldBlock {
+                self.state.dropped.fetch_add(1, Ordering::SeqCst);
+            }
+        } result
+    }
+    
+    /// queues this command, failing with WouldBlock when the queue
+    /// is full. Callers on the reading side must not wait for space,
+    /// as a client that stops reading would stop being read too.
+    pub fn send(&self, command: Command) -> Result<()> {
+        self.queue(Frame::Line(command.serialize()))
+    }
+    
+    /// queues this frame if there is space in the queue. The depth is
+    /// counted before the frame can reach the writer thread, which
+    /// subtracts it once written. The writer thread clears the depth
+    /// on exit, so a failed send never takes it below zero.
+    fn queue(&self, frame: Frame) -> Result<()> {
+        self.state.depth.fetch_add(1, Ordering::SeqCst);
+        let result = self.sender.try_send(frame);
+        if result.is_err() {
+            let _ = self.state.depth.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |depth| depth.checked_sub(1));
+        }
+        match result {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(Error::new(ErrorKind::WouldBlock, "writer queue full")),
+            Err(TrySendError::Disconnected(_)) => Err(Error::new(ErrorKind::BrokenPipe, "writer closed"))
+        }
+    }
+    
+    /// queues a websocket pong frame with this payload, dropping it
+    /// if the queue is full as the client will ping again. Ignored on
+    /// line framed writers.
+    pub fn pong(&self, payload: Vec<u8>) -> Result<()> {
+        if self.framing != Framing::WebSocket {
+            return Ok(());
+        }
+        match self.queue(Frame::Pong(payload)) {
+            Err(ref error) if error.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result
+        }
+    }
+    
+    /// stops the writer once everything queued so far is written.
+    pub fn close(&self) {
+        let _ = self.sender.send(Frame::Close);
+    }
+    
+    /// stops the writer if there is space in the queue.
+    pub fn try_close(&self) {
+        let _ = self.sender.try_send(Frame::Close);
+    }
+    
+    /// waits for the writer thread to finish until this deadline,
+    /// returning true if it finished.
+    pub fn wait(&self, deadline: Instant) -> bool {
+        let mut done = self.state.done.lock().unwrap();
+        while !*done {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                break;
+            }
+            done = self.state.signal.wait_timeout(done, remaining).unwrap().0;
+        } *done
+    }
+    
+    /// returns how this writer frames its output.
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+    
+    /// returns true if the writer thread is still running.
+    pub fn alive(&self) -> bool {
+        !*self.state.done.lock().unwrap()
+    }
+    
+    /// returns the number of frames waiting to be written.
+    pub fn depth(&self) -> usize {
+        self.state.depth.load(Ordering::SeqCst)
+    }
+    
+    /// returns the number of packets, text and bytes dropped on a
+    /// full queue.
+    pub fn dropped(&self) -> usize {
+        self.state.dropped.load(Ordering::SeqCst)
+    }
+    
+    /// writes frames until closed, disconnected or a write fails.
+    fn run(mut stream: TcpStream, framing: Framing, receiver: Receiver<Frame>, state: &State, stats: &Stats, latency: Option<Duration>) {
+        let mut batch = Vec::with_capacity(MAX_BATCH);
+        let mut closing = false;
+        while !closing {
+            match receiver.recv() {
+                Ok(Frame::Close) => { closing = true; break },
+                Ok(frame)        => batch.push(frame),
+                Err(_)           => break
+            }
+            
+            // coalesce frames arriving within the flush latency.
+            let deadline = latency.map(|latency| Instant::now() + latency);
+            while batch.len() < MAX_BATCH {
+                let frame = match deadline {
+                    Some(deadline) => receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())).map_err(|error| error == RecvTimeoutError::Disconnected),
+                    None           => receiver.try_recv().map_err(|error| error == TryRecvError::Disconnected)
+                };
+                match frame {
+                    Ok(Frame::Close) => { closing = true; break },
+                    Ok(frame)        => batch.push(frame),
+                    Err(true)        => { closing = true; break },
+                    Err(false)       => break
+                }
+            }
+            let written = batch.len();
+            let result  = Writer::write_batch(&mut stream, framing, &batch);
+            state.depth.fetch_sub(written, Ordering::SeqCst);
+            batch.clear();
+            match result {
+                Ok(bytes) => stats.send(bytes),
+                Err(_)    => return
+            }
+        }
+        if closing && framing == Framing::WebSocket {
+            let _ = stream.write_all(&[0x88, 0x00]);
+        }
+    }
+    
+    /// writes these frames as websocket frames, or terminating
+    /// packets and lines with a newline, returning the number of
+    /// bytes written. Raw writers skip packets and lines.
+    fn write_batch(stream: &mut TcpStream, framing: Framing, batch: &[Frame]) -> Result<usize> {
+        let heads = match framing {
+            Framing::Lines | Framing::Raw => Vec::new(),
+            Framing::WebSocket => batch.iter().map(|frame| match *frame {
+                Frame::Packet(ref packet) => Writer::frame_head(0x1, packet.bytes().len()),
+                Frame::Line(ref line)     => Writer::frame_head(0x1, line.len()),
+                Frame::Text(ref text)     => Writer::frame_head(0x1, text.len()),
+                Frame::Bytes(ref bytes)   => Writer::frame_head(0x2, bytes.len()),
+                Frame::Pong(ref payload)  => Writer::frame_head(0xA, payload.len()),
+                Frame::Close              => Vec::new()
+            }).collect()
+        };
+        let mut slices = Vec::with_capacity(batch.len() * 2);
+        for (index, frame) in batch.iter().enumerate() {
+            let (payload, newline) = match *frame {
+                Frame::Packet(ref packet) => (packet.bytes(), true),
+                Frame::Line(ref line)     => (line.as_bytes(), true),
+                Frame::Text(ref text)     => (text.as_bytes(), false),
+                Frame::Bytes(ref bytes)   => (bytes.as_slice(), false),
+                Frame::Pong(ref payload)  => (payload.as_slice(), false),
+                Frame::Close              => continue
+            };
+            match framing {
+                Framing::Raw if newline => {},
+                Framing::Lines | Framing::Raw => {
+                    slices.push(IoSlice::new(payload));
+                    if newline {
+                        slices.push(IoSlice::new(b"\n"));
+                    }
+                },
+                Framing::WebSocket => {
+                    slices.push(IoSlice::new(&heads[index]));
+                    slices.push(IoSlice::new(payload));
+                }
+            }
+        }
+        let mut slices = &mut slices[..];
+        let mut total  = 0;
+        while !slices.is_empty() {
+            match stream.write_vectored(slices) {
+                Ok(0) => return Err(Error::new(ErrorKind::WriteZero, "failed to write frames")),
+                Ok(written) => {
+                    IoSlice::advance_slices(&mut slices, written);
+                    total += written;
+                },
+                Err(ref error) if error.kind() == ErrorKind::Interrupted => {},
+                Err(error) => return Err(error)
+            }
+        } Ok(total)
+    }
+    
+    /// returns the head of an unmasked, final websocket frame with
+    /// this opcode and payload length.
+    fn frame_head(opcode: u8, length: usize) -> Vec<u8> {
+        let mut head = vec![0x80 | opcode];
+        if length < 126 {
+            head.push(length as u8);
+        } else if length <= 0xFFFF {
+            head.push(126);
+            head.extend_from_slice(&(length as u16).to_be_bytes());
+        } else {
+            head.push(127);
+            head.extend_from_slice(&(length as u64).to_be_bytes());
+        } head
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::sync::mpsc::Receiver;
+    
+    use super::super::super::protocol::Message;
+    
+    /// returns a writer for the accepted end of a loopback connection
+    /// and the client end to read it from.
+    fn connected(latency: Option<Duration>) -> (Writer, JoinHandle<()>, TcpStream) {
+        let listener    = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client      = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let (writer, handle) = Writer::spawn(stream, Framing::Lines, 16, latency, Stats::new());
+        (writer, handle, client)
+    }
+    
+    /// returns a writer with no thread draining its queue.
+    fn stalled(capacity: usize) -> (Writer, Receiver<Frame>) {
+        let (sender, receiver) = sync_channel(capacity);
+        (Writer { sender, state: Arc::new(State::default()), framing: Framing::Lines }, receiver)
+    }
+    
+    #[test]
+    fn frames_are_written_as_lines() {
+        let (writer, handle, client) = connected(None);
+        let message = Message::new("news".to_string(), "dave".to_string(), "hello".to_string());
+        writer.send_packet(&Packet::new(message.clone())).unwrap();
+        writer.send(Command::Error("denied".to_string())).unwrap();
+        writer.close();
+        handle.join().unwrap();
+        
+        let mut output = String::new();
+        BufReader::new(client).read_to_string(&mut output).unwrap();
+        assert_eq!(output, format!("{}\ne:denied\n", message.serialize()));
+        assert_eq!(writer.depth(), 0);
+        assert!(!writer.alive());
+    }
+    
+    #[test]
+    fn frames_within_the_latency_are_coalesced() {
+        let (writer, handle, mut client) = connected(Some(Duration::from_millis(200)));
+        for token in &["1", "2", "3"] {
+            writer.send(Command::Ping(token.to_string())).unwrap();
+        }
+        client.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let error = client.read(&mut [0; 64]).unwrap_err();
+        assert!(error.kind() == ErrorKind::WouldBlock || error.kind() == ErrorKind::TimedOut);
+        assert_eq!(writer.depth(), 3);
+        
+        client.set_read_timeout(None).unwrap();
+        writer.close();
+        handle.join().unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "ping:1\nping:2\nping:3\n");
+        assert_eq!(writer.depth(), 0);
+    }
+    
+    #[test]
+    fn full_queues_do_not_block() {
+        let (writer, receiver) = stalled(1);
+        let packet = Packet::new(Message::new("news".to_string(), "dave".to_string(), "hello".to_string()));
+        writer.send_packet(&packet).unwrap();
+        assert_eq!(writer.send_packet(&packet).unwrap_err().kind(), ErrorKind::WouldBlock);
+        assert_eq!(writer.send(Command::Ping("1".to_string())).unwrap_err().kind(), ErrorKind::WouldBlock);
+        assert_eq!((writer.depth(), writer.dropped()), (1, 1));
+        
+        drop(receiver);
+        assert_eq!(writer.send(Command::Ping("2".to_string())).unwrap_err().kind(), ErrorKind::BrokenPipe);
+        assert_eq!((writer.depth(), writer.dropped()), (1, 1));
+    }
+}
@@ -0,0 +1,140 @@
+extern crate pubsub;
+
+use pubsub::protocol::Command;
+use pubsub::server::bridge::DEFAULT_HOPS;
+use pubsub::server::{Bridge, BridgeConfig, BridgeHandle, Config, Server, ServerHandle, Settings, Topics};
+
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// a native client failing instead of blocking when the server
+/// stays silent.
+struct Peer {
+    stream : TcpStream,
+    reader : BufReader<TcpStream>
+}
+impl Peer {
+    fn connect(addr: SocketAddr, user: &str, topics: &[&str]) -> Peer {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        let mut peer = Peer { stream, reader };
+        peer.send(Command::Identity(user.to_string()));
+        for topic in topics {
+            peer.send(Command::Subscribe(topic.to_string()));
+        }
+        peer.sync();
+        peer
+    }
+
+    fn send(&mut self, command: Command) {
+        self.stream.write_all(format!("{}\n", command.serialize()).as_bytes()).unwrap();
+    }
+
+    /// waits until the server has handled every command sent so far.
+    fn sync(&mut self) {
+        self.send(Command::Ping("sync".to_string()));
+        match self.next() {
+            Command::Pong(ref token) if token == "sync" => {},
+            command => panic!("expected pong, received {:?}", command)
+        }
+    }
+
+    fn next(&mut self) -> Command {
+        let mut line = String::new();
+        assert!(self.reader.read_line(&mut line).unwrap() > 0, "server closed the connection");
+        Command::parse(&line).unwrap()
+    }
+
+    /// returns the next message as topic, user and message.
+    fn message(&mut self) -> (String, String, String) {
+        match self.next() {
+            Command::Message(topic, user, message) => (topic, user, message),
+            command => panic!("expected a message, received {:?}", command)
+        }
+    }
+}
+
+fn message(topic: &str, user: &str, message: &str) -> (String, String, String) {
+    (topic.to_string(), user.to_string(), message.to_string())
+}
+
+/// starts a named broker on an ephemeral port.
+fn broker(name: &str) -> (Topics, Settings, ServerHandle) {
+    let topics   = Topics::new();
+    let settings = Settings::new(Config { name: Some(name.to_string()), ..Config::default() });
+    let handle   = Server::start_with_settings("127.0.0.1:0", topics.clone(), settings.clone()).unwrap();
+    (topics, settings, handle)
+}
+
+/// bridges shared/* topics of this broker to the remote one.
+fn bridge(topics: &Topics, settings: &Settings, remote: &str, addr: SocketAddr) -> BridgeHandle {
+    let config = BridgeConfig {
+        remote   : remote.to_string(),
+        address  : addr.to_string(),
+        topics   : vec!["shared/*".to_string()],
+        hops     : DEFAULT_HOPS,
+        user     : None,
+        password : None
+    };
+    Bridge::start(config, topics.clone(), settings.clone()).unwrap()
+}
+
+#[test]
+fn bridges_in_both_directions() {
+    let (topics_a, settings_a, server_a) = broker("site-a");
+    let (topics_b, settings_b, server_b) = broker("site-b");
+    let (addr_a, addr_b) = (server_a.local_addr(), server_b.local_addr());
+    let bridge_a = bridge(&topics_a, &settings_a, "site-b", addr_b);
+    let bridge_b = bridge(&topics_b, &settings_b, "site-a", addr_a);
+
+    let mut alice = Peer::connect(addr_a, "alice", &["shared/news", "local"]);
+    let mut bob   = Peer::connect(addr_b, "bob", &["shared/news", "local"]);
+
+    // each side receives the other's messages once, under the
+    // publisher's name. bob receiving alice's message means it went
+    // through site-b's bridge queue before bob's reply, so an echo
+    // back to site-a would reach alice ahead of the reply.
+    alice.send(Command::Publish("shared/news".to_string(), "from-a".to_string()));
+    alice.send(Command::Publish("local".to_string(), "not-bridged".to_string()));
+    assert_eq!(alice.message(), message("shared/news", "alice", "from-a"));
+    assert_eq!(alice.message(), message("local", "alice", "not-bridged"));
+    assert_eq!(bob.message(), message("shared/news", "alice", "from-a"));
+
+    bob.send(Command::Publish("shared/news".to_string(), "from-b".to_string()));
+    assert_eq!(bob.message(), message("shared/news", "bob", "from-b"));
+    assert_eq!(alice.message(), message("shared/news", "bob", "from-b"));
+
+    // a broker drops messages whose route names it.
+    alice.send(Command::Forward("shared/news".to_string(), vec!["site-a".to_string()], "mallory".to_string(), "looped".to_string()));
+    alice.send(Command::Publish("shared/news".to_string(), "after-loop".to_string()));
+    assert_eq!(alice.message(), message("shared/news", "alice", "after-loop"));
+    assert_eq!(bob.message(), message("shared/news", "alice", "after-loop"));
+
+    // messages are delivered locally but not bridged once their
+    // route reaches the hop limit.
+    let route = |length: usize| (0..length).map(|index| format!("site-{}", index)).collect::<Vec<_>>();
+    alice.send(Command::Forward("shared/news".to_string(), route(DEFAULT_HOPS), "carol".to_string(), "far".to_string()));
+    alice.send(Command::Forward("shared/news".to_string(), route(DEFAULT_HOPS - 1), "carol".to_string(), "near".to_string()));
+    assert_eq!(alice.message(), message("shared/news", "carol", "far"));
+    assert_eq!(alice.message(), message("shared/news", "carol", "near"));
+    assert_eq!(bob.message(), message("shared/news", "carol", "near"));
+
+    // a message already routed through the remote is not sent back.
+    alice.send(Command::Forward("shared/news".to_string(), vec!["site-b".to_string()], "carol".to_string(), "returned".to_string()));
+    alice.send(Command::Publish("shared/news".to_string(), "last".to_string()));
+    assert_eq!(alice.message(), message("shared/news", "carol", "returned"));
+    assert_eq!(alice.message(), message("shared/news", "alice", "last"));
+    assert_eq!(bob.message(), message("shared/news", "alice", "last"));
+
+    bob.send(Command::Publish("shared/news".to_string(), "end".to_string()));
+    assert_eq!(bob.message(), message("shared/news", "bob", "end"));
+    assert_eq!(alice.message(), message("shared/news", "bob", "end"));
+
+    bridge_a.shutdown();
+    bridge_b.shutdown();
+    server_a.shutdown().unwrap();
+    server_b.shutdown().unwrap();
+}